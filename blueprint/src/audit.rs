@@ -0,0 +1,622 @@
+//! Tamper-evident audit log of the requests passing through the gateway.
+//!
+//! When enabled, every request a client sends through [`SseServer::forward`] is recorded as one
+//! JSON line once its response (or policy rejection) is seen. Entries contain the service and
+//! session ids, the caller identity as seen from the auth proxy, the method, the tool name,
+//! the arguments (hashed or redacted), the result status and the latency.
+//!
+//! Entries are hash-chained: `hash = sha256(prev_hash || json(entry without hash))`, where
+//! `prev_hash` is the hash of the previous line (all zeros for the first one). Editing, removing
+//! or reordering lines breaks the chain from that point on.
+//!
+//! The audit log is configured by the operator through environment variables:
+//!
+//! - `MCP_AUDIT_LOG`: path of the JSONL file, the audit log is disabled when unset.
+//! - `MCP_AUDIT_ARGUMENTS`: `hash` (default) to only record a hash of the arguments, or
+//!   `redacted` to also record the arguments with sensitive values redacted.
+//! - `MCP_AUDIT_REDACT`: comma separated, case-insensitive key patterns (with `*` wildcards)
+//!   whose values are redacted, defaults to the ones redacted from the logs, see
+//!   [`crate::redact`].
+//! - `MCP_AUDIT_CALLER_HEADERS`: comma separated request headers carrying the caller identity,
+//!   none by default. When none is present, the token id of the `Authorization` header
+//!   (`{token_id}|{token}`), which the auth proxy authenticated, is used.
+//!
+//! The clients can send any header, so the caller headers are only trustworthy when the proxy
+//! in front of the gateway sets them from the identity it authenticated and strips them from
+//! the client requests. Only list headers set this way in `MCP_AUDIT_CALLER_HEADERS`.
+//!
+//! [`SseServer::forward`]: crate::transport::SseServer::forward
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use axum::http::HeaderMap;
+use rmcp::model::{
+    ClientJsonRpcMessage, JsonRpcMessage, JsonRpcRequest, RequestId, ServerJsonRpcMessage,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use crate::error::Error;
+use crate::redact::{SENSITIVE_PATTERNS, matches_any, parse_patterns};
+use crate::transport::SessionId;
+
+/// Headers carrying the caller identity by default, none as the clients could forge them
+pub const DEFAULT_CALLER_HEADERS: &[&str] = &[];
+
+/// The value recorded in place of a redacted argument
+const REDACTED: &str = "[REDACTED]";
+
+/// How the request arguments are recorded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuditArguments {
+    /// Only a sha256 hash of the arguments is recorded
+    #[default]
+    Hash,
+    /// The hash and the arguments with sensitive values redacted are recorded
+    Redacted,
+}
+
+/// Operator configuration of the audit log
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// Path of the JSONL file the entries are appended to
+    pub path: PathBuf,
+    /// How the request arguments are recorded
+    pub arguments: AuditArguments,
+    /// Uppercase key patterns whose values are redacted
+    pub redact: Vec<String>,
+    /// Lowercase request headers carrying the caller identity
+    pub caller_headers: Vec<String>,
+}
+
+impl AuditConfig {
+    /// Loads the configuration from the environment, returns `None` if the audit log is disabled.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os("MCP_AUDIT_LOG")?;
+        let arguments = match std::env::var("MCP_AUDIT_ARGUMENTS").as_deref() {
+            Ok("redacted") => AuditArguments::Redacted,
+            Ok("hash") | Err(_) => AuditArguments::Hash,
+            Ok(other) => {
+                tracing::warn!(value = other, "unknown MCP_AUDIT_ARGUMENTS, using `hash`");
+                AuditArguments::Hash
+            }
+        };
+        Some(Self {
+            path: path.into(),
+            arguments,
+            redact: std::env::var("MCP_AUDIT_REDACT").map_or_else(
+                |_| SENSITIVE_PATTERNS.clone(),
+                |value| parse_patterns(&value),
+            ),
+            caller_headers: list_from_env("MCP_AUDIT_CALLER_HEADERS", DEFAULT_CALLER_HEADERS),
+        })
+    }
+}
+
+fn list_from_env(key: &str, default: &[&str]) -> Vec<String> {
+    match std::env::var(key) {
+        Ok(value) => value
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        Err(_) => default.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+/// The outcome of an audited request
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditStatus {
+    /// The MCP server answered with a result
+    Ok,
+    /// The MCP server answered with an error
+    Error,
+    /// The request was rejected by the gateway access policy
+    Denied,
+    /// The session ended before the request was answered
+    Aborted,
+}
+
+/// A single line of the audit log
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// Unix timestamp in milliseconds of when the request was received
+    pub timestamp: u64,
+    pub service_id: u64,
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    pub request_id: RequestId,
+    pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<Value>,
+    pub status: AuditStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<i32>,
+    pub latency_ms: u64,
+    /// Hash of the previous entry, hex encoded
+    pub prev_hash: String,
+    /// Hash of this entry, hex encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// The operator-wide audit log
+///
+/// Entries are handed to a background task that chains and appends them,
+/// so recording never blocks the forwarding loop.
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditConfig,
+    tx: tokio::sync::mpsc::UnboundedSender<AuditEntry>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the audit log file and resumes the hash chain from its last entry.
+    pub async fn open(config: AuditConfig) -> Result<Self, Error> {
+        let prev_hash = last_hash(&config.path).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .await?;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<AuditEntry>();
+        let path = config.path.clone();
+        tokio::spawn(async move {
+            let mut prev_hash = prev_hash;
+            while let Some(mut entry) = rx.recv().await {
+                entry.prev_hash = prev_hash.clone();
+                entry.hash = None;
+                let mut hasher = Sha256::new();
+                hasher.update(entry.prev_hash.as_bytes());
+                // Serializing a plain struct never fails
+                hasher.update(serde_json::to_vec(&entry).unwrap_or_default());
+                let hash = hex::encode(hasher.finalize());
+                entry.hash = Some(hash.clone());
+                let mut line = serde_json::to_vec(&entry).unwrap_or_default();
+                line.push(b'\n');
+                if let Err(e) = file.write_all(&line).await {
+                    tracing::error!(error = %e, path = %path.display(), "failed to write audit entry");
+                    continue;
+                }
+                if let Err(e) = file.flush().await {
+                    tracing::error!(error = %e, path = %path.display(), "failed to flush audit log");
+                }
+                prev_hash = hash;
+            }
+        });
+        tracing::info!(path = %config.path.display(), "audit log enabled");
+        Ok(Self { config, tx })
+    }
+
+    /// Returns a handle that records entries for the given service.
+    pub fn for_service(self: &Arc<Self>, service_id: u64) -> ServiceAudit {
+        ServiceAudit {
+            log: self.clone(),
+            service_id,
+        }
+    }
+
+    fn record(&self, entry: AuditEntry) {
+        if self.tx.send(entry).is_err() {
+            tracing::error!("audit log writer is gone, dropping entry");
+        }
+    }
+}
+
+/// Reads the hash of the last entry of an existing audit log.
+///
+/// A crash while appending can leave a torn last line, without its newline. It was never part
+/// of the chain, so it is cut off and the chain resumes from the entry before it. A complete
+/// line that doesn't parse is not a crash artifact and fails the open.
+async fn last_hash(path: &std::path::Path) -> Result<String, Error> {
+    let genesis = hex::encode([0u8; 32]);
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(genesis),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reader = tokio::io::BufReader::new(file);
+    // The offset and content of the last two non-empty lines
+    let (mut previous, mut last) = (None, None::<(u64, Vec<u8>)>);
+    let (mut offset, mut line) = (0, Vec::new());
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).await?;
+        if read == 0 {
+            break;
+        }
+        if !line.trim_ascii().is_empty() {
+            previous = last.replace((offset, line.clone()));
+        }
+        offset += read as u64;
+    }
+    let Some((start, last)) = last else {
+        return Ok(genesis);
+    };
+    let torn = !last.ends_with(b"\n");
+    match entry_hash(&last) {
+        Ok(hash) => {
+            if torn {
+                // The entry made it but not its newline, the next one must not be appended to it
+                let mut file = tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(b"\n").await?;
+            }
+            Ok(hash)
+        }
+        Err(e) if torn => {
+            tracing::warn!(error = %e, path = %path.display(), "cutting off the torn last line of the audit log");
+            let file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+            file.set_len(start).await?;
+            match previous {
+                Some((_, line)) => entry_hash(&line),
+                None => Ok(genesis),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses an audit log line and returns the hash of its entry.
+fn entry_hash(line: &[u8]) -> Result<String, Error> {
+    let entry: AuditEntry = serde_json::from_slice(line).map_err(std::io::Error::other)?;
+    entry.hash.ok_or_else(|| {
+        Error::Io(std::io::Error::other(
+            "last audit log entry has no hash, refusing to continue the chain",
+        ))
+    })
+}
+
+/// An [`AuditLog`] bound to a service
+#[derive(Debug, Clone)]
+pub struct ServiceAudit {
+    log: Arc<AuditLog>,
+    service_id: u64,
+}
+
+impl ServiceAudit {
+    /// Extracts the caller identity from the headers of the SSE connection.
+    pub fn caller_identity(&self, headers: &HeaderMap) -> Option<Arc<str>> {
+        let configured = self
+            .log
+            .config
+            .caller_headers
+            .iter()
+            .find_map(|name| headers.get(name.as_str())?.to_str().ok());
+        let token_id = || {
+            let value = headers
+                .get(axum::http::header::AUTHORIZATION)?
+                .to_str()
+                .ok()?;
+            let token = value.strip_prefix("Bearer ").unwrap_or(value);
+            token.split_once('|').map(|(id, _)| id)
+        };
+        configured.or_else(token_id).map(Into::into)
+    }
+
+    /// Starts auditing a new session.
+    pub(crate) fn session(&self, session_id: SessionId, caller: Option<Arc<str>>) -> SessionAudit {
+        SessionAudit {
+            audit: self.clone(),
+            session_id,
+            caller,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+/// A request waiting for its response
+#[derive(Debug)]
+struct PendingRequest {
+    timestamp: u64,
+    started: Instant,
+    method: String,
+    tool: Option<String>,
+    arguments_hash: Option<String>,
+    arguments: Option<Value>,
+}
+
+/// Per-session audit state, pairing requests with their responses
+#[derive(Debug)]
+pub(crate) struct SessionAudit {
+    audit: ServiceAudit,
+    session_id: SessionId,
+    caller: Option<Arc<str>>,
+    pending: HashMap<RequestId, PendingRequest>,
+}
+
+impl SessionAudit {
+    /// Records a request going from the client to the MCP server.
+    pub fn on_request(&mut self, message: &ClientJsonRpcMessage) {
+        let JsonRpcMessage::Request(JsonRpcRequest { id, request, .. }) = message else {
+            return;
+        };
+        let config = &self.audit.log.config;
+        let request = serde_json::to_value(request).unwrap_or_default();
+        let method = request["method"].as_str().unwrap_or_default().to_string();
+        let params = &request["params"];
+        let tool = (method == "tools/call")
+            .then(|| params["name"].as_str().map(ToString::to_string))
+            .flatten();
+        let arguments = params.get("arguments").filter(|a| !a.is_null());
+        let arguments_hash = arguments
+            .map(|a| hex::encode(Sha256::digest(serde_json::to_vec(a).unwrap_or_default())));
+        let arguments = arguments
+            .filter(|_| config.arguments == AuditArguments::Redacted)
+            .map(|a| redact(a.clone(), &config.redact));
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.pending.insert(
+            id.clone(),
+            PendingRequest {
+                timestamp,
+                started: Instant::now(),
+                method,
+                tool,
+                arguments_hash,
+                arguments,
+            },
+        );
+    }
+
+    /// Records a response (or a policy rejection) going back to the client.
+    pub fn on_response(&mut self, message: &ServerJsonRpcMessage, denied: bool) {
+        let (id, status, error_code) = match message {
+            JsonRpcMessage::Response(response) => (&response.id, AuditStatus::Ok, None),
+            JsonRpcMessage::Error(error) if denied => {
+                (&error.id, AuditStatus::Denied, Some(error.error.code.0))
+            }
+            JsonRpcMessage::Error(error) => {
+                (&error.id, AuditStatus::Error, Some(error.error.code.0))
+            }
+            _ => return,
+        };
+        let Some(pending) = self.pending.remove(id) else {
+            return;
+        };
+        self.record(id.clone(), pending, status, error_code);
+    }
+
+    fn record(
+        &self,
+        request_id: RequestId,
+        pending: PendingRequest,
+        status: AuditStatus,
+        error_code: Option<i32>,
+    ) {
+        self.audit.log.record(AuditEntry {
+            timestamp: pending.timestamp,
+            service_id: self.audit.service_id,
+            session_id: self.session_id.to_string(),
+            caller: self.caller.as_deref().map(ToString::to_string),
+            request_id,
+            method: pending.method,
+            tool: pending.tool,
+            arguments_hash: pending.arguments_hash,
+            arguments: pending.arguments,
+            status,
+            error_code,
+            latency_ms: pending.started.elapsed().as_millis() as u64,
+            prev_hash: String::new(),
+            hash: None,
+        });
+    }
+}
+
+impl Drop for SessionAudit {
+    /// Records the requests left unanswered when the session ends, however it ends.
+    fn drop(&mut self) {
+        for (id, pending) in std::mem::take(&mut self.pending) {
+            self.record(id, pending, AuditStatus::Aborted, None);
+        }
+    }
+}
+
+/// Replaces the values of keys matching any of the patterns, recursively.
+fn redact(value: Value, patterns: &[String]) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    if matches_any(patterns, &key) {
+                        (key, Value::String(REDACTED.to_string()))
+                    } else {
+                        (key, redact(value, patterns))
+                    }
+                })
+                .collect(),
+        ),
+        Value::Array(items) => {
+            Value::Array(items.into_iter().map(|v| redact(v, patterns)).collect())
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(path: PathBuf) -> AuditConfig {
+        AuditConfig {
+            path,
+            arguments: AuditArguments::Hash,
+            redact: Vec::new(),
+            caller_headers: Vec::new(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mcp-audit-{name}-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn request(id: u32) -> ClientJsonRpcMessage {
+        serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": { "name": "echo", "arguments": { "msg": "hi" } },
+        }))
+        .unwrap()
+    }
+
+    fn response(id: u32) -> ServerJsonRpcMessage {
+        serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": { "content": [], "isError": false },
+        }))
+        .unwrap()
+    }
+
+    /// Records one answered request per session and waits for the writer.
+    async fn record_sessions(path: &std::path::Path, sessions: usize) {
+        let log = Arc::new(AuditLog::open(config(path.to_path_buf())).await.unwrap());
+        let audit = log.for_service(7);
+        for _ in 0..sessions {
+            let mut session = audit.session(SessionId::from("session"), None);
+            session.on_request(&request(1));
+            session.on_response(&response(1), false);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    fn read_entries(path: &std::path::Path) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn arguments_are_redacted_like_the_logs() {
+        let keys = [
+            "GOOGLE_APPLICATION_CREDENTIAL",
+            "privateKey",
+            "x-api-key",
+            "github_token",
+            "Authorization",
+        ];
+        let mut arguments: serde_json::Map<String, Value> = keys
+            .iter()
+            .map(|key| (key.to_string(), Value::from("hidden")))
+            .collect();
+        arguments.insert("query".into(), Value::from("visible"));
+        let redacted = redact(
+            serde_json::json!({ "nested": [arguments] }),
+            &SENSITIVE_PATTERNS,
+        );
+        let redacted = &redacted["nested"][0];
+        for key in keys {
+            assert!(crate::redact::is_sensitive(key), "{key}");
+            assert_eq!(redacted[key], REDACTED, "{key}");
+        }
+        assert_eq!(redacted["query"], "visible");
+    }
+
+    #[tokio::test]
+    async fn chain_resumes_after_reopen() {
+        let path = temp_path("resume");
+        record_sessions(&path, 1).await;
+        record_sessions(&path, 1).await;
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["prevHash"], hex::encode([0u8; 32]));
+        assert_eq!(entries[1]["prevHash"], entries[0]["hash"]);
+    }
+
+    #[tokio::test]
+    async fn torn_last_line_is_cut_off() {
+        let path = temp_path("torn");
+        record_sessions(&path, 2).await;
+        let complete = std::fs::read_to_string(&path).unwrap();
+        let second = complete.lines().nth(1).unwrap();
+        // A crash in the middle of the third entry
+        std::fs::write(&path, format!("{complete}{}", &second[..second.len() / 2])).unwrap();
+
+        record_sessions(&path, 1).await;
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2]["prevHash"], entries[1]["hash"]);
+    }
+
+    #[tokio::test]
+    async fn entry_without_newline_is_kept() {
+        let path = temp_path("newline");
+        record_sessions(&path, 1).await;
+        let complete = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, complete.trim_end()).unwrap();
+
+        record_sessions(&path, 1).await;
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1]["prevHash"], entries[0]["hash"]);
+    }
+
+    #[tokio::test]
+    async fn corrupted_complete_line_is_refused() {
+        let path = temp_path("corrupted");
+        record_sessions(&path, 1).await;
+        let complete = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{complete}not an entry\n")).unwrap();
+        assert!(AuditLog::open(config(path)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_are_aborted() {
+        let path = temp_path("aborted");
+        let log = Arc::new(AuditLog::open(config(path.clone())).await.unwrap());
+        let mut session = log.for_service(7).session(SessionId::from("session"), None);
+        session.on_request(&request(1));
+        session.on_request(&request(2));
+        session.on_response(&response(1), false);
+        drop(session);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["status"], "ok");
+        assert_eq!(entries[1]["status"], "aborted");
+        assert_eq!(entries[1]["requestId"], 2);
+    }
+
+    #[test]
+    fn caller_headers_are_not_trusted_by_default() {
+        let config = AuditConfig {
+            caller_headers: DEFAULT_CALLER_HEADERS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            ..config(temp_path("caller"))
+        };
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let audit = Arc::new(AuditLog { config, tx }).for_service(7);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant-id", "forged".parse().unwrap());
+        assert_eq!(audit.caller_identity(&headers), None);
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            "Bearer 42|secret".parse().unwrap(),
+        );
+        assert_eq!(audit.caller_identity(&headers).as_deref(), Some("42"));
+    }
+}
@@ -0,0 +1,143 @@
+//! Start of the MCP servers of the services requested with `autoStart`.
+//!
+//! Without it, the owner has to wait for the service to be approved and then call the start
+//! job with their ECDSA key. With `autoStart` and `ownerEcdsaKey` in the request params, the
+//! operator starts the MCP server as soon as the blueprint runs for the initialized service,
+//! and again after the operator restarts.
+//!
+//! The operator can't submit a job result on its own, so once the servers run the endpoint is
+//! published as the RPC address of the operator preferences of the blueprint
+//! (`services.operators(blueprint_id, operator)`). The extrinsic is only submitted when the
+//! address on-chain differs. A start job called by the owner without arguments still returns
+//! the endpoint: it finds the servers already running with the same config and returns right
+//! away, without restarting them.
+
+use blueprint_sdk::contexts::tangle::TangleClientContext;
+use blueprint_sdk::crypto::sp_core::SpSr25519;
+use blueprint_sdk::crypto::tangle_pair_signer::TanglePairSigner;
+use blueprint_sdk::keystore::backends::Backend;
+use blueprint_sdk::tangle::extract::List;
+use blueprint_sdk::tangle::serde::from_field;
+use blueprint_sdk::tangle_subxt::tangle_testnet_runtime::api;
+use blueprint_sdk::tangle_subxt::tangle_testnet_runtime::api::runtime_types::bounded_collections::bounded_vec::BoundedVec;
+use blueprint_sdk::tangle_subxt::tangle_testnet_runtime::api::runtime_types::tangle_primitives::services::field::BoundedString;
+use futures::TryFutureExt;
+
+use crate::error::Error;
+use crate::{MyContext, RequestParams, jobs};
+
+/// Starts the MCP server of the service in the background if it was requested with
+/// `autoStart`.
+pub fn spawn_auto_start(ctx: &MyContext, service_id: u64) {
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        if let Err(e) = auto_start(&ctx, service_id).await {
+            blueprint_sdk::error!(error = %e, %service_id, "Failed to auto-start the MCP servers");
+        }
+    });
+}
+
+async fn auto_start(ctx: &MyContext, service_id: u64) -> Result<(), Error> {
+    let client = ctx
+        .env
+        .tangle_client()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?;
+    let instance = client
+        .storage()
+        .at_latest()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?
+        .fetch(&api::storage().services().instances(service_id))
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?
+        .ok_or(Error::ServiceNotFound(service_id))?;
+    let mut request_args = instance.args;
+    let mut params =
+        from_field::<RequestParams>(request_args.0.pop().ok_or(Error::MissingRequestParams)?)
+            .map_err(Error::InvalidRequestParams)?;
+
+    if !params.auto_start {
+        return Ok(());
+    }
+    let List(ecdsa_owner) = params
+        .owner_ecdsa_key
+        .0
+        .take()
+        .ok_or(Error::InvalidOwnerKey)?;
+    let ecdsa_owner = jobs::owner_ecdsa_key(ecdsa_owner)?;
+
+    blueprint_sdk::info!(%service_id, "Auto-starting the MCP servers");
+    jobs::start_and_register(
+        ctx,
+        service_id,
+        instance.owner,
+        params.server_configs(),
+        ecdsa_owner,
+    )
+    .await?;
+    let endpoint = jobs::public_endpoint();
+    blueprint_sdk::info!(%service_id, %endpoint, "MCP servers auto-started");
+    if let Err(e) = publish_endpoint(ctx, &endpoint).await {
+        // The servers keep running, the owner can still get the endpoint from the start job
+        blueprint_sdk::error!(error = %e, %service_id, "Failed to publish the MCP endpoint");
+    }
+    Ok(())
+}
+
+/// Sets the RPC address of the operator preferences of the blueprint to `endpoint`, unless
+/// it's already the address on-chain.
+async fn publish_endpoint(ctx: &MyContext, endpoint: &str) -> Result<(), Error> {
+    let blueprint_id = ctx
+        .env
+        .protocol_settings
+        .tangle()
+        .map_err(|_| Error::MissingBlueprintId)?
+        .blueprint_id;
+    let public = ctx.env.keystore().first_local::<SpSr25519>()?;
+    let pair = ctx.env.keystore().get_secret::<SpSr25519>(&public)?;
+    let signer = TanglePairSigner::new(pair.0);
+    let client = ctx
+        .env
+        .tangle_client()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?;
+    let preferences = client
+        .storage()
+        .at_latest()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?
+        .fetch(
+            &api::storage()
+                .services()
+                .operators(blueprint_id, signer.account_id().clone()),
+        )
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?;
+    if preferences.is_some_and(|preferences| preferences.rpc_address.0.0 == endpoint.as_bytes()) {
+        return Ok(());
+    }
+
+    let call = api::tx().services().update_rpc_address(
+        blueprint_id,
+        BoundedString(BoundedVec(endpoint.as_bytes().to_vec())),
+    );
+    client
+        .tx()
+        .sign_and_submit_then_watch_default(&call, &signer)
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?
+        .wait_for_finalized_success()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?;
+    blueprint_sdk::info!(%endpoint, "Published the MCP endpoint as the operator RPC address");
+    Ok(())
+}
@@ -0,0 +1,257 @@
+//! CORS and `Origin` validation of the SSE endpoints.
+//!
+//! Browser-based MCP clients, like the MCP inspector, send a CORS preflight before posting
+//! their messages and can only read the response headers that are exposed to them. The
+//! endpoints answer the preflights of the allowed origins and expose the headers of the
+//! transport.
+//!
+//! A web page can also reach the endpoints served on the loopback interface through DNS
+//! rebinding, so requests carrying an `Origin` that isn't allowed are rejected, as recommended
+//! by the MCP specification. Requests without an `Origin` don't come from a browser and are
+//! always accepted.
+//!
+//! Loopback origins, e.g. `http://localhost:6274`, are always allowed. The operator allows
+//! others with `MCP_CORS_ORIGINS`, a comma-separated list of origins or `*` for any, adds the
+//! request headers the clients may send with `MCP_CORS_HEADERS`, and lets the clients send
+//! credentials with `MCP_CORS_CREDENTIALS=true`.
+
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use tower_http::cors::{AllowCredentials, AllowOrigin, CorsLayer};
+
+/// How long a browser may cache the answer to a preflight
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// The request headers of the transport, always allowed
+const TRANSPORT_HEADERS: [HeaderName; 4] = [
+    header::CONTENT_TYPE,
+    header::AUTHORIZATION,
+    HeaderName::from_static("last-event-id"),
+    HeaderName::from_static("mcp-session-id"),
+];
+
+/// The response headers of the transport, exposed to the clients
+const EXPOSED_HEADERS: [HeaderName; 2] = [
+    header::RETRY_AFTER,
+    HeaderName::from_static("mcp-session-id"),
+];
+
+/// The policy set by the operator with `MCP_CORS_ORIGINS`, `MCP_CORS_HEADERS` and
+/// `MCP_CORS_CREDENTIALS`
+pub(crate) static OPERATOR_POLICY: LazyLock<Arc<CorsPolicy>> = LazyLock::new(|| {
+    let list = |name: &str| -> Vec<String> {
+        std::env::var(name)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let allowed_headers = list("MCP_CORS_HEADERS")
+        .into_iter()
+        .filter_map(|name| match HeaderName::try_from(name.as_str()) {
+            Ok(name) => Some(name),
+            Err(_) => {
+                tracing::warn!(%name, "Ignoring the invalid header name in MCP_CORS_HEADERS");
+                None
+            }
+        })
+        .collect();
+    let allow_credentials =
+        std::env::var("MCP_CORS_CREDENTIALS").is_ok_and(|s| matches!(s.trim(), "1" | "true"));
+    Arc::new(CorsPolicy {
+        allowed_origins: list("MCP_CORS_ORIGINS"),
+        allowed_headers,
+        allow_credentials,
+    })
+});
+
+/// Which browser origins may reach an SSE server, and what their requests may carry
+#[derive(Debug, Clone, Default)]
+pub struct CorsPolicy {
+    /// The origins allowed besides the loopback ones, e.g. `https://app.example.com`, or `*`
+    /// for any
+    pub allowed_origins: Vec<String>,
+    /// The request headers allowed besides the ones of the transport
+    pub allowed_headers: Vec<HeaderName>,
+    /// Whether the clients may send credentials, never granted to `*`, only to the listed
+    /// and loopback origins
+    pub allow_credentials: bool,
+}
+
+impl CorsPolicy {
+    /// Whether a request with this `Origin` header may reach the server.
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        self.allows_any() || self.lists(origin)
+    }
+
+    fn allows_any(&self) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    /// Whether the origin is a loopback one or listed by the operator.
+    fn lists(&self, origin: &HeaderValue) -> bool {
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        let origin = origin.trim_end_matches('/');
+        is_loopback(origin)
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    }
+
+    /// The layer answering the preflights and adding the CORS headers to the responses.
+    pub fn layer(self: &Arc<Self>) -> CorsLayer {
+        let policy = self.clone();
+        let origins = AllowOrigin::predicate(move |origin, _| policy.allows(origin));
+        let credentials = if self.allow_credentials {
+            let policy = self.clone();
+            AllowCredentials::predicate(move |origin, _| policy.lists(origin))
+        } else {
+            AllowCredentials::from(false)
+        };
+        let mut headers = TRANSPORT_HEADERS.to_vec();
+        headers.extend(self.allowed_headers.iter().cloned());
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers(headers)
+            .allow_credentials(credentials)
+            .expose_headers(EXPOSED_HEADERS)
+            .max_age(PREFLIGHT_MAX_AGE)
+    }
+}
+
+/// Whether the origin is served from the loopback interface, e.g. `http://127.0.0.1:6274`.
+fn is_loopback(origin: &str) -> bool {
+    let Some(authority) = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    else {
+        return false;
+    };
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split_once(']').map_or(ipv6, |(host, _)| host),
+        None => authority.split(':').next().unwrap_or(authority),
+    };
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::readiness::{self, ProbeTarget};
+    use crate::test_support::{SseClient, serve_fake};
+
+    fn with_origin<B>(mut request: hyper::Request<B>, origin: &str) -> hyper::Request<B> {
+        request
+            .headers_mut()
+            .insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        request
+    }
+
+    fn preflight(
+        path: &str,
+        origin: &str,
+    ) -> hyper::Request<http_body_util::Full<axum::body::Bytes>> {
+        let mut request = with_origin(readiness::get(path), origin);
+        *request.method_mut() = Method::OPTIONS;
+        let headers = request.headers_mut();
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static("POST"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("content-type,x-extra"),
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn only_allowed_origins_reach_the_endpoints() {
+        let policy = Arc::new(CorsPolicy {
+            allowed_origins: vec!["https://app.example.com/".to_string()],
+            allowed_headers: vec![HeaderName::from_static("x-extra")],
+            allow_credentials: true,
+        });
+        let socket = serve_fake("cors", |config| config.cors = policy).await;
+        let target = ProbeTarget::Unix(socket);
+        // Clients without an origin aren't browsers
+        let client = SseClient::connect(&target, "/sse").await;
+        let path = readiness::path_of(&client.endpoint);
+
+        let request = with_origin(readiness::get("/sse"), "http://evil.example");
+        let response = readiness::send(&target, request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let request = preflight(path, "http://localhost.evil.example");
+        let response = readiness::send(&target, request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = readiness::send(&target, preflight(path, "http://localhost:6274"))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:6274"
+        );
+        let allowed = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(allowed.contains("x-extra"), "{allowed}");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let initialized = r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#;
+        let origins = [
+            "https://APP.example.com",
+            "http://127.0.0.1:9",
+            "http://[::1]:80",
+            "https://localhost",
+        ];
+        for origin in origins {
+            let request = with_origin(readiness::post(path, initialized.into()), origin);
+            let response = readiness::send(&target, request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED, "{origin}");
+            assert_eq!(
+                response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+                origin
+            );
+            let exposed = response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+                .to_str()
+                .unwrap();
+            assert!(exposed.contains("retry-after"), "{exposed}");
+        }
+    }
+
+    #[tokio::test]
+    async fn credentials_are_only_allowed_for_listed_origins() {
+        let policy = Arc::new(CorsPolicy {
+            allowed_origins: vec!["*".to_string()],
+            allowed_headers: Vec::new(),
+            allow_credentials: true,
+        });
+        let socket = serve_fake("cors-any", |config| config.cors = policy).await;
+        let target = ProbeTarget::Unix(socket);
+
+        let request = with_origin(readiness::get("/sse"), "http://other.example");
+        let response = readiness::send(&target, request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://other.example"
+        );
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+}
@@ -6,6 +6,9 @@ pub enum Error {
     /// Bridge error
     #[error(transparent)]
     Bridge(#[from] blueprint_sdk::bridge::Error),
+    /// Keystore error
+    #[error(transparent)]
+    Keystore(#[from] blueprint_sdk::keystore::Error),
 
     #[error("Service {0} no longer exists")]
     ServiceNotFound(u64),
@@ -15,8 +18,26 @@ pub enum Error {
     InvalidRequestParams(#[from] blueprint_sdk::tangle::serde::error::Error),
     #[error("Invalid request params: unknown runtime")]
     UnknownRuntime,
+    #[error("Invalid owner ECDSA key: expected a 33 bytes compressed public key")]
+    InvalidOwnerKey,
     #[error("Missing port binding")]
     MissingPortBinding,
+    #[error("Invalid port range {0}: expected `start-end`")]
+    InvalidPortRange(String),
+    #[error("No free port left in the range {0}-{1}")]
+    PortsExhausted(u16, u16),
+    #[error("Port {0} is already in use")]
+    PortInUse(u16),
+    #[error("MCP server did not become ready within {0:?}: {1}")]
+    NotReady(std::time::Duration, String),
+    #[error("Invalid request params: {0}")]
+    InvalidConfig(#[from] crate::validate::ValidationErrors),
+    #[error(
+        "Invalid secret environment variable {0}: it could not be decrypted with the operator key"
+    )]
+    InvalidSecret(String),
+    #[error("The blueprint id is not configured, secrets can't be decrypted")]
+    MissingBlueprintId,
 
     /// I/O error
     #[error(transparent)]
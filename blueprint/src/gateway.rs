@@ -0,0 +1,542 @@
+//! Aggregating MCP gateway in front of the named MCP servers of a service.
+//!
+//! The service router forwards `/{name}/...` to each MCP server, so a client has to connect
+//! to every server of the bundle. The gateway is served on `/sse` of the router instead and
+//! exposes all of them as a single MCP server:
+//!
+//! - On the first request of a session, it connects to every MCP server of the service and
+//!   performs one `initialize` with each of them.
+//! - `tools/list`, `resources/list`, `resources/templates/list` and `prompts/list` are merged,
+//!   the names being prefixed with `{name}__` to avoid collisions.
+//! - `tools/call` and `prompts/get` are routed to the MCP server named by the prefix, and
+//!   `resources/read` to the MCP server that listed the resource.
+//!
+//! The upstream sessions go through the SSE server of each MCP server, so their access
+//! policy and audit still apply, and are closed with the session of the gateway. They are
+//! opened with the request headers of the client connection, so the calls are audited with
+//! the caller of the gateway session.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use axum::http::{HeaderMap, HeaderName, header};
+use futures::future::join_all;
+use futures::{SinkExt, Stream};
+use rmcp::model::{
+    CallToolRequestParam, CallToolResult, ClientJsonRpcMessage, ErrorData as McpError,
+    GetPromptRequestParam, GetPromptResult, ListPromptsResult, ListResourceTemplatesResult,
+    ListResourcesResult, ListToolsResult, PaginatedRequestParam, ReadResourceRequestParam,
+    ReadResourceResult, ServerCapabilities, ServerInfo, ServerJsonRpcMessage,
+};
+use rmcp::service::{RequestContext, RunningService, ServiceError, serve_client_with_ct};
+use rmcp::{RoleClient, RoleServer, ServerHandler};
+use tokio::sync::OnceCell;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::{CancellationToken, PollSender};
+
+use crate::readiness::{self, ProbeTarget, SseReader};
+use crate::router::Routes;
+
+/// Separates the name of the MCP server from the name of a tool, resource or prompt
+pub const NAME_SEPARATOR: &str = "__";
+
+/// The SSE path of the MCP servers
+const UPSTREAM_SSE_PATH: &str = "/sse";
+
+/// Headers of the client connection describing the connection itself rather than the caller,
+/// not forwarded to the MCP servers
+const CONNECTION_HEADERS: [HeaderName; 13] = [
+    header::HOST,
+    header::CONNECTION,
+    header::UPGRADE,
+    header::ACCEPT,
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::TRANSFER_ENCODING,
+    header::SEC_WEBSOCKET_KEY,
+    header::SEC_WEBSOCKET_VERSION,
+    header::SEC_WEBSOCKET_EXTENSIONS,
+    header::SEC_WEBSOCKET_PROTOCOL,
+    HeaderName::from_static("last-event-id"),
+    HeaderName::from_static("x-forwarded-prefix"),
+];
+
+/// An MCP server the gateway is connected to
+struct Upstream {
+    name: String,
+    client: RunningService<RoleClient, ()>,
+}
+
+/// The MCP server exposed to a client session of the gateway
+#[derive(Clone)]
+pub struct Gateway {
+    session: Arc<Session>,
+}
+
+impl Gateway {
+    /// Creates the gateway of a client session opened with `headers`, routing to the MCP
+    /// servers of `routes`.
+    pub fn new(routes: Routes, headers: &HeaderMap) -> Self {
+        let mut headers = headers.clone();
+        for name in CONNECTION_HEADERS {
+            headers.remove(name);
+        }
+        Self {
+            session: Arc::new(Session {
+                routes,
+                headers,
+                upstreams: OnceCell::new(),
+                resources: Default::default(),
+                ct: CancellationToken::new(),
+            }),
+        }
+    }
+}
+
+/// The upstream sessions of a client session
+struct Session {
+    routes: Routes,
+    /// The headers of the client connection forwarded to the MCP servers
+    headers: HeaderMap,
+    upstreams: OnceCell<Vec<Upstream>>,
+    /// The MCP server of each resource URI, as listed last
+    resources: Mutex<HashMap<String, String>>,
+    /// Closes the upstream sessions
+    ct: CancellationToken,
+}
+
+impl Session {
+    /// The MCP servers of the session, connected on first use.
+    async fn upstreams(&self) -> &[Upstream] {
+        self.upstreams.get_or_init(|| self.connect_all()).await
+    }
+
+    /// Connects to every MCP server of the service, skipping the unreachable ones.
+    async fn connect_all(&self) -> Vec<Upstream> {
+        let routes = self
+            .routes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let connections = routes.into_iter().map(|(name, endpoint)| async move {
+            let Some(target) = ProbeTarget::from_endpoint(&endpoint) else {
+                tracing::warn!(server = %name, %endpoint, "unsupported MCP server endpoint");
+                return None;
+            };
+            let transport = match connect(target, self.headers.clone(), self.ct.clone()).await {
+                Ok(transport) => transport,
+                Err(e) => {
+                    tracing::warn!(error = %e, server = %name, "failed to connect to the MCP server");
+                    return None;
+                }
+            };
+            match serve_client_with_ct((), transport, self.ct.child_token()).await {
+                Ok(client) => Some(Upstream { name, client }),
+                Err(e) => {
+                    tracing::warn!(error = %e, server = %name, "failed to initialize the MCP server");
+                    None
+                }
+            }
+        });
+        join_all(connections).await.into_iter().flatten().collect()
+    }
+
+    /// The MCP server named by the prefix of `name`, and the name on that server.
+    async fn resolve<'a>(&self, name: &'a str) -> Result<(&Upstream, &'a str), McpError> {
+        self.upstreams()
+            .await
+            .iter()
+            .filter_map(|upstream| {
+                let rest = name
+                    .strip_prefix(upstream.name.as_str())?
+                    .strip_prefix(NAME_SEPARATOR)?;
+                Some((upstream, rest))
+            })
+            // Server names may contain the separator, prefer the longest match
+            .max_by_key(|(upstream, _)| upstream.name.len())
+            .ok_or_else(|| McpError::invalid_params(format!("unknown name: {name}"), None))
+    }
+
+    /// Runs `list` on every MCP server that supports it and merges the results.
+    async fn merge<'a, T, F, Fut>(
+        &'a self,
+        supports: fn(&ServerCapabilities) -> bool,
+        list: F,
+    ) -> Vec<T>
+    where
+        F: Fn(&'a Upstream) -> Fut,
+        Fut: Future<Output = Result<Vec<T>, ServiceError>>,
+    {
+        let upstreams = self
+            .upstreams()
+            .await
+            .iter()
+            .filter(|upstream| supports(&upstream.client.peer_info().capabilities));
+        join_all(upstreams.map(|upstream| async {
+            list(upstream).await.unwrap_or_else(|e| {
+                tracing::warn!(error = %e, server = %upstream.name, "failed to list the MCP server items");
+                Vec::new()
+            })
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.ct.cancel();
+    }
+}
+
+impl ServerHandler for Gateway {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .enable_prompts()
+                .build(),
+            ..Default::default()
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        let tools = self
+            .session
+            .merge(
+                |capabilities| capabilities.tools.is_some(),
+                |upstream| async move {
+                    let mut tools = upstream.client.list_all_tools().await?;
+                    for tool in &mut tools {
+                        tool.name = Cow::Owned(prefixed(&upstream.name, &tool.name));
+                    }
+                    Ok(tools)
+                },
+            )
+            .await;
+        Ok(ListToolsResult {
+            next_cursor: None,
+            tools,
+        })
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let (upstream, name) = self.session.resolve(&request.name).await?;
+        upstream
+            .client
+            .call_tool(CallToolRequestParam {
+                name: Cow::Owned(name.to_string()),
+                arguments: request.arguments,
+            })
+            .await
+            .map_err(into_mcp_error)
+    }
+
+    async fn list_resources(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        let resources = self
+            .session
+            .merge(
+                |capabilities| capabilities.resources.is_some(),
+                |upstream| async move {
+                    let mut resources = upstream.client.list_all_resources().await?;
+                    let mut owners = self
+                        .session
+                        .resources
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    for resource in &mut resources {
+                        owners.insert(resource.raw.uri.clone(), upstream.name.clone());
+                        resource.raw.name = prefixed(&upstream.name, &resource.raw.name);
+                    }
+                    Ok(resources)
+                },
+            )
+            .await;
+        Ok(ListResourcesResult {
+            next_cursor: None,
+            resources,
+        })
+    }
+
+    async fn list_resource_templates(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourceTemplatesResult, McpError> {
+        let resource_templates = self
+            .session
+            .merge(
+                |capabilities| capabilities.resources.is_some(),
+                |upstream| async move {
+                    let mut templates = upstream.client.list_all_resource_templates().await?;
+                    for template in &mut templates {
+                        template.raw.name = prefixed(&upstream.name, &template.raw.name);
+                    }
+                    Ok(templates)
+                },
+            )
+            .await;
+        Ok(ListResourceTemplatesResult {
+            next_cursor: None,
+            resource_templates,
+        })
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        let owner = self
+            .session
+            .resources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&request.uri)
+            .cloned();
+        let upstreams = self.session.upstreams().await;
+        if let Some(upstream) = owner.and_then(|owner| upstreams.iter().find(|u| u.name == owner)) {
+            return upstream
+                .client
+                .read_resource(request)
+                .await
+                .map_err(into_mcp_error);
+        }
+        // Not listed, e.g. expanded from a template, ask every MCP server with resources
+        for upstream in upstreams
+            .iter()
+            .filter(|upstream| upstream.client.peer_info().capabilities.resources.is_some())
+        {
+            if let Ok(result) = upstream.client.read_resource(request.clone()).await {
+                return Ok(result);
+            }
+        }
+        Err(McpError::resource_not_found(
+            format!("unknown resource: {}", request.uri),
+            None,
+        ))
+    }
+
+    async fn list_prompts(
+        &self,
+        _request: PaginatedRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, McpError> {
+        let prompts = self
+            .session
+            .merge(
+                |capabilities| capabilities.prompts.is_some(),
+                |upstream| async move {
+                    let mut prompts = upstream.client.list_all_prompts().await?;
+                    for prompt in &mut prompts {
+                        prompt.name = prefixed(&upstream.name, &prompt.name);
+                    }
+                    Ok(prompts)
+                },
+            )
+            .await;
+        Ok(ListPromptsResult {
+            next_cursor: None,
+            prompts,
+        })
+    }
+
+    async fn get_prompt(
+        &self,
+        request: GetPromptRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, McpError> {
+        let (upstream, name) = self.session.resolve(&request.name).await?;
+        upstream
+            .client
+            .get_prompt(GetPromptRequestParam {
+                name: name.to_string(),
+                arguments: request.arguments,
+            })
+            .await
+            .map_err(into_mcp_error)
+    }
+}
+
+/// The name of an item of the MCP server as exposed by the gateway.
+fn prefixed(server: &str, name: &str) -> String {
+    format!("{server}{NAME_SEPARATOR}{name}")
+}
+
+fn into_mcp_error(error: ServiceError) -> McpError {
+    match error {
+        ServiceError::McpError(error) => error,
+        error => McpError::internal_error(error.to_string(), None),
+    }
+}
+
+/// Opens an SSE session with the MCP server with the given headers, closed when `ct` is
+/// cancelled.
+///
+/// The messages of the stream are read from the `message` events, and the messages of the
+/// sink are posted to the path announced by the `endpoint` event.
+async fn connect(
+    target: ProbeTarget,
+    headers: HeaderMap,
+    ct: CancellationToken,
+) -> io::Result<(
+    impl futures::Sink<ClientJsonRpcMessage, Error = io::Error> + Send + 'static,
+    impl Stream<Item = ServerJsonRpcMessage> + Send + 'static,
+)> {
+    let mut request = readiness::get(UPSTREAM_SSE_PATH);
+    request.headers_mut().extend(headers);
+    let response = readiness::send(&target, request)
+        .await
+        .map_err(io::Error::other)?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(io::Error::other(format!("SSE endpoint answered {status}")));
+    }
+    let mut events = SseReader::new(response.into_body());
+    let post_path = loop {
+        let (event, data) = events.next().await.map_err(io::Error::other)?;
+        if event == "endpoint" {
+            break readiness::path_of(&data).to_string();
+        }
+    };
+
+    let (from_server_tx, from_server_rx) = tokio::sync::mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let (event, data) = tokio::select! {
+                event = events.next() => match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        tracing::debug!(error = %e, "MCP server SSE stream closed");
+                        break;
+                    }
+                },
+                () = ct.cancelled() => break,
+            };
+            if event != "message" {
+                continue;
+            }
+            match serde_json::from_str::<ServerJsonRpcMessage>(&data) {
+                Ok(message) => {
+                    if from_server_tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "invalid message from the MCP server"),
+            }
+        }
+    });
+
+    let (to_server_tx, mut to_server_rx) = tokio::sync::mpsc::channel::<ClientJsonRpcMessage>(64);
+    tokio::spawn(async move {
+        while let Some(message) = to_server_rx.recv().await {
+            let body = match serde_json::to_vec(&message) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!(error = %e, "failed to serialize the message");
+                    continue;
+                }
+            };
+            match readiness::send(&target, readiness::post(&post_path, body.into())).await {
+                Ok(response) if !response.status().is_success() => {
+                    tracing::warn!(status = %response.status(), "MCP server rejected the message");
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to post the message");
+                    break;
+                }
+            }
+        }
+    });
+
+    let sink = PollSender::new(to_server_tx).sink_map_err(io::Error::other);
+    Ok((sink, ReceiverStream::new(from_server_rx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use rmcp::service::serve_client;
+
+    use super::*;
+    use crate::audit::{AuditArguments, AuditConfig, AuditLog};
+    use crate::router::ServiceRouter;
+    use crate::test_support::{fake_factory, temp_socket};
+    use crate::transport::{SseServer, SseServerConfig};
+
+    #[tokio::test]
+    async fn upstream_calls_are_audited_with_the_caller_of_the_gateway() {
+        let audit_path =
+            std::env::temp_dir().join(format!("mcp-gateway-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&audit_path);
+        let audit = AuditLog::open(AuditConfig {
+            path: audit_path.clone(),
+            arguments: AuditArguments::Hash,
+            redact: Vec::new(),
+            caller_headers: vec!["x-caller".to_string()],
+        })
+        .await
+        .unwrap();
+        let socket = temp_socket("gateway-upstream");
+        let config = SseServerConfig {
+            unix_socket: Some(socket.clone()),
+            audit: Some(Arc::new(audit).for_service(1)),
+            tls: None,
+            ..SseServerConfig::new(([127, 0, 0, 1], 0).into())
+        };
+        SseServer::serve_with_config(config)
+            .await
+            .unwrap()
+            .forward(fake_factory);
+        let router = ServiceRouter::serve_unix(temp_socket("gateway-router")).unwrap();
+        router.set_routes(BTreeMap::from([(
+            "fake".to_string(),
+            format!("unix://{}", socket.display()),
+        )]));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-caller", "alice".parse().unwrap());
+        let target = ProbeTarget::from_endpoint(router.endpoint()).unwrap();
+        let transport = connect(target, headers, CancellationToken::new())
+            .await
+            .unwrap();
+        let client = serve_client((), transport).await.unwrap();
+        client
+            .call_tool(CallToolRequestParam {
+                name: "fake__echo".into(),
+                arguments: None,
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let audited = std::fs::read_to_string(&audit_path).unwrap();
+        let call = audited
+            .lines()
+            .find(|line| line.contains("\"method\":\"tools/call\""))
+            .unwrap_or_else(|| panic!("no tools/call entry in {audited}"));
+        assert!(call.contains("\"caller\":\"alice\""), "{call}");
+        router.stop();
+    }
+}
@@ -1,8 +1,7 @@
-use blueprint_sdk::auth::models::ServiceOwnerModel;
 use blueprint_sdk::auth::proxy::DEFAULT_AUTH_PROXY_PORT;
-use blueprint_sdk::auth::types::KeyType;
 use blueprint_sdk::tangle::extract::{BlockHash, List, ServiceId, TangleArg};
 use blueprint_sdk::tangle::serde::from_field;
+use blueprint_sdk::tangle_subxt::subxt::utils::AccountId32;
 use blueprint_sdk::tangle_subxt::tangle_testnet_runtime::api;
 use blueprint_sdk::{
     contexts::tangle::TangleClientContext, extract::Context, tangle::extract::TangleResult,
@@ -11,6 +10,10 @@ use futures::TryFutureExt;
 
 use crate::MyContext;
 use crate::error::Error;
+use crate::registration;
+
+/// The length of a compressed secp256k1 public key
+const ECDSA_KEY_LEN: usize = 33;
 
 /// Start the configured MCP server
 pub async fn mcp_start(
@@ -46,38 +49,91 @@ pub async fn mcp_start(
         return Err(Error::MissingRequestParams);
     }
 
-    let config = from_field::<crate::RequestParams>(request_args.0.pop().unwrap())
-        .map(|p| p.config)
+    let mut params = from_field::<crate::RequestParams>(request_args.0.pop().unwrap())
         .map_err(Error::InvalidRequestParams)?;
+    // The key in the request params is used when the job is called without one
+    let ecdsa_owner = match params.owner_ecdsa_key.0.take() {
+        Some(List(key)) if ecdsa_owner.is_empty() => key,
+        _ => ecdsa_owner,
+    };
+    let ecdsa_owner = owner_ecdsa_key(ecdsa_owner)?;
 
-    blueprint_sdk::debug!(?config, %service_id, %owner, "Starting MCP server with config");
+    start_and_register(
+        &ctx,
+        service_id,
+        owner,
+        params.server_configs(),
+        ecdsa_owner,
+    )
+    .await?;
 
-    let mut mcp_server_manager = ctx.mcp_server_manager.lock().await;
-    let endpoint = mcp_server_manager
-        .start_server(&ctx, service_id, owner.clone(), config)
-        .await?;
+    Ok(TangleResult(public_endpoint()))
+}
 
-    let bridge = ctx.env.bridge().await?;
+/// Checks that the owner key is a compressed ECDSA public key, the auth proxy can't verify
+/// the owner's challenges with anything else.
+pub(crate) fn owner_ecdsa_key(key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if key.len() != ECDSA_KEY_LEN {
+        return Err(Error::InvalidOwnerKey);
+    }
+    Ok(key)
+}
 
-    bridge
-        .register_blueprint_service_proxy(
-            service_id,
-            Some("mcp_"),
-            &endpoint,
-            &[
-                ServiceOwnerModel {
-                    key_type: KeyType::Sr25519 as _,
-                    key_bytes: owner.0.to_vec(),
-                },
-                ServiceOwnerModel {
-                    key_type: KeyType::Ecdsa as _,
-                    key_bytes: ecdsa_owner,
-                },
-            ],
-        )
-        .await?;
+/// The endpoint of the MCP servers of the services, behind the auth proxy of the bridge
+pub(crate) fn public_endpoint() -> String {
+    format!("http://127.0.0.1:{DEFAULT_AUTH_PROXY_PORT}")
+}
+
+/// Starts the MCP servers of the service and registers them with the auth proxy of the bridge,
+/// stopping them again if the registration fails.
+pub(crate) async fn start_and_register(
+    ctx: &MyContext,
+    service_id: u64,
+    owner: AccountId32,
+    servers: Vec<(String, crate::McpServerConfig)>,
+    ecdsa_owner: Vec<u8>,
+) -> Result<(), Error> {
+    blueprint_sdk::debug!(?servers, %service_id, %owner, "Starting MCP servers with config");
 
-    let endpoint = format!("http://127.0.0.1:{DEFAULT_AUTH_PROXY_PORT}");
+    // Held until the endpoint is registered, so a concurrent stop of the service waits for it
+    let mut service = ctx.mcp_server_manager.lock_service(service_id).await;
+    let generation = service.generation;
+    if let Err(e) = ctx
+        .mcp_server_manager
+        .start_servers(ctx, &mut service, owner, servers)
+        .await
+    {
+        // A failed config change restores the previous servers, possibly on another endpoint,
+        // and drops their registration if they couldn't be restored
+        if service.registered {
+            let result = if service.is_running() {
+                registration::register(ctx, &mut service).await
+            } else {
+                registration::unregister(ctx, &mut service).await
+            };
+            if let Err(e) = result {
+                blueprint_sdk::error!(error = %e, %service_id, "Failed to update the registration of the restored MCP servers");
+            }
+        }
+        return Err(e);
+    }
+    service.ecdsa_owner = Some(ecdsa_owner);
+
+    if let Err(e) = registration::register(ctx, &mut service).await {
+        // Don't leave servers running that no one can reach, unless they were already running
+        if service.generation != generation {
+            blueprint_sdk::warn!(error = %e, %service_id, "Failed to register the MCP servers, stopping them");
+            if let Err(e) = ctx.mcp_server_manager.stop_all(&mut service).await {
+                blueprint_sdk::error!(error = %e, %service_id, "Failed to stop the MCP servers");
+            }
+            if service.registered
+                && let Err(e) = registration::unregister(ctx, &mut service).await
+            {
+                blueprint_sdk::error!(error = %e, %service_id, "Failed to unregister the replaced MCP server");
+            }
+        }
+        return Err(e);
+    }
 
-    Ok(TangleResult(endpoint))
+    Ok(())
 }
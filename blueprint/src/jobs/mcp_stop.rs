@@ -4,18 +4,29 @@ use blueprint_sdk::tangle::extract::{ServiceId, TangleArg};
 
 use crate::MyContext;
 use crate::error::Error;
+use crate::registration;
 
-/// Stop the configured MCP server
+/// Stop the configured MCP servers
 pub async fn mcp_stop(
     Context(ctx): Context<MyContext>,
     ServiceId(service_id): ServiceId,
     TangleArg(_): TangleArg<()>,
 ) -> Result<TangleResult<bool>, Error> {
-    let mut manager = ctx.mcp_server_manager.lock().await;
-    let stopped = manager.stop_server(service_id).await?;
-    let bridge = ctx.env.bridge().await?;
-    bridge
-        .unregister_blueprint_service_proxy(service_id)
-        .await?;
-    Ok(TangleResult(stopped))
+    let Some(mut service) = ctx.mcp_server_manager.try_lock_service(service_id).await else {
+        // Nothing was started for the service by this operator
+        return Ok(TangleResult(false));
+    };
+    // Unregister first, so a failure leaves the server running and reachable
+    registration::unregister(&ctx, &mut service).await?;
+    match ctx.mcp_server_manager.stop_all(&mut service).await {
+        Ok(stopped) => Ok(TangleResult(stopped)),
+        Err(e) => {
+            if service.is_running()
+                && let Err(e) = registration::register(&ctx, &mut service).await
+            {
+                blueprint_sdk::error!(error = %e, %service_id, "Failed to register the MCP server again");
+            }
+            Err(e)
+        }
+    }
 }
@@ -5,4 +5,5 @@ pub const MCP_START_JOB_ID: u8 = 0;
 pub const MCP_STOP_JOB_ID: u8 = 1;
 
 pub use mcp_start::mcp_start;
+pub(crate) use mcp_start::{owner_ecdsa_key, public_endpoint, start_and_register};
 pub use mcp_stop::mcp_stop;
@@ -1,24 +1,68 @@
+use crate::audit::{AuditConfig, AuditLog};
 use crate::manager::McpServerManager;
+use crate::ports::PortAllocator;
+use crate::secrets::SecretStore;
 use blueprint_sdk::macros::context::ServicesContext;
 use blueprint_sdk::runner::config::BlueprintEnvironment;
 use blueprint_sdk::tangle::extract::{List, Optional, TangleArg};
 use docktopus::bollard::Docker;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
+/// Tamper-evident audit log of the forwarded requests
+mod audit;
+/// Start of the mcp servers of the services requested with `autoStart`
+mod autostart;
+/// CORS and origin validation of the SSE endpoints
+mod cors;
 /// Different types of errors that can occur in the mcp server
 mod error;
+/// Aggregating gateway merging the mcp servers of a service into one
+mod gateway;
 /// Blueprint Jobs
 mod jobs;
+/// Cleanup of the mcp servers of the services that ended on-chain
+mod lifecycle;
 /// The mcp server manager
 mod manager;
+/// Metrics endpoint with the queue depths of the mcp servers
+mod metrics;
+/// Gateway access policy for tools, resources and prompts
+mod policy;
+/// Port allocation for the mcp servers
+mod ports;
+/// Readiness probe of the mcp servers
+mod readiness;
+/// Periodic reconciliation of the mcp servers with the bridge and the containers
+mod reconcile;
+/// Redaction of sensitive values in tracing output
+mod redact;
+/// Registration of the mcp servers with the auth proxy of the bridge
+mod registration;
+/// Routing of the named mcp servers of a service under their own path
+mod router;
+/// Secret environment variables encrypted to the operator key
+mod secrets;
+/// Helpers shared by the tests
+#[cfg(test)]
+mod test_support;
+/// TLS termination of the SSE servers
+mod tls;
 /// The MCP Transport converter
 mod transport;
+/// Validation of the mcp server configuration
+mod validate;
 
+pub use autostart::spawn_auto_start;
 pub use jobs::{MCP_START_JOB_ID, MCP_STOP_JOB_ID, mcp_start, mcp_stop};
+pub use lifecycle::spawn_lifecycle_watcher;
+pub use metrics::spawn_metrics_endpoint;
+pub use policy::{AccessList, McpServerPolicy};
+pub use reconcile::spawn_reconciler;
+pub use secrets::spawn_secrets_endpoint;
+pub use validate::{ValidationError, ValidationErrors};
 
 /// Represents the runtime of the MCP server (Python, JS, Docker etc.)
-#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum McpRuntime {
     /// Unknown runtime
@@ -30,16 +74,20 @@ pub enum McpRuntime {
     Javascript,
     /// using a docker container to run the mcp server
     Docker,
+    /// Will connect to an mcp server hosted elsewhere, over SSE or Streamable HTTP
+    Remote,
 }
 
-#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct McpServerConfig {
     /// The different runtimes that can be used to run the mcp server
     pub runtime: McpRuntime,
-    /// The package to use for the mcp server or the docker image
+    /// The package to use for the mcp server, the docker image or the url of the remote server
     ///
-    /// Example: `mcp-server@x.y.z` for Python or JS, or `mcp-server:latest` for Docker
+    /// Example: `mcp-server@x.y.z` for Python or JS, `mcp-server:latest` for Docker, or
+    /// `https://mcp.example.com/sse` (SSE) or `https://mcp.example.com/mcp` (Streamable HTTP)
+    /// for Remote
     pub package: String,
     /// A list of arguments to pass to the mcp server
     /// This is optional and can be empty
@@ -47,15 +95,80 @@ pub struct McpServerConfig {
     pub args: Optional<List<String>>,
     /// Environment variables for the MCP server
     /// This is optional and can be empty
+    ///
+    /// These are stored on-chain in plaintext, use `secret_env` for API keys and tokens.
     #[serde(default)]
     pub env: Optional<List<(String, String)>>,
+    /// Secret environment variables for the MCP server, with values encrypted to the
+    /// operator's ECDSA key (see the `secrets` module for the format)
+    /// This is optional and can be empty
+    #[serde(default)]
+    pub secret_env: Optional<List<(String, String)>>,
+    /// HTTP headers sent to the remote mcp server, only used with [`McpRuntime::Remote`]
+    /// This is optional and can be empty
+    ///
+    /// `${NAME}` in a value is replaced with the secret environment variable `NAME`, so API
+    /// keys and tokens can be sent without storing them in plaintext.
+    #[serde(default)]
+    pub headers: Optional<List<(String, String)>>,
     /// The transport adapter to use for the MCP server
     #[serde(default)]
     pub transport_adapter: SupportedTransportAdapter,
+    /// Which tools, resources and prompts of the MCP server are exposed to clients
+    /// This is optional and exposes everything by default
+    #[serde(default)]
+    pub policy: McpServerPolicy,
+    /// An HTTP path answering with a success status once the MCP server is ready
+    /// This is optional, an MCP `initialize` request is used by default
+    ///
+    /// Only used with [`SupportedTransportAdapter::None`], the gateway is always probed with
+    /// an MCP `initialize` request.
+    #[serde(default)]
+    pub health_path: Optional<String>,
+}
+
+/// Sensitive `env` values and `args` are redacted, see the `redact` module.
+impl std::fmt::Debug for McpServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let args = redact::Args::from(self.args.0.clone().unwrap_or_default().0);
+        let env: redact::EnvVars = self
+            .env
+            .0
+            .clone()
+            .unwrap_or_default()
+            .0
+            .into_iter()
+            .collect();
+        let headers: redact::EnvVars = self
+            .headers
+            .0
+            .clone()
+            .unwrap_or_default()
+            .0
+            .into_iter()
+            .collect();
+        let secret_env: Vec<&String> = self
+            .secret_env
+            .0
+            .iter()
+            .flat_map(|l| l.0.iter().map(|(name, _)| name))
+            .collect();
+        f.debug_struct("McpServerConfig")
+            .field("runtime", &self.runtime)
+            .field("package", &self.package)
+            .field("args", &args)
+            .field("env", &env)
+            .field("secret_env", &secret_env)
+            .field("headers", &headers)
+            .field("transport_adapter", &self.transport_adapter)
+            .field("policy", &self.policy)
+            .field("health_path", &self.health_path.0)
+            .finish()
+    }
 }
 
 /// The supported transport adapters for the MCP server
-#[derive(Default, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 #[non_exhaustive]
 pub enum SupportedTransportAdapter {
@@ -88,6 +201,48 @@ impl SupportedTransportAdapter {
 #[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RequestParams {
+    /// The mcp server of the service, used when `servers` is empty
+    #[serde(default)]
+    pub config: McpServerConfig,
+    /// Named mcp servers served together by the service, each under `/{name}` on its endpoint
+    /// This is optional and can be empty
+    #[serde(default)]
+    pub servers: Optional<List<NamedMcpServerConfig>>,
+    /// Start the mcp server as soon as the service is initialized, without calling the
+    /// start job
+    #[serde(default)]
+    pub auto_start: bool,
+    /// The ECDSA key of the owner allowed to access the mcp server through the auth proxy,
+    /// as 33 bytes compressed public key
+    /// Required with `auto_start`, and used when the start job is called without a key
+    #[serde(default)]
+    pub owner_ecdsa_key: Optional<List<u8>>,
+}
+
+impl RequestParams {
+    /// The configs of the mcp servers of the service by name, `config` is named
+    /// [`DEFAULT_SERVER_NAME`] when no `servers` are given.
+    pub fn server_configs(self) -> Vec<(String, McpServerConfig)> {
+        let servers = self.servers.0.map(|l| l.0).unwrap_or_default();
+        if servers.is_empty() {
+            return vec![(DEFAULT_SERVER_NAME.to_string(), self.config)];
+        }
+        servers.into_iter().map(|s| (s.name, s.config)).collect()
+    }
+}
+
+/// The name of the mcp server of a service defined with `config` only
+pub const DEFAULT_SERVER_NAME: &str = "default";
+
+/// An mcp server of a service bundling several of them
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamedMcpServerConfig {
+    /// The name of the mcp server, the path segment it is served under
+    ///
+    /// Example: `github`, served under `/github/sse`
+    pub name: String,
+    /// The config of the mcp server
     pub config: McpServerConfig,
 }
 
@@ -95,8 +250,12 @@ pub struct RequestParams {
 pub struct MyContext {
     #[config]
     env: BlueprintEnvironment,
-    pub mcp_server_manager: Arc<Mutex<McpServerManager>>,
+    pub mcp_server_manager: Arc<McpServerManager>,
     pub docker: Arc<Docker>,
+    /// The audit log, enabled by the operator with `MCP_AUDIT_LOG`
+    pub audit: Option<Arc<AuditLog>>,
+    /// Encrypted secrets supplied off-chain through the secrets endpoint
+    pub secrets: SecretStore,
 }
 
 impl MyContext {
@@ -106,54 +265,21 @@ impl MyContext {
                 "Failed to create Docker client: {e}"
             )))
         })?;
+        let audit = match AuditConfig::from_env() {
+            Some(config) => Some(Arc::new(AuditLog::open(config).await?)),
+            None => None,
+        };
         Ok(Self {
             env,
-            mcp_server_manager: Arc::new(Mutex::new(McpServerManager::default())),
+            mcp_server_manager: Arc::new(McpServerManager::new(
+                PortAllocator::from_env()?,
+                std::env::var_os("MCP_SOCKET_DIR").map(Into::into),
+            )),
             docker: docker_builder.client(),
+            audit,
+            secrets: SecretStore::default(),
         })
     }
-    /// Finds the next available port by binding to localhost:0 and retrieving the assigned port.
-    ///
-    /// This function uses the OS's ability to assign an available port when binding to port 0.
-    /// The TCP listener is immediately closed after retrieving the port number.
-    ///
-    /// # Important: Race Condition Warning
-    ///
-    /// There is an inherent race condition in this approach: between the time the port is
-    /// released (when the listener is dropped) and when the caller attempts to use the port,
-    /// another process may bind to the same port. This is a fundamental limitation of this
-    /// approach and should be considered when using this function, but this scenario is
-    /// unlikely in practice for most applications.
-    ///
-    /// For critical applications, consider keeping the listener alive and passing it to the
-    /// caller, or using a more sophisticated port management strategy.
-    ///
-    /// # Returns
-    ///
-    /// Returns the port number that was available at the time of testing.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if:
-    /// - Unable to bind to localhost (network issues, permission problems)
-    /// - Unable to retrieve the local address from the bound socket
-    pub async fn next_available_port(&self) -> Result<u16, error::Error> {
-        let tcp = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
-            .await
-            .map_err(|e| {
-                error::Error::Io(std::io::Error::other(format!(
-                    "Failed to bind TCP listener: {e}"
-                )))
-            })?;
-        let local_addr = tcp.local_addr().map_err(|e| {
-            error::Error::Io(std::io::Error::other(format!(
-                "Failed to get local address: {e}"
-            )))
-        })?;
-        // Close the listener immediately after getting the port
-        drop(tcp);
-        Ok(local_addr.port())
-    }
 }
 
 /// The request parameters for this blueprint
@@ -0,0 +1,134 @@
+//! Cleanup of the MCP servers of the services that ended on-chain.
+//!
+//! A service instance can be terminated by its owner, expire when its TTL runs out, or drop
+//! the operator. The MCP server would otherwise keep running until `mcp_stop` is called, which
+//! can't happen anymore once the instance is gone. The lifecycle watcher follows the finalized
+//! blocks and, for every service with an MCP server, stops it when:
+//!
+//! - a `ServiceTerminated` event is emitted for the service,
+//! - the service instance no longer exists (terminated or expired),
+//! - the service is no longer in the profile of the operator (the operator was removed).
+//!
+//! The bridge registration, port, Unix domain socket directory and off-chain secrets of the
+//! service are removed with it, and the manager forgets the service.
+
+use std::time::Duration;
+
+use blueprint_sdk::contexts::tangle::TangleClientContext;
+use blueprint_sdk::crypto::sp_core::SpSr25519;
+use blueprint_sdk::crypto::tangle_pair_signer::TanglePairSigner;
+use blueprint_sdk::keystore::backends::Backend;
+use blueprint_sdk::tangle_subxt::subxt::utils::AccountId32;
+use blueprint_sdk::tangle_subxt::tangle_testnet_runtime::api;
+use futures::{StreamExt, TryFutureExt};
+
+use crate::error::Error;
+use crate::{MyContext, registration};
+
+/// How long to wait before subscribing again to the finalized blocks after an error
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/// Spawns the lifecycle watcher.
+pub async fn spawn_lifecycle_watcher(ctx: &MyContext) -> Result<(), Error> {
+    let public = ctx.env.keystore().first_local::<SpSr25519>()?;
+    let pair = ctx.env.keystore().get_secret::<SpSr25519>(&public)?;
+    let operator = TanglePairSigner::new(pair.0).account_id().clone();
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = watch(&ctx, &operator).await {
+                blueprint_sdk::warn!(error = %e, "Service lifecycle watcher failed, resubscribing");
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    });
+    Ok(())
+}
+
+/// Follows the finalized blocks until the subscription ends.
+async fn watch(ctx: &MyContext, operator: &AccountId32) -> Result<(), Error> {
+    let client = ctx
+        .env
+        .tangle_client()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?;
+    let mut blocks = client
+        .blocks()
+        .subscribe_finalized()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?;
+    tracing::info!("Watching the service lifecycle events");
+    while let Some(block) = blocks.next().await {
+        let block = block.map_err(Into::into).map_err(Error::Sdk)?;
+        let events = block
+            .events()
+            .map_err(Into::into)
+            .map_err(Error::Sdk)
+            .await?;
+        for event in events.find::<api::services::events::ServiceTerminated>() {
+            let event = event.map_err(Into::into).map_err(Error::Sdk)?;
+            terminate_service(ctx, event.service_id, "the service was terminated").await;
+        }
+
+        let storage = client.storage().at(block.hash());
+        let profile = storage
+            .fetch(
+                &api::storage()
+                    .services()
+                    .operators_profile(operator.clone()),
+            )
+            .map_err(Into::into)
+            .map_err(Error::Sdk)
+            .await?;
+        for service_id in ctx.mcp_server_manager.service_ids() {
+            let instance = storage
+                .fetch(&api::storage().services().instances(service_id))
+                .map_err(Into::into)
+                .map_err(Error::Sdk)
+                .await?;
+            if instance.is_none() {
+                terminate_service(ctx, service_id, "the service no longer exists").await;
+            } else if profile
+                .as_ref()
+                .is_some_and(|profile| !profile.services.0.contains(&service_id))
+            {
+                terminate_service(ctx, service_id, "the operator was removed from the service")
+                    .await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stops the MCP server of the service and removes everything kept for it.
+#[tracing::instrument(skip(ctx))]
+async fn terminate_service(ctx: &MyContext, service_id: u64, reason: &str) {
+    let Some(mut service) = ctx.mcp_server_manager.try_lock_service(service_id).await else {
+        // Never run by this operator, only secrets may have been supplied for it
+        ctx.secrets.write().await.remove(&service_id);
+        return;
+    };
+    blueprint_sdk::info!("Cleaning up the MCP servers of the ended service");
+    if service.registered
+        && let Err(e) = registration::unregister(ctx, &mut service).await
+    {
+        blueprint_sdk::error!(error = %e, "Failed to unregister the MCP server");
+    }
+    if let Err(e) = ctx.mcp_server_manager.stop_all(&mut service).await {
+        blueprint_sdk::error!(error = %e, "Failed to stop the MCP servers");
+        // Keep the entry, the reconciliation retries once the server exited
+        return;
+    }
+    if let Some(dir) = &ctx.mcp_server_manager.socket_dir {
+        let dir = dir.join(service_id.to_string());
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            blueprint_sdk::warn!(error = %e, dir = %dir.display(), "Failed to remove the socket directory");
+        }
+    }
+    ctx.secrets.write().await.remove(&service_id);
+    ctx.mcp_server_manager.remove_service(&mut service);
+}
@@ -2,14 +2,44 @@ use docktopus::bollard::image::{CreateImageOptions, ListImagesOptions};
 use docktopus::bollard::models::PortBinding;
 use docktopus::bollard::secret::{RestartPolicy, RestartPolicyNameEnum};
 use futures::{FutureExt, StreamExt, TryFutureExt};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::HashMap;
+use std::path::Path;
 use tokio::process::Command;
 use tokio_util::sync::CancellationToken;
 
 use crate::SupportedTransportAdapter;
 use crate::error::Error;
 use crate::manager::McpRunner;
-use crate::transport::SseServer;
+use crate::redact::{Args, EnvVars};
+use crate::transport::{SseServer, SseServerConfig, prepare_unix_socket};
+
+/// Where the Unix domain socket directory of the service is mounted in the container
+///
+/// The container may run as any user: it is added to the group of the directory, which is
+/// group-writable and setgid, so the socket it creates there belongs to the operator group.
+/// The image must create the socket group-writable (e.g. with a `0007` umask) for the auth
+/// proxy to connect to it.
+const CONTAINER_SOCKET_DIR: &str = "/run/mcp";
+
+/// The mode of a socket directory shared with a container: group-writable and setgid
+const SHARED_SOCKET_DIR_MODE: u32 = 0o2770;
+
+/// The prefix of the names of the containers of the MCP servers
+pub const CONTAINER_NAME_PREFIX: &str = "mcp-server-";
+
+/// The name of the container of the named MCP server of a service
+pub fn container_name(service_id: u64, name: &str) -> String {
+    format!("{CONTAINER_NAME_PREFIX}{service_id}-{name}")
+}
+
+/// The service id and server name of a container of an MCP server
+pub fn parse_container_name(container_name: &str) -> Option<(u64, &str)> {
+    let (service_id, name) = container_name
+        .trim_start_matches('/')
+        .strip_prefix(CONTAINER_NAME_PREFIX)?
+        .split_once('-')?;
+    Some((service_id.parse().ok()?, name))
+}
 
 /// Docker runner
 #[derive(Debug, Clone)]
@@ -248,6 +278,46 @@ impl DockerRunner {
         Ok(())
     }
 
+    /// Remove a container left over with the given name
+    ///
+    /// Container names are derived from the service id, so a container that outlived a crash
+    /// of the blueprint, or one still being stopped after its server was replaced, would make
+    /// `create_container` fail with a name conflict.
+    ///
+    /// # Arguments
+    /// * `docker_client` - A reference to the bollard Docker client
+    /// * `name` - The name of the container to remove
+    ///
+    /// # Returns
+    /// * `Ok(())` - If the container was removed or did not exist
+    /// * `Err(Error)` - If there was an error removing the container
+    pub(crate) async fn remove_stale_container(
+        &self,
+        docker_client: &docktopus::bollard::Docker,
+        name: &str,
+    ) -> Result<(), Error> {
+        use docktopus::bollard::container::RemoveContainerOptions;
+        use docktopus::bollard::errors::Error as DockerError;
+
+        let options = RemoveContainerOptions {
+            force: true,
+            v: true,
+            link: false,
+        };
+        match docker_client.remove_container(name, Some(options)).await {
+            Ok(()) => {
+                blueprint_sdk::debug!(?name, "Removed stale Docker container");
+                Ok(())
+            }
+            Err(DockerError::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(e) => Err(Error::Io(std::io::Error::other(format!(
+                "Failed to remove stale Docker container {name}: {e}"
+            )))),
+        }
+    }
+
     /// Inspect a Docker image and extract exposed ports
     ///
     /// This method queries the Docker daemon to get the image configuration
@@ -300,15 +370,17 @@ impl DockerRunner {
 }
 
 impl McpRunner for DockerRunner {
-    #[tracing::instrument(skip(self, ctx), fields(%package, args, service_id, env_vars, runtime = "docker"))]
+    #[tracing::instrument(skip(self, ctx, sse_config), fields(%package, ?args, service_id, ?env_vars, runtime = "docker"))]
     async fn start(
         &self,
         ctx: &crate::MyContext,
         service_id: u64,
+        name: &str,
         package: String,
-        args: Vec<String>,
-        mut env_vars: BTreeMap<String, String>,
+        args: Args,
+        mut env_vars: EnvVars,
         transport_adapter: SupportedTransportAdapter,
+        sse_config: SseServerConfig,
     ) -> Result<CancellationToken, Error> {
         // Ensure Docker is available
         let mut checked = self.check(ctx).await;
@@ -349,12 +421,46 @@ impl McpRunner for DockerRunner {
         };
         use docktopus::bollard::models::HostConfig;
 
-        // Only configure port bindings if the image exposes ports
-        let port_bindings_map = if let Some(&container_port) = exposed_ports.first() {
-            blueprint_sdk::debug!(%container_port, %allocated_port, "Configuring port mapping");
-            // Set the PORT environment variable for the container that will be used by the MCP server
+        // Set the PORT environment variable for the container that will be used by the MCP server
+        if let Some(container_port) = exposed_ports.first() {
             env_vars.insert("PORT".to_string(), container_port.to_string());
+        }
 
+        // Without the gateway and with Unix domain sockets enabled, the socket directory is
+        // mounted in the container and the MCP server listens on the socket in `MCP_SOCKET`
+        // instead of publishing a port
+        let mounted_socket = sse_config
+            .unix_socket
+            .clone()
+            .filter(|_| transport_adapter.is_none());
+        let mut socket_group = None;
+        let binds = match &mounted_socket {
+            Some(path) => {
+                let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+                    return Err(Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid Unix domain socket path {}", path.display()),
+                    )));
+                };
+                prepare_unix_socket(path)?;
+                socket_group = Some(share_socket_dir(dir)?);
+                let container_socket = Path::new(CONTAINER_SOCKET_DIR).join(file_name);
+                env_vars.insert(
+                    "MCP_SOCKET".to_string(),
+                    container_socket.display().to_string(),
+                );
+                Some(vec![format!("{}:{CONTAINER_SOCKET_DIR}", dir.display())])
+            }
+            None => None,
+        };
+
+        // Only configure port bindings if the image exposes ports and is reached directly,
+        // behind the gateway the allocated port is served by the gateway itself
+        let direct_port = exposed_ports
+            .first()
+            .filter(|_| transport_adapter.is_none() && mounted_socket.is_none());
+        let port_bindings_map = if let Some(&container_port) = direct_port {
+            blueprint_sdk::debug!(%container_port, %allocated_port, "Configuring port mapping");
             let mut port_bindings_map: HashMap<String, Option<Vec<PortBinding>>> = HashMap::new();
             let port_binding = PortBinding {
                 host_ip: Some("127.0.0.1".to_string()),
@@ -363,9 +469,10 @@ impl McpRunner for DockerRunner {
             port_bindings_map.insert(format!("{container_port}/tcp"), Some(vec![port_binding]));
             Some(port_bindings_map)
         } else {
-            blueprint_sdk::debug!(?package, "No exposed ports found, skipping port mapping");
+            blueprint_sdk::debug!(?package, "No directly exposed ports, skipping port mapping");
             None
         };
+        let binds_host_port = port_bindings_map.is_some();
 
         // Convert environment variables to Vec<String> format
         let env: Vec<String> = env_vars.iter().map(|(k, v)| format!("{k}={v}")).collect();
@@ -373,12 +480,14 @@ impl McpRunner for DockerRunner {
         // Create container configuration with port bindings
         let config = Config {
             image: Some(package.clone()),
-            cmd: Some(args),
+            cmd: Some(args.into_inner()),
             env: Some(env),
             attach_stdin: Some(true),
             attach_stdout: Some(true),
             host_config: Some(HostConfig {
                 port_bindings: port_bindings_map,
+                binds,
+                group_add: socket_group.map(|gid| vec![gid.to_string()]),
                 restart_policy: Some(RestartPolicy {
                     name: Some(RestartPolicyNameEnum::ON_FAILURE),
                     maximum_retry_count: None,
@@ -389,11 +498,16 @@ impl McpRunner for DockerRunner {
             ..Default::default()
         };
 
+        // Remove any container left with the same name before creating the new one
+        let container_name = container_name(service_id, name);
+        self.remove_stale_container(&docker_client, &container_name)
+            .await?;
+
         // Create the container directly using bollard
         let create_response = docker_client
             .create_container(
                 Some(CreateContainerOptions {
-                    name: format!("mcp-server-{service_id}"),
+                    name: container_name,
                     platform: None,
                 }),
                 config,
@@ -408,15 +522,28 @@ impl McpRunner for DockerRunner {
         let container_id = create_response.id;
         blueprint_sdk::debug!(?container_id, "Created Docker container");
 
-        // Start the container
-        docker_client
+        // Start the container, the reserved port must be free for Docker to bind it
+        let reservation = sse_config.reservation.as_ref().filter(|_| binds_host_port);
+        if let Some(reservation) = reservation {
+            reservation.release();
+        }
+        if let Err(e) = docker_client
             .start_container(&container_id, None::<StartContainerOptions<String>>)
             .await
-            .map_err(|e| {
-                Error::Io(std::io::Error::other(format!(
-                    "Failed to start Docker container: {e}"
-                )))
-            })?;
+        {
+            let _ = self
+                .remove_stale_container(&docker_client, &container_id)
+                .await;
+            let message = e.to_string();
+            if message.contains("port is already allocated")
+                || message.contains("address already in use")
+            {
+                return Err(Error::PortInUse(allocated_port));
+            }
+            return Err(Error::Io(std::io::Error::other(format!(
+                "Failed to start Docker container: {message}"
+            ))));
+        }
 
         blueprint_sdk::debug!(?container_id, "Started Docker container");
 
@@ -456,10 +583,9 @@ impl McpRunner for DockerRunner {
         };
 
         let ct = match transport_adapter {
-            SupportedTransportAdapter::StdioToSSE => {
-                let endpoint = format!("http://127.0.0.1:{allocated_port}");
-                SseServer::serve(endpoint.parse()?).await?.forward(factory)
-            }
+            SupportedTransportAdapter::StdioToSSE => SseServer::serve_with_config(sse_config)
+                .await?
+                .forward(factory),
             SupportedTransportAdapter::None => CancellationToken::new(),
         };
 
@@ -495,6 +621,9 @@ impl McpRunner for DockerRunner {
                     "Failed to remove Docker container"
                 );
             }
+            if let Some(path) = mounted_socket {
+                let _ = std::fs::remove_file(path);
+            }
         });
 
         Ok(ct)
@@ -517,6 +646,15 @@ impl McpRunner for DockerRunner {
     }
 }
 
+/// Lets the container create its socket in `dir`, whatever user it runs as, and returns the
+/// group id it must be added to.
+fn share_socket_dir(dir: &Path) -> std::io::Result<u32> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(SHARED_SOCKET_DIR_MODE))?;
+    Ok(std::fs::metadata(dir)?.gid())
+}
+
 struct DockerTransport {
     results: docktopus::bollard::container::AttachContainerResults,
 }
@@ -1,13 +1,13 @@
 use futures::TryFutureExt;
 use rmcp::transport::TokioChildProcess;
-use std::collections::BTreeMap;
 use tokio::process::Command;
 use tokio_util::sync::CancellationToken;
 
 use crate::SupportedTransportAdapter;
 use crate::error::Error;
 use crate::manager::McpRunner;
-use crate::transport::SseServer;
+use crate::redact::{Args, EnvVars};
+use crate::transport::{SseServer, SseServerConfig};
 
 /// JavaScript runner
 ///
@@ -16,15 +16,17 @@ use crate::transport::SseServer;
 pub struct JsRunner;
 
 impl McpRunner for JsRunner {
-    #[tracing::instrument(skip(self, ctx), fields(%package, args, port_bindings, runtime = "js"))]
+    #[tracing::instrument(skip(self, ctx, sse_config), fields(%package, ?args, ?env_vars, port_bindings, runtime = "js"))]
     async fn start(
         &self,
         ctx: &crate::MyContext,
         service_id: u64,
+        name: &str,
         package: String,
-        args: Vec<String>,
-        env_vars: BTreeMap<String, String>,
+        args: Args,
+        env_vars: EnvVars,
         transport_adapter: SupportedTransportAdapter,
+        sse_config: SseServerConfig,
     ) -> Result<CancellationToken, Error> {
         // Ensure bun is installed
         let mut checked = self.check(ctx).await;
@@ -42,24 +44,20 @@ impl McpRunner for JsRunner {
             }
         }
 
-        let allocated_port = env_vars
-            .get("PORT")
-            .and_then(|p| p.parse::<u16>().ok())
-            .ok_or(Error::MissingPortBinding)?;
-        let endpoint = format!("http://127.0.0.1:{allocated_port}");
-
         let factory = move || {
             let mut cmd = Command::new("bunx");
             cmd.arg("-y")
                 .arg(&package)
                 .arg("--")
-                .args(&args)
-                .envs(&env_vars)
+                .args(args.iter())
+                .envs(env_vars.iter())
                 .kill_on_drop(true);
             let transport = TokioChildProcess::new(&mut cmd);
             futures::future::ready(transport)
         };
-        let ct = SseServer::serve(endpoint.parse()?).await?.forward(factory);
+        let ct = SseServer::serve_with_config(sse_config)
+            .await?
+            .forward(factory);
         Ok(ct)
     }
 
@@ -7,17 +7,29 @@
 //! 1. Python (using uvx)
 //! 2. Javascript (using bunx)
 //! 3. Docker (using docker)
+//! 4. Remote (connecting to an MCP server hosted elsewhere)
 //!
 //! The MCP servers can be run in the background and the endpoint will be returned
 //! to the caller.
 
 use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, MutexGuard, PoisonError};
 
 use blueprint_sdk::tangle_subxt::subxt::utils::AccountId32;
+use sha2::{Digest, Sha256};
 use tokio_util::sync::CancellationToken;
 
 use crate::error::Error;
-use crate::{McpRuntime, SupportedTransportAdapter};
+use crate::ports::{MAX_PORT_ATTEMPTS, PortAllocator};
+use crate::readiness::{self, Probe, ProbeTarget};
+use crate::redact::{Args, EnvVars};
+use crate::router::ServiceRouter;
+use crate::secrets::{OperatorKey, SecretScope};
+use crate::tls;
+use crate::transport::{QueueDepths, QueueMetrics, SseServerConfig};
+use crate::validate::{HeaderSegment, header_segments};
+use crate::{DEFAULT_SERVER_NAME, McpRuntime, SupportedTransportAdapter};
 
 /// TBD
 pub mod docker;
@@ -25,17 +37,103 @@ pub mod docker;
 pub mod js;
 /// Uses uvx to run the mcp server
 pub mod python;
+/// Connects to a remote mcp server over SSE or Streamable HTTP
+pub mod remote;
 
-#[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
+/// The directory of the sockets the mcp servers served over TLS are reached on by the operator,
+/// created only accessible by the operator user
+static INTERNAL_SOCKET_DIR: LazyLock<PathBuf> =
+    LazyLock::new(|| std::env::temp_dir().join(format!("mcp-blueprint-{}", uuid::Uuid::new_v4())));
+
+/// Manages the mcp servers of all the services
+///
+/// A service runs one or more named mcp servers. Each service has its own entry and lock, so
+/// independent services start and stop in parallel while conflicting operations on the same
+/// service are serialized.
+#[derive(Default, Debug)]
 pub struct McpServerManager {
-    /// Service Id to the McpServer mapping
-    pub servers: BTreeMap<u64, McpServer>,
-    /// Mapping of service id to the owner
-    pub owners: BTreeMap<u64, AccountId32>,
-    /// Mapping of service id to the endpoint
-    pub endpoints: BTreeMap<u64, String>,
+    /// Service Id to its entry
+    services: std::sync::Mutex<BTreeMap<u64, Arc<tokio::sync::Mutex<ServiceEntry>>>>,
+    /// The ports allocated to the services
+    ports: std::sync::Mutex<PortAllocator>,
+    /// The queue metrics of the running mcp servers by service id and name, read without
+    /// locking the services
+    queues: std::sync::Mutex<BTreeMap<(u64, String), QueueMetrics>>,
+    /// Where the per-service Unix domain sockets are created, enabled by the operator with
+    /// `MCP_SOCKET_DIR`; the servers are only reachable on TCP when unset
+    pub socket_dir: Option<PathBuf>,
+}
+
+/// The lifecycle state of the mcp server of a service
+#[derive(Default, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum McpServerState {
+    /// No mcp server is running for the service
+    #[default]
+    Pending,
+    /// The runtime is being installed and the mcp server started
+    Starting,
+    /// The mcp server is running
+    Running,
+    /// The mcp server is being stopped
+    Stopping,
+    /// The mcp server failed to start, with the error
+    Failed(String),
+}
+
+/// The mcp servers of a service, locked while an operation on them is in progress
+#[derive(Default, Debug)]
+pub struct ServiceEntry {
+    /// The service id
+    pub service_id: u64,
+    /// The owner of the service
+    pub owner: Option<AccountId32>,
+    /// The ECDSA key of the owner, allowed to access the mcp servers through the auth proxy
+    pub ecdsa_owner: Option<Vec<u8>>,
+    /// The endpoint registered with the auth proxy, the one of the default mcp server or of the
+    /// router in front of the named ones
+    pub endpoint: Option<String>,
+    /// Whether the endpoint is registered with the auth proxy of the bridge
+    pub registered: bool,
+    /// Incremented every time mcp servers are started, to tell whether a start replaced them
+    pub generation: u64,
+    /// The mcp servers of the service by name
+    pub servers: BTreeMap<String, ServerEntry>,
+    /// The router serving the named mcp servers under their own path
+    pub router: Option<ServiceRouter>,
+    /// Set once the entry is removed from the manager, the operations that were waiting for
+    /// it must lock the service again
+    pub removed: bool,
+}
+
+impl ServiceEntry {
+    /// Whether any mcp server of the service is running.
+    pub fn is_running(&self) -> bool {
+        self.servers
+            .values()
+            .any(|server| server.state == McpServerState::Running && server.server.is_some())
+    }
 }
 
+/// A named mcp server of a service
+#[derive(Default, Debug)]
+pub struct ServerEntry {
+    /// The lifecycle state of the mcp server
+    pub state: McpServerState,
+    /// The endpoint of the mcp server
+    pub endpoint: Option<String>,
+    /// The endpoint the router forwards to, a Unix domain socket when the endpoint of the mcp
+    /// server is served over TLS
+    pub internal_endpoint: Option<String>,
+    /// The running mcp server
+    pub server: Option<McpServer>,
+    /// The config the mcp server was last started with, restored if replacing it fails
+    pub config: Option<crate::McpServerConfig>,
+}
+
+/// Exclusive access to the entry of a service
+pub type ServiceGuard = tokio::sync::OwnedMutexGuard<ServiceEntry>;
+
 #[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
 pub struct McpServer {
     /// Runtime of the mcp server
@@ -44,27 +142,41 @@ pub struct McpServer {
     pub package: String,
     /// A list of arguments to pass to the mcp server
     #[serde(default)]
-    pub args: Vec<String>,
+    pub args: Args,
     /// Environment variables to pass to the mcp server
     #[serde(default)]
-    pub env_vars: BTreeMap<String, String>,
+    pub env_vars: EnvVars,
+    /// Digest of the config and secrets the mcp server was started with,
+    /// used to tell a repeated start apart from a config change
+    #[serde(default)]
+    pub config_digest: String,
 
     /// The cancellation token for the mcp server
     #[serde(skip)]
     pub cancellation_token: Option<CancellationToken>,
+    /// The depths of the queues of the gateway in front of the mcp server
+    #[serde(skip)]
+    pub queue_metrics: QueueMetrics,
 }
 
 pub trait McpRunner {
     /// Start the mcp server
-    /// Returns (CancellationToken, endpoint)
+    ///
+    /// `sse_config` is the configuration of the gateway in front of the mcp server,
+    /// used when the transport adapter is [`SupportedTransportAdapter::StdioToSSE`].
+    ///
+    /// Returns the CancellationToken that stops the mcp server
+    #[allow(clippy::too_many_arguments)]
     async fn start(
         &self,
         ctx: &crate::MyContext,
         service_id: u64,
+        name: &str,
         package: String,
-        args: Vec<String>,
-        env_vars: BTreeMap<String, String>,
+        args: Args,
+        env_vars: EnvVars,
         transport_adapter: SupportedTransportAdapter,
+        sse_config: SseServerConfig,
     ) -> Result<CancellationToken, Error>;
 
     /// Check if the runtime is installed and available
@@ -75,116 +187,638 @@ pub trait McpRunner {
 }
 
 impl McpServerManager {
-    #[tracing::instrument(skip(self, ctx, config), fields(service_id, %owner))]
-    pub async fn start_server(
-        &mut self,
+    pub fn new(ports: PortAllocator, socket_dir: Option<PathBuf>) -> Self {
+        Self {
+            services: Default::default(),
+            ports: std::sync::Mutex::new(ports),
+            queues: Default::default(),
+            socket_dir,
+        }
+    }
+
+    /// Locks the entry of the service, creating it if needed and waiting for the operation in
+    /// progress on it, if any.
+    pub async fn lock_service(&self, service_id: u64) -> ServiceGuard {
+        loop {
+            let entry = self
+                .services
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(service_id)
+                .or_insert_with(|| {
+                    Arc::new(tokio::sync::Mutex::new(ServiceEntry {
+                        service_id,
+                        ..Default::default()
+                    }))
+                })
+                .clone();
+            let service = entry.lock_owned().await;
+            if !service.removed {
+                return service;
+            }
+        }
+    }
+
+    /// Locks the entry of the service if the manager knows it, waiting for the operation in
+    /// progress on it, if any.
+    ///
+    /// Returns `None` for a service this operator never ran, or that was removed meanwhile.
+    pub async fn try_lock_service(&self, service_id: u64) -> Option<ServiceGuard> {
+        let entry = self
+            .services
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&service_id)?
+            .clone();
+        let service = entry.lock_owned().await;
+        (!service.removed).then_some(service)
+    }
+
+    /// The ids of all the services known to the manager.
+    pub fn service_ids(&self) -> Vec<u64> {
+        self.services
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    /// Forgets the locked service once its mcp servers are stopped, it is created again by
+    /// the next [`Self::lock_service`].
+    pub fn remove_service(&self, service: &mut ServiceEntry) {
+        service.removed = true;
+        self.services
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&service.service_id);
+        self.ports().release_service(service.service_id);
+    }
+
+    fn ports(&self) -> MutexGuard<'_, PortAllocator> {
+        self.ports.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn queues(&self) -> MutexGuard<'_, BTreeMap<(u64, String), QueueMetrics>> {
+        self.queues.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The queue depths of the running mcp servers, by service id and name.
+    pub fn queue_depths(&self) -> Vec<(u64, String, QueueDepths)> {
+        self.queues()
+            .iter()
+            .map(|((service_id, name), metrics)| (*service_id, name.clone(), metrics.snapshot()))
+            .collect()
+    }
+
+    /// Start the named MCP servers of the locked service, replacing the running ones whose
+    /// config changed and stopping the ones that are no longer part of it.
+    ///
+    /// Returns the endpoint of the service: the one of the MCP server if it's the only one and
+    /// named [`DEFAULT_SERVER_NAME`], otherwise the one of the router serving each MCP server
+    /// under `/{name}`.
+    #[tracing::instrument(skip(self, ctx, service, servers), fields(service_id = service.service_id, %owner))]
+    pub async fn start_servers(
+        &self,
         ctx: &crate::MyContext,
-        service_id: u64,
+        service: &mut ServiceEntry,
         owner: AccountId32,
-        config: crate::McpServerConfig,
+        servers: Vec<(String, crate::McpServerConfig)>,
     ) -> Result<String, Error> {
+        crate::validate::validate_servers(&servers)?;
+
+        // The running servers, restored if the new ones fail to start
+        let previous: Vec<(String, crate::McpServerConfig)> = service
+            .servers
+            .iter()
+            .filter(|(_, server)| server.state == McpServerState::Running)
+            .filter_map(|(name, server)| Some((name.clone(), server.config.clone()?)))
+            .collect();
+
+        let removed: Vec<String> = service
+            .servers
+            .keys()
+            .filter(|name| !servers.iter().any(|(n, _)| n == *name))
+            .cloned()
+            .collect();
+        for name in removed {
+            self.stop_server(service, &name).await?;
+            service.servers.remove(&name);
+        }
+
+        let mut started = Vec::new();
+        let mut result = Ok(());
+        for (name, config) in &servers {
+            match self.start_server(ctx, service, name, config.clone()).await {
+                Ok(true) => started.push(name.clone()),
+                Ok(false) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let result = match result {
+            Ok(()) => self.service_endpoint(service),
+            Err(e) => Err(e),
+        };
+        let endpoint = match result {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                // Don't leave half of the new servers running, go back to the previous ones
+                self.restore_servers(ctx, service, previous).await;
+                return Err(e);
+            }
+        };
+
+        if !started.is_empty() {
+            service.generation += 1;
+        }
+        service.owner = Some(owner);
+        service.endpoint = Some(endpoint.clone());
+        blueprint_sdk::debug!(%endpoint, ?started, "MCP servers started");
+        Ok(endpoint)
+    }
+
+    /// Brings the locked service back to its previous mcp servers after a failed start,
+    /// replacing or stopping the ones started since. A server that failed keeps its state.
+    async fn restore_servers(
+        &self,
+        ctx: &crate::MyContext,
+        service: &mut ServiceEntry,
+        previous: Vec<(String, crate::McpServerConfig)>,
+    ) {
+        let new: Vec<String> = service
+            .servers
+            .keys()
+            .filter(|name| !previous.iter().any(|(n, _)| n == *name))
+            .cloned()
+            .collect();
+        for name in new {
+            if let Err(e) = self.stop_server(service, &name).await {
+                blueprint_sdk::error!(error = %e, %name, "Failed to stop the MCP server");
+            }
+        }
+        // Servers still running with their previous config are left untouched
+        for (name, config) in previous {
+            if let Err(e) = self.start_server(ctx, service, &name, config).await {
+                blueprint_sdk::error!(error = %e, %name, "Failed to restore the MCP server");
+            }
+        }
+
+        if service.is_running() {
+            match self.service_endpoint(service) {
+                Ok(endpoint) => {
+                    blueprint_sdk::info!(%endpoint, "Restored the previous MCP servers");
+                    service.endpoint = Some(endpoint);
+                    return;
+                }
+                Err(e) => {
+                    blueprint_sdk::error!(error = %e, "Failed to serve the restored MCP servers");
+                }
+            }
+        }
+        for name in service.servers.keys().cloned().collect::<Vec<_>>() {
+            if let Err(e) = self.stop_server(service, &name).await {
+                blueprint_sdk::error!(error = %e, %name, "Failed to stop the MCP server");
+            }
+        }
+        self.stop_router(service);
+        service.owner = None;
+        service.endpoint = None;
+    }
+
+    /// The endpoint of the service, starting the router in front of named MCP servers.
+    fn service_endpoint(&self, service: &mut ServiceEntry) -> Result<String, Error> {
+        if service.servers.len() == 1
+            && let Some(endpoint) = service
+                .servers
+                .get(DEFAULT_SERVER_NAME)
+                .and_then(|server| server.endpoint.clone())
+        {
+            self.stop_router(service);
+            return Ok(endpoint);
+        }
+
+        let routes = service
+            .servers
+            .iter()
+            .filter_map(|(name, server)| Some((name.clone(), server.internal_endpoint.clone()?)))
+            .collect();
+        let router = match service.router.take() {
+            Some(router) => router,
+            None => {
+                let service_id = service.service_id;
+                match &self.socket_dir {
+                    Some(dir) => ServiceRouter::serve_unix(
+                        dir.join(service_id.to_string()).join("mcp.sock"),
+                    )?,
+                    None => {
+                        let reservation = self.ports().reserve(&service_id.to_string())?;
+                        ServiceRouter::serve_tcp(&reservation)?
+                    }
+                }
+            }
+        };
+        router.set_routes(routes);
+        let endpoint = router.endpoint().to_string();
+        service.router = Some(router);
+        Ok(endpoint)
+    }
+
+    fn stop_router(&self, service: &mut ServiceEntry) {
+        if let Some(router) = service.router.take() {
+            router.stop();
+            self.ports().release(&service.service_id.to_string());
+        }
+    }
+
+    /// Start the named MCP server of the locked service, unless it's already running with the
+    /// same config.
+    ///
+    /// Returns whether the MCP server was started.
+    #[tracing::instrument(skip(self, ctx, service, config), fields(service_id = service.service_id))]
+    async fn start_server(
+        &self,
+        ctx: &crate::MyContext,
+        service: &mut ServiceEntry,
+        name: &str,
+        config: crate::McpServerConfig,
+    ) -> Result<bool, Error> {
+        let off_chain_secrets = ctx
+            .secrets
+            .read()
+            .await
+            .get(&service.service_id)
+            .cloned()
+            .unwrap_or_default();
+        let config_digest = config_digest(&config, &off_chain_secrets)?;
+
+        // Starting an already running server is a no-op, unless the config changed
+        if let Some(existing) = service.servers.get(name).and_then(|s| s.server.as_ref()) {
+            let running = existing
+                .cancellation_token
+                .as_ref()
+                .is_some_and(|ct| !ct.is_cancelled());
+            if running && existing.config_digest == config_digest {
+                blueprint_sdk::debug!("MCP server already running with the same config");
+                return Ok(false);
+            }
+            blueprint_sdk::info!(running, "Replacing the MCP server");
+            self.stop_server(service, name).await?;
+        }
+
+        let service_id = service.service_id;
+        let entry = service.servers.entry(name.to_string()).or_default();
+        entry.state = McpServerState::Starting;
+        let result = self
+            .launch(
+                ctx,
+                service_id,
+                name,
+                config.clone(),
+                off_chain_secrets,
+                config_digest,
+            )
+            .await;
+        match result {
+            Ok((server, endpoint, internal_endpoint)) => {
+                self.queues()
+                    .insert((service_id, name.to_string()), server.queue_metrics.clone());
+                entry.state = McpServerState::Running;
+                entry.server = Some(server);
+                entry.config = Some(config);
+                entry.endpoint = Some(endpoint.clone());
+                entry.internal_endpoint = Some(internal_endpoint);
+                blueprint_sdk::debug!(
+                    %endpoint,
+                    "MCP server started"
+                );
+                Ok(true)
+            }
+            Err(e) => {
+                self.ports().release(&server_key(service_id, name));
+                entry.state = McpServerState::Failed(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Allocates a port and starts the mcp server with the runtime of the config.
+    ///
+    /// Returns the mcp server, its endpoint and the endpoint the router forwards to.
+    async fn launch(
+        &self,
+        ctx: &crate::MyContext,
+        service_id: u64,
+        name: &str,
+        config: crate::McpServerConfig,
+        off_chain_secrets: BTreeMap<String, String>,
+        config_digest: String,
+    ) -> Result<(McpServer, String, String), Error> {
         use crate::manager::docker::DockerRunner;
         use crate::manager::js::JsRunner;
         use crate::manager::python::PythonRunner;
+        use crate::manager::remote::RemoteRunner;
 
-        let args = config.args.0.unwrap_or_default().0.clone();
+        let args = Args::from(config.args.0.unwrap_or_default().0);
+        let base_env: EnvVars = config.env.0.unwrap_or_default().0.into_iter().collect();
 
-        let allocated_port = ctx.next_available_port().await?;
-
-        let env_vars: BTreeMap<String, String> = config
-            .env
+        // Secrets supplied off-chain take precedence over the ones in the request params
+        let mut secrets: BTreeMap<String, String> = config
+            .secret_env
             .0
             .unwrap_or_default()
             .0
             .into_iter()
-            .chain(std::iter::once((
-                "PORT".to_string(),
-                allocated_port.to_string(),
-            )))
             .collect();
+        secrets.extend(off_chain_secrets);
+        let secrets = if secrets.is_empty() {
+            secrets
+        } else {
+            let scope = SecretScope::from_env(&ctx.env, service_id)?;
+            OperatorKey::from_env(&ctx.env)?.decrypt_all(scope, secrets)?
+        };
+        let headers = remote_headers(config.headers.0.unwrap_or_default().0, &secrets);
 
-        blueprint_sdk::debug!(
-            ?args,
-            ?env_vars,
-            package = %config.package,
-            runtime = ?config.runtime,
-            "Starting MCP server with args"
-        );
-        let ct = match config.runtime {
-            crate::McpRuntime::Python => {
-                PythonRunner
-                    .start(
-                        ctx,
-                        service_id,
-                        config.package.clone(),
-                        args.clone(),
-                        env_vars.clone(),
-                        config.transport_adapter,
-                    )
-                    .await?
-            }
-            crate::McpRuntime::Javascript => {
-                JsRunner
-                    .start(
-                        ctx,
-                        service_id,
-                        config.package.clone(),
-                        args.clone(),
-                        env_vars.clone(),
-                        config.transport_adapter,
-                    )
-                    .await?
-            }
-            crate::McpRuntime::Docker => {
-                DockerRunner
-                    .start(
-                        ctx,
-                        service_id,
-                        config.package.clone(),
-                        args.clone(),
-                        env_vars.clone(),
-                        config.transport_adapter,
-                    )
-                    .await?
+        let policy = Arc::new(config.policy);
+        let audit = ctx
+            .audit
+            .as_ref()
+            .map(|audit| audit.for_service(service_id));
+        let unix_socket = self
+            .socket_dir
+            .as_ref()
+            .map(|dir| dir.join(service_id.to_string()).join(name).join("mcp.sock"));
+        // The gateway served over TLS may require a client certificate, the operator reaches it
+        // on a socket of its own. A container served without the gateway is never over TLS.
+        let internal_socket = (unix_socket.is_none()
+            && tls::OPERATOR_TLS.is_some()
+            && config.transport_adapter.is_stdio_to_sse())
+        .then(|| {
+            INTERNAL_SOCKET_DIR
+                .join(service_id.to_string())
+                .join(name)
+                .join("mcp.sock")
+        });
+
+        // The port is held until the runtime binds it, but a container without the gateway
+        // binds it itself, so another process may take it first; retry on another port then.
+        let queue_metrics = QueueMetrics::default();
+        // Tells the sessions of the readiness probe apart, not to audit them nor keep them open
+        let probe_token = uuid::Uuid::new_v4().to_string();
+        let mut attempt = 1;
+        let (ct, env_vars, allocated_port) = loop {
+            let reservation = self.ports().reserve(&server_key(service_id, name))?;
+            let allocated_port = reservation.port();
+            let sse_config = SseServerConfig {
+                policy: policy.clone(),
+                audit: audit.clone(),
+                reservation: Some(reservation.clone()),
+                unix_socket: unix_socket.clone(),
+                internal_socket: internal_socket.clone(),
+                metrics: queue_metrics.clone(),
+                probe_token: Some(probe_token.clone()),
+                ..SseServerConfig::new(reservation.addr())
+            };
+
+            let mut env_vars = base_env.clone();
+            env_vars.insert("PORT".to_string(), allocated_port.to_string());
+            let mut runtime_env = env_vars.clone();
+            for (name, value) in &secrets {
+                runtime_env.insert_secret(name.clone(), value.clone());
             }
-            crate::McpRuntime::Unknown => {
-                return Err(Error::UnknownRuntime);
+
+            blueprint_sdk::debug!(
+                ?args,
+                env_vars = ?runtime_env,
+                package = %config.package,
+                runtime = ?config.runtime,
+                attempt,
+                "Starting MCP server with args"
+            );
+            let result = match config.runtime {
+                crate::McpRuntime::Python => {
+                    PythonRunner
+                        .start(
+                            ctx,
+                            service_id,
+                            name,
+                            config.package.clone(),
+                            args.clone(),
+                            runtime_env,
+                            config.transport_adapter,
+                            sse_config,
+                        )
+                        .await
+                }
+                crate::McpRuntime::Javascript => {
+                    JsRunner
+                        .start(
+                            ctx,
+                            service_id,
+                            name,
+                            config.package.clone(),
+                            args.clone(),
+                            runtime_env,
+                            config.transport_adapter,
+                            sse_config,
+                        )
+                        .await
+                }
+                crate::McpRuntime::Docker => {
+                    DockerRunner
+                        .start(
+                            ctx,
+                            service_id,
+                            name,
+                            config.package.clone(),
+                            args.clone(),
+                            runtime_env,
+                            config.transport_adapter,
+                            sse_config,
+                        )
+                        .await
+                }
+                crate::McpRuntime::Remote => {
+                    // A remote server has no environment, the secrets are sent in its headers
+                    RemoteRunner
+                        .start(
+                            ctx,
+                            service_id,
+                            name,
+                            config.package.clone(),
+                            args.clone(),
+                            headers.clone(),
+                            config.transport_adapter,
+                            sse_config,
+                        )
+                        .await
+                }
+                crate::McpRuntime::Unknown => Err(Error::UnknownRuntime),
+            };
+            match result {
+                Ok(ct) => break (ct, env_vars, allocated_port),
+                Err(Error::PortInUse(port)) if attempt < MAX_PORT_ATTEMPTS => {
+                    blueprint_sdk::warn!(%port, attempt, "Port already in use, retrying on another port");
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
             }
         };
+
+        // Don't hand out the endpoint before the mcp server answers
+        let target = match unix_socket.as_ref().or(internal_socket.as_ref()) {
+            Some(path) => ProbeTarget::Unix(path.clone()),
+            None => ProbeTarget::Tcp((std::net::Ipv4Addr::LOCALHOST, allocated_port).into()),
+        };
+        let probe = match config.health_path.0 {
+            Some(path) if config.transport_adapter.is_none() => Probe::Health { path },
+            _ => Probe::Initialize {
+                sse_path: "/sse".to_string(),
+                probe_token: Some(probe_token),
+            },
+        };
+        if let Err(e) = readiness::wait_ready(&target, &probe, &ct).await {
+            blueprint_sdk::error!(error = %e, "MCP server did not become ready, stopping it");
+            ct.cancel();
+            return Err(e);
+        }
+
         let server = McpServer {
             runtime: config.runtime,
             package: config.package,
             args,
             env_vars,
+            config_digest,
             cancellation_token: Some(ct),
+            queue_metrics,
         };
-        let endpoint = format!("http://127.0.0.1:{allocated_port}");
-        self.servers.insert(service_id, server);
-        self.owners.insert(service_id, owner);
-        self.endpoints.insert(service_id, endpoint.clone());
-        blueprint_sdk::debug!(
-            %endpoint,
-            "MCP server started"
-        );
-        Ok(endpoint)
+        let endpoint = match &unix_socket {
+            Some(path) => format!("unix://{}", path.display()),
+            None => format!("http://127.0.0.1:{allocated_port}"),
+        };
+        let internal_endpoint = match &internal_socket {
+            Some(path) => format!("unix://{}", path.display()),
+            None => endpoint.clone(),
+        };
+        Ok((server, endpoint, internal_endpoint))
+    }
+
+    /// Stop all the MCP servers of the locked service.
+    ///
+    /// Returns whether any MCP server was running.
+    #[tracing::instrument(skip(self, service), fields(service_id = service.service_id))]
+    pub async fn stop_all(&self, service: &mut ServiceEntry) -> Result<bool, Error> {
+        self.stop_router(service);
+        let mut stopped = false;
+        let names: Vec<String> = service.servers.keys().cloned().collect();
+        for name in names {
+            stopped |= self.stop_server(service, &name).await?;
+        }
+        service.owner = None;
+        service.endpoint = None;
+        Ok(stopped)
     }
-    /// Stop the MCP server with the given service_id.
-    #[tracing::instrument(skip(self), fields(service_id))]
-    pub async fn stop_server(&mut self, service_id: u64) -> Result<bool, Error> {
+
+    /// Stop the named MCP server of the locked service.
+    #[tracing::instrument(skip(self, service), fields(service_id = service.service_id))]
+    pub async fn stop_server(&self, service: &mut ServiceEntry, name: &str) -> Result<bool, Error> {
         blueprint_sdk::debug!("Stopping MCP server");
-        if let Some(mut server) = self.servers.remove(&service_id) {
-            if let Some(ct) = server.cancellation_token.take() {
-                ct.cancel();
-                ct.cancelled().await;
-                blueprint_sdk::debug!("MCP server cancelled");
+        let Some(entry) = service.servers.get_mut(name) else {
+            blueprint_sdk::debug!("MCP server not found");
+            return Ok(false);
+        };
+        let Some(mut server) = entry.server.take() else {
+            blueprint_sdk::debug!("MCP server not running");
+            return Ok(false);
+        };
+        entry.state = McpServerState::Stopping;
+        self.queues()
+            .remove(&(service.service_id, name.to_string()));
+        if let Some(ct) = server.cancellation_token.take() {
+            ct.cancel();
+            ct.cancelled().await;
+            blueprint_sdk::debug!("MCP server cancelled");
+        }
+        entry.endpoint = None;
+        entry.internal_endpoint = None;
+        self.ports().release(&server_key(service.service_id, name));
+        entry.state = McpServerState::Pending;
+        if let Some(router) = &service.router {
+            router.remove_route(name);
+        }
+        blueprint_sdk::debug!("MCP server stopped");
+        Ok(true)
+    }
+}
+
+/// The key of a named mcp server of a service, used for its port allocation
+fn server_key(service_id: u64, name: &str) -> String {
+    format!("{service_id}/{name}")
+}
+
+/// The headers of a remote mcp server, with `${NAME}` replaced by the secret `NAME`.
+///
+/// The placeholders are those of the header value as given, a secret value is never scanned
+/// for more of them.
+fn remote_headers(headers: Vec<(String, String)>, secrets: &BTreeMap<String, String>) -> EnvVars {
+    let mut result = EnvVars::default();
+    for (name, template) in headers {
+        let mut value = String::with_capacity(template.len());
+        let mut has_secret = false;
+        for segment in header_segments(&template) {
+            match segment {
+                HeaderSegment::Text(text) => value.push_str(text),
+                HeaderSegment::Secret(key) => match secrets.get(key) {
+                    Some(secret) => {
+                        value.push_str(secret);
+                        has_secret = true;
+                    }
+                    // Unknown secrets are rejected by the validation
+                    None => value.push_str(&format!("${{{key}}}")),
+                },
             }
-            self.owners.remove(&service_id);
-            self.endpoints.remove(&service_id);
-            blueprint_sdk::debug!("MCP server stopped");
-            Ok(true)
+        }
+        if has_secret {
+            result.insert_secret(name, value);
         } else {
-            blueprint_sdk::debug!("MCP server not found");
-            Ok(false)
+            result.insert(name, value);
         }
     }
+    result
+}
+
+/// Computes the digest of the config and the off-chain secrets of an mcp server.
+fn config_digest(
+    config: &crate::McpServerConfig,
+    off_chain_secrets: &BTreeMap<String, String>,
+) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(config).map_err(std::io::Error::from)?);
+    hasher.update(serde_json::to_vec(off_chain_secrets).map_err(std::io::Error::from)?);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_values_are_not_expanded_in_headers() {
+        let secrets = BTreeMap::from([
+            ("TOKEN".to_string(), "${OTHER}".to_string()),
+            ("OTHER".to_string(), "hidden".to_string()),
+        ]);
+        let headers = remote_headers(
+            vec![
+                ("Authorization".into(), "Bearer ${TOKEN}".into()),
+                ("X-Api-Version".into(), "2".into()),
+            ],
+            &secrets,
+        );
+        assert_eq!(headers["Authorization"], "Bearer ${OTHER}");
+        assert_eq!(headers["X-Api-Version"], "2");
+        assert!(!format!("{headers:?}").contains("OTHER"));
+    }
 }
@@ -1,5 +1,3 @@
-use std::collections::BTreeMap;
-
 use futures::TryFutureExt;
 use rmcp::transport::TokioChildProcess;
 use tokio::process::Command;
@@ -8,7 +6,8 @@ use tokio_util::sync::CancellationToken;
 use crate::SupportedTransportAdapter;
 use crate::error::Error;
 use crate::manager::McpRunner;
-use crate::transport::SseServer;
+use crate::redact::{Args, EnvVars};
+use crate::transport::{SseServer, SseServerConfig};
 
 /// Python runner
 /// This runner uses the `uv` package to run Python scripts
@@ -16,15 +15,17 @@ use crate::transport::SseServer;
 pub struct PythonRunner;
 
 impl McpRunner for PythonRunner {
-    #[tracing::instrument(skip(self, ctx), fields(%package, args, port_bindings, runtime = "python"))]
+    #[tracing::instrument(skip(self, ctx, sse_config), fields(%package, ?args, ?env_vars, port_bindings, runtime = "python"))]
     async fn start(
         &self,
         ctx: &crate::MyContext,
         service_id: u64,
+        name: &str,
         package: String,
-        args: Vec<String>,
-        env_vars: BTreeMap<String, String>,
+        args: Args,
+        env_vars: EnvVars,
         transport_adapter: SupportedTransportAdapter,
+        sse_config: SseServerConfig,
     ) -> Result<CancellationToken, Error> {
         // Ensure uv is installed
         let mut checked = self.check(ctx).await;
@@ -42,25 +43,21 @@ impl McpRunner for PythonRunner {
             }
         }
 
-        let allocated_port = env_vars
-            .get("PORT")
-            .and_then(|p| p.parse::<u16>().ok())
-            .ok_or(Error::MissingPortBinding)?;
-        let endpoint = format!("http://127.0.0.1:{allocated_port}");
-
         let factory = move || {
             let mut cmd = Command::new("uvx");
             cmd.arg("run")
                 .arg(&package)
                 .arg("--")
-                .args(&args)
-                .envs(&env_vars)
+                .args(args.iter())
+                .envs(env_vars.iter())
                 .kill_on_drop(true);
             let transport = TokioChildProcess::new(&mut cmd);
             futures::future::ready(transport)
         };
 
-        let ct = SseServer::serve(endpoint.parse()?).await?.forward(factory);
+        let ct = SseServer::serve_with_config(sse_config)
+            .await?
+            .forward(factory);
         Ok(ct)
     }
 
@@ -0,0 +1,569 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, StatusCode, Url, redirect};
+use rmcp::model::{
+    ClientJsonRpcMessage, ErrorData, JsonRpcError, JsonRpcMessage, JsonRpcVersion2_0,
+    ServerJsonRpcMessage,
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::{CancellationToken, DropGuard, PollSender};
+
+use crate::SupportedTransportAdapter;
+use crate::error::Error;
+use crate::manager::McpRunner;
+use crate::readiness::SseReader;
+use crate::redact::{Args, EnvVars};
+use crate::transport::{SseServer, SseServerConfig};
+use crate::validate::is_public_address;
+
+/// The header carrying the session of a Streamable HTTP server
+const SESSION_ID_HEADER: &str = "mcp-session-id";
+
+/// Remote runner
+///
+/// This runner connects to an MCP server hosted elsewhere, `package` being its URL, and serves
+/// it behind the gateway like the local runtimes. A URL whose path ends with `/sse` is reached
+/// over SSE, any other one over Streamable HTTP.
+///
+/// Only public addresses are connected to, redirects are not followed, and the messages of an
+/// SSE server are only posted to its own origin, so a server can't reach the operator's
+/// loopback services, e.g. the MCP servers of the other services, or the cloud metadata
+/// endpoint with the headers of the service.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct RemoteRunner;
+
+impl McpRunner for RemoteRunner {
+    /// `headers` are sent with every request to the remote MCP server.
+    #[tracing::instrument(skip(self, _ctx, sse_config), fields(%package, ?headers, runtime = "remote"))]
+    async fn start(
+        &self,
+        _ctx: &crate::MyContext,
+        _service_id: u64,
+        _name: &str,
+        package: String,
+        _args: Args,
+        headers: EnvVars,
+        _transport_adapter: SupportedTransportAdapter,
+        sse_config: SseServerConfig,
+    ) -> Result<CancellationToken, Error> {
+        let url = Url::parse(&package).map_err(std::io::Error::other)?;
+        let mut default_headers = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            let name = HeaderName::try_from(name.as_str()).map_err(std::io::Error::other)?;
+            let mut value = HeaderValue::try_from(value.as_str()).map_err(std::io::Error::other)?;
+            value.set_sensitive(true);
+            default_headers.insert(name, value);
+        }
+        let client = Client::builder()
+            .default_headers(default_headers)
+            .redirect(redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build()
+            .map_err(std::io::Error::other)?;
+
+        let factory = move || RemoteTransport::connect(client.clone(), url.clone());
+        let ct = SseServer::serve_with_config(sse_config)
+            .await?
+            .forward(factory);
+        Ok(ct)
+    }
+
+    async fn check(&self, _ctx: &crate::MyContext) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    async fn install(&self, _ctx: &crate::MyContext) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Resolves the host names of the remote MCP servers to their public addresses only, the
+/// addresses in the URLs are checked by the validation.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_address(&addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// A client session with a remote MCP server, closed when dropped
+pub struct RemoteTransport {
+    stream: ReceiverStream<ServerJsonRpcMessage>,
+    sink: PollSender<ClientJsonRpcMessage>,
+    _guard: DropGuard,
+}
+
+impl RemoteTransport {
+    /// Opens a session with the remote MCP server.
+    pub async fn connect(client: Client, url: Url) -> std::io::Result<Self> {
+        let ct = CancellationToken::new();
+        let (from_server_tx, from_server_rx) = mpsc::channel(64);
+        let (to_server_tx, to_server_rx) = mpsc::channel(64);
+        if url.path().ends_with("/sse") {
+            connect_sse(client, url, to_server_rx, from_server_tx, ct.clone()).await?;
+        } else {
+            tokio::spawn(streamable_http(
+                client,
+                url,
+                to_server_rx,
+                from_server_tx,
+                ct.clone(),
+            ));
+        }
+        Ok(Self {
+            stream: ReceiverStream::new(from_server_rx),
+            sink: PollSender::new(to_server_tx),
+            _guard: ct.drop_guard(),
+        })
+    }
+}
+
+impl Sink<ClientJsonRpcMessage> for RemoteTransport {
+    type Error = std::io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.sink
+            .poll_ready_unpin(cx)
+            .map_err(std::io::Error::other)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: ClientJsonRpcMessage) -> Result<(), Self::Error> {
+        self.sink
+            .start_send_unpin(item)
+            .map_err(std::io::Error::other)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.sink
+            .poll_flush_unpin(cx)
+            .map_err(std::io::Error::other)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.sink
+            .poll_close_unpin(cx)
+            .map_err(std::io::Error::other)
+    }
+}
+
+impl Stream for RemoteTransport {
+    type Item = ServerJsonRpcMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+/// Opens the SSE stream, then reads the messages from its `message` events and posts the
+/// messages of the client to the endpoint announced by its `endpoint` event.
+async fn connect_sse(
+    client: Client,
+    url: Url,
+    mut to_server: mpsc::Receiver<ClientJsonRpcMessage>,
+    from_server: mpsc::Sender<ServerJsonRpcMessage>,
+    ct: CancellationToken,
+) -> std::io::Result<()> {
+    let response = client
+        .get(url.clone())
+        .header(ACCEPT, "text/event-stream")
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(std::io::Error::other)?;
+    let mut events = SseReader::from_stream(response.bytes_stream());
+    let post_url = loop {
+        let (event, data) = events.next().await.map_err(std::io::Error::other)?;
+        if event == "endpoint" {
+            break url.join(&data).map_err(std::io::Error::other)?;
+        }
+    };
+    // The messages carry the headers of the service, they must not be sent anywhere else
+    if post_url.origin() != url.origin() {
+        return Err(std::io::Error::other(
+            "the remote MCP server announced an endpoint on another origin",
+        ));
+    }
+
+    let reader_ct = ct.clone();
+    let reader_tx = from_server.clone();
+    tokio::spawn(async move {
+        loop {
+            let (event, data) = tokio::select! {
+                event = events.next() => match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "remote MCP server SSE stream closed");
+                        break;
+                    }
+                },
+                () = reader_ct.cancelled() => break,
+            };
+            if event == "message" && !forward_messages(&data, &reader_tx).await {
+                break;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                message = to_server.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                () = ct.cancelled() => break,
+            };
+            let result = client
+                .post(post_url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(serialize(&message))
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(e) = result {
+                tracing::warn!(error = %e, "failed to post the message to the remote MCP server");
+                if !reject(&message, &e, &from_server).await {
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Posts the messages of the client and reads the messages from the JSON or SSE responses,
+/// ending the session on the remote MCP server once the client is gone.
+async fn streamable_http(
+    client: Client,
+    url: Url,
+    mut to_server: mpsc::Receiver<ClientJsonRpcMessage>,
+    from_server: mpsc::Sender<ServerJsonRpcMessage>,
+    ct: CancellationToken,
+) {
+    let session_id: Arc<Mutex<Option<HeaderValue>>> = Arc::default();
+    loop {
+        let message = tokio::select! {
+            message = to_server.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            () = ct.cancelled() => break,
+        };
+        // A request such as a long `tools/call` must not hold the next messages, e.g. its
+        // `notifications/cancelled`
+        let post = post_message(
+            client.clone(),
+            url.clone(),
+            message,
+            session_id.clone(),
+            from_server.clone(),
+            ct.clone(),
+        );
+        let ct = ct.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                () = post => {}
+                () = ct.cancelled() => {}
+            }
+        });
+    }
+
+    let session_id = session_id
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    if let Some(session_id) = session_id {
+        let _ = client
+            .delete(url)
+            .header(SESSION_ID_HEADER, session_id)
+            .send()
+            .await;
+    }
+}
+
+/// Posts a message of the client to the Streamable HTTP server and sends the messages of the
+/// response to the client, cancelling `ct` once the session is over.
+async fn post_message(
+    client: Client,
+    url: Url,
+    message: ClientJsonRpcMessage,
+    session_id: Arc<Mutex<Option<HeaderValue>>>,
+    from_server: mpsc::Sender<ServerJsonRpcMessage>,
+    ct: CancellationToken,
+) {
+    let current_session_id = session_id
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let mut request = client
+        .post(url)
+        .header(ACCEPT, "application/json, text/event-stream")
+        .header(CONTENT_TYPE, "application/json")
+        .body(serialize(&message));
+    if let Some(session_id) = &current_session_id {
+        request = request.header(SESSION_ID_HEADER, session_id.clone());
+    }
+    let response = match request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+    {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to post the message to the remote MCP server");
+            // The session expired, the client has to start a new one
+            let expired = e.status() == Some(StatusCode::NOT_FOUND) && current_session_id.is_some();
+            if expired || !reject(&message, &e, &from_server).await {
+                ct.cancel();
+            }
+            return;
+        }
+    };
+    if let Some(id) = response.headers().get(SESSION_ID_HEADER) {
+        *session_id.lock().unwrap_or_else(PoisonError::into_inner) = Some(id.clone());
+    }
+    if response.status() == StatusCode::ACCEPTED {
+        return;
+    }
+    let is_sse = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if is_sse {
+        let mut events = SseReader::from_stream(response.bytes_stream());
+        while let Ok((event, data)) = events.next().await {
+            if event == "message" && !forward_messages(&data, &from_server).await {
+                ct.cancel();
+                break;
+            }
+        }
+    } else {
+        match response.text().await {
+            Ok(body) => {
+                if !forward_messages(&body, &from_server).await {
+                    ct.cancel();
+                }
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to read the remote MCP server response")
+            }
+        }
+    }
+}
+
+fn serialize(message: &ClientJsonRpcMessage) -> Vec<u8> {
+    serde_json::to_vec(message).unwrap_or_default()
+}
+
+/// Sends the message, or the batch of messages, in `data` to the client.
+///
+/// Returns `false` once the client is gone.
+async fn forward_messages(data: &str, from_server: &mpsc::Sender<ServerJsonRpcMessage>) -> bool {
+    let messages = match serde_json::from_str::<ServerJsonRpcMessage>(data) {
+        Ok(message) => vec![message],
+        Err(_) => match serde_json::from_str::<Vec<ServerJsonRpcMessage>>(data) {
+            Ok(messages) => messages,
+            Err(e) => {
+                tracing::warn!(error = %e, "invalid message from the remote MCP server");
+                return true;
+            }
+        },
+    };
+    for message in messages {
+        if from_server.send(message).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Answers a request that could not be delivered with an error, so the client doesn't wait
+/// for a response that will never come.
+///
+/// Returns `false` once the client is gone.
+async fn reject(
+    message: &ClientJsonRpcMessage,
+    error: &reqwest::Error,
+    from_server: &mpsc::Sender<ServerJsonRpcMessage>,
+) -> bool {
+    let JsonRpcMessage::Request(request) = message else {
+        return true;
+    };
+    let rejection = JsonRpcMessage::Error(JsonRpcError {
+        jsonrpc: JsonRpcVersion2_0,
+        id: request.id.clone(),
+        error: ErrorData::internal_error(
+            format!("the remote MCP server is unavailable: {error}"),
+            None,
+        ),
+    });
+    from_server.send(rejection).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::http::StatusCode as HttpStatusCode;
+    use axum::http::header;
+    use axum::response::IntoResponse;
+    use axum::routing::{get, post};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_support::{call_tool, initialize};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Binds a loopback port and returns its URL.
+    async fn listen() -> (tokio::net::TcpListener, String) {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    /// Serves `router` on a loopback port and returns its URL.
+    async fn serve(router: Router) -> String {
+        let (listener, url) = listen().await;
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        url
+    }
+
+    /// An SSE stream announcing `endpoint`.
+    fn announce(endpoint: String) -> impl IntoResponse {
+        (
+            [(header::CONTENT_TYPE, "text/event-stream")],
+            format!("event: endpoint\ndata: {endpoint}\n\n"),
+        )
+    }
+
+    async fn connect(url: &str) -> std::io::Result<RemoteTransport> {
+        RemoteTransport::connect(Client::new(), Url::parse(url).unwrap()).await
+    }
+
+    async fn request(transport: &mut RemoteTransport, message: Value) -> Value {
+        transport
+            .send(serde_json::from_value(message).unwrap())
+            .await
+            .unwrap();
+        let response = tokio::time::timeout(TIMEOUT, transport.next())
+            .await
+            .unwrap()
+            .unwrap();
+        serde_json::to_value(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn host_names_are_resolved_to_public_addresses_only() {
+        let resolving = PublicResolver.resolve("localhost".parse().unwrap());
+        assert!(resolving.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sse_endpoints_on_another_origin_are_refused() {
+        let (listener, url) = listen().await;
+        let port = listener.local_addr().unwrap().port();
+        let endpoint = format!("http://localhost:{port}/message");
+        let posted = Arc::new(Mutex::new(false));
+        let router = Router::new()
+            .route("/sse", get(move || async move { announce(endpoint) }))
+            .route(
+                "/message",
+                post({
+                    let posted = posted.clone();
+                    move || async move {
+                        *posted.lock().unwrap() = true;
+                        HttpStatusCode::ACCEPTED
+                    }
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let error = connect(&format!("{url}/sse")).await.err().unwrap();
+        assert!(error.to_string().contains("another origin"), "{error}");
+        assert!(!*posted.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn sse_requests_are_rejected_when_a_post_fails() {
+        let router = Router::new()
+            .route("/sse", get(|| async { announce("/message".into()) }))
+            .route(
+                "/message",
+                post(|| async { HttpStatusCode::INTERNAL_SERVER_ERROR }),
+            );
+        let url = serve(router).await;
+
+        let mut transport = connect(&format!("{url}/sse")).await.unwrap();
+        let response = request(&mut transport, initialize(1)).await;
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], -32603);
+    }
+
+    #[tokio::test]
+    async fn streamable_http_requests_are_rejected_when_a_post_fails() {
+        let router = Router::new().route(
+            "/mcp",
+            post(|| async { HttpStatusCode::INTERNAL_SERVER_ERROR }),
+        );
+        let url = serve(router).await;
+
+        let mut transport = connect(&format!("{url}/mcp")).await.unwrap();
+        let response = request(&mut transport, initialize(1)).await;
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], -32603);
+    }
+
+    #[tokio::test]
+    async fn slow_streamable_http_requests_dont_hold_the_next_ones() {
+        let router = Router::new().route(
+            "/mcp",
+            post(|body: Bytes| async move {
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                if request["method"] == "tools/call" {
+                    std::future::pending::<()>().await;
+                }
+                (
+                    [(header::CONTENT_TYPE, "application/json")],
+                    json!({ "jsonrpc": "2.0", "id": request["id"], "result": {} }).to_string(),
+                )
+            }),
+        );
+        let url = serve(router).await;
+
+        let mut transport = connect(&format!("{url}/mcp")).await.unwrap();
+        transport
+            .send(serde_json::from_value(call_tool(1, "hang", json!({}))).unwrap())
+            .await
+            .unwrap();
+        let response = request(
+            &mut transport,
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "ping" }),
+        )
+        .await;
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["result"], json!({}));
+    }
+}
@@ -0,0 +1,144 @@
+//! Metrics endpoint of the operator.
+//!
+//! Each gateway in front of an MCP server bounds the queues of its sessions, answering
+//! `503 Service Unavailable` once they are full. The operator enables this endpoint with
+//! `MCP_METRICS_ADDR` to scrape the queue depths of the running MCP servers at `/metrics`, in
+//! the Prometheus text format, and tune `MCP_QUEUE_CAPACITY`, `MCP_MAX_PENDING_SESSIONS` and
+//! `MCP_SEND_TIMEOUT` with them.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+use axum::Router;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+
+use crate::MyContext;
+use crate::error::Error;
+use crate::transport::QueueDepths;
+
+/// The content type of the Prometheus text format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Spawns the metrics endpoint if the operator enabled it with `MCP_METRICS_ADDR`.
+pub async fn spawn_metrics_endpoint(ctx: &MyContext) -> Result<(), Error> {
+    let Ok(bind) = std::env::var("MCP_METRICS_ADDR") else {
+        return Ok(());
+    };
+    let bind: SocketAddr = bind.parse()?;
+    let router = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(ctx.clone());
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    tracing::info!(%bind, "metrics endpoint listening");
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            tracing::error!(error = %e, "metrics endpoint shutdown with error");
+        }
+    });
+    Ok(())
+}
+
+async fn metrics_handler(State(ctx): State<MyContext>) -> impl IntoResponse {
+    let depths = ctx.mcp_server_manager.queue_depths();
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], render(&depths))
+}
+
+/// A metric of the queues of a gateway
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&QueueDepths) -> u64,
+}
+
+const METRICS: [Metric; 5] = [
+    Metric {
+        name: "mcp_gateway_sessions",
+        kind: "gauge",
+        help: "The open sessions of the gateway",
+        value: |d| d.sessions as u64,
+    },
+    Metric {
+        name: "mcp_gateway_pending_sessions",
+        kind: "gauge",
+        help: "The new sessions waiting for their MCP server",
+        value: |d| d.pending_sessions as u64,
+    },
+    Metric {
+        name: "mcp_gateway_client_messages",
+        kind: "gauge",
+        help: "The messages of the clients waiting to be forwarded to the MCP server",
+        value: |d| d.client_messages as u64,
+    },
+    Metric {
+        name: "mcp_gateway_server_messages",
+        kind: "gauge",
+        help: "The messages of the MCP server waiting to be sent to the clients",
+        value: |d| d.server_messages as u64,
+    },
+    Metric {
+        name: "mcp_gateway_rejected_total",
+        kind: "counter",
+        help: "The requests rejected because of a full queue",
+        value: |d| d.rejected,
+    },
+];
+
+/// Renders the queue depths of the MCP servers, by service id and name.
+fn render(depths: &[(u64, String, QueueDepths)]) -> String {
+    let mut out = String::new();
+    for metric in &METRICS {
+        let name = metric.name;
+        let _ = writeln!(out, "# HELP {name} {}", metric.help);
+        let _ = writeln!(out, "# TYPE {name} {}", metric.kind);
+        for (service_id, server, depths) in depths {
+            // Server names are validated, they need no escaping
+            let _ = writeln!(
+                out,
+                "{name}{{service_id=\"{service_id}\",server=\"{server}\"}} {}",
+                (metric.value)(depths)
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depths_are_rendered_by_server() {
+        let busy = QueueDepths {
+            sessions: 3,
+            pending_sessions: 1,
+            client_messages: 7,
+            server_messages: 2,
+            rejected: 5,
+        };
+        let depths = [
+            (1, "default".to_string(), QueueDepths::default()),
+            (2, "github".to_string(), busy),
+        ];
+        let rendered = render(&depths);
+        let expected = [
+            "# TYPE mcp_gateway_sessions gauge",
+            "mcp_gateway_sessions{service_id=\"1\",server=\"default\"} 0",
+            "mcp_gateway_sessions{service_id=\"2\",server=\"github\"} 3",
+            "mcp_gateway_pending_sessions{service_id=\"2\",server=\"github\"} 1",
+            "mcp_gateway_client_messages{service_id=\"2\",server=\"github\"} 7",
+            "mcp_gateway_server_messages{service_id=\"2\",server=\"github\"} 2",
+            "# TYPE mcp_gateway_rejected_total counter",
+            "mcp_gateway_rejected_total{service_id=\"2\",server=\"github\"} 5",
+        ];
+        for line in expected {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{line} not in {rendered}"
+            );
+        }
+    }
+}
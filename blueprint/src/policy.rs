@@ -0,0 +1,403 @@
+//! Gateway access policy for the MCP server capabilities.
+//!
+//! Service owners may only want to expose a subset of what an MCP server offers (e.g. the
+//! read-only tools of a GitHub server). Since every message between the client and the MCP
+//! server passes through [`SseServer::forward`](crate::transport::SseServer::forward), the
+//! policy is enforced there:
+//!
+//! - `tools/list`, `resources/list`, `resources/templates/list` and `prompts/list` responses
+//!   are filtered so that hidden entries never reach the client.
+//! - `tools/call`, `resources/read`, `resources/subscribe`, `prompts/get` and
+//!   `completion/complete` requests for hidden entries are answered with a JSON-RPC error and
+//!   never reach the MCP server.
+//! - A list response that can't be filtered is replaced with an error rather than passed on.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use blueprint_sdk::tangle::extract::{List, Optional};
+use rmcp::model::{
+    ClientJsonRpcMessage, ClientRequest, ErrorData, JsonRpcError, JsonRpcMessage,
+    JsonRpcVersion2_0, Reference, RequestId, ServerJsonRpcMessage, ServerResult,
+};
+
+/// Access policy applied by the gateway in front of the MCP server
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpServerPolicy {
+    /// Which tools are exposed, matched against the tool name
+    #[serde(default)]
+    pub tools: AccessList,
+    /// Which resources are exposed, matched against the resource URI (or URI template)
+    #[serde(default)]
+    pub resources: AccessList,
+    /// Which prompts are exposed, matched against the prompt name
+    #[serde(default)]
+    pub prompts: AccessList,
+}
+
+/// An allowlist and a denylist of names
+///
+/// Entries are matched exactly, or as a glob when they contain `*` (e.g. `get_*`).
+/// An empty allowlist allows everything, and the denylist always takes precedence.
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessList {
+    /// Only these entries are exposed
+    /// This is optional and can be empty
+    #[serde(default)]
+    pub allow: Optional<List<String>>,
+    /// These entries are never exposed
+    /// This is optional and can be empty
+    #[serde(default)]
+    pub deny: Optional<List<String>>,
+}
+
+impl AccessList {
+    /// Returns `true` if this list does not restrict anything.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.allow_entries().is_empty() && self.deny_entries().is_empty()
+    }
+
+    /// Returns `true` if `name` is exposed by this list.
+    #[must_use]
+    pub fn allows(&self, name: &str) -> bool {
+        let allow = self.allow_entries();
+        let allowed = allow.is_empty() || allow.iter().any(|p| glob_match(p, name));
+        allowed && !self.deny_entries().iter().any(|p| glob_match(p, name))
+    }
+
+    fn allow_entries(&self) -> &[String] {
+        self.allow
+            .0
+            .as_ref()
+            .map(|l| l.0.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn deny_entries(&self) -> &[String] {
+        self.deny
+            .0
+            .as_ref()
+            .map(|l| l.0.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+impl McpServerPolicy {
+    /// Returns `true` if the policy does not restrict anything.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty() && self.resources.is_empty() && self.prompts.is_empty()
+    }
+}
+
+/// Matches `value` against `pattern`, where `*` matches any (possibly empty) sequence of characters.
+pub(crate) fn glob_match(pattern: &str, value: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == value;
+    };
+    let Some(mut value) = value.strip_prefix(prefix) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    // The last part must match the end of the value, the rest may appear anywhere in order.
+    let suffix = parts.pop().unwrap_or_default();
+    for part in parts {
+        match value.find(part) {
+            Some(idx) => value = &value[idx + part.len()..],
+            None => return false,
+        }
+    }
+    value.len() >= suffix.len() && value.ends_with(suffix)
+}
+
+/// The kind of list request whose response has to be filtered
+#[derive(Debug, Clone, Copy)]
+enum PendingList {
+    Tools,
+    Resources,
+    ResourceTemplates,
+    Prompts,
+}
+
+/// Per-session policy enforcement
+///
+/// Responses do not carry their method, so the ids of in-flight list requests are
+/// remembered until the matching response comes back from the MCP server. The bridge of the
+/// session rejects a request reusing the id of a pending one, which would take its response.
+#[derive(Debug)]
+pub(crate) struct PolicyFilter {
+    policy: Arc<McpServerPolicy>,
+    pending: HashMap<RequestId, PendingList>,
+}
+
+impl PolicyFilter {
+    pub fn new(policy: Arc<McpServerPolicy>) -> Self {
+        Self {
+            policy,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Checks a message going from the client to the MCP server.
+    ///
+    /// Returns the error to send back to the client if the request is not allowed,
+    /// in which case the message must not be forwarded.
+    pub fn check_request(
+        &mut self,
+        message: &ClientJsonRpcMessage,
+    ) -> Option<ServerJsonRpcMessage> {
+        if self.policy.is_empty() {
+            return None;
+        }
+        let JsonRpcMessage::Request(request) = message else {
+            return None;
+        };
+        let policy = &self.policy;
+        let error = match &request.request {
+            ClientRequest::ListToolsRequest(_) => {
+                self.pending.insert(request.id.clone(), PendingList::Tools);
+                None
+            }
+            ClientRequest::ListResourcesRequest(_) => {
+                self.pending
+                    .insert(request.id.clone(), PendingList::Resources);
+                None
+            }
+            ClientRequest::ListResourceTemplatesRequest(_) => {
+                self.pending
+                    .insert(request.id.clone(), PendingList::ResourceTemplates);
+                None
+            }
+            ClientRequest::ListPromptsRequest(_) => {
+                self.pending
+                    .insert(request.id.clone(), PendingList::Prompts);
+                None
+            }
+            ClientRequest::CallToolRequest(call) if !policy.tools.allows(&call.params.name) => {
+                Some(ErrorData::invalid_params(
+                    format!("Unknown tool: {}", call.params.name),
+                    None,
+                ))
+            }
+            ClientRequest::ReadResourceRequest(read)
+                if !policy.resources.allows(&read.params.uri) =>
+            {
+                Some(ErrorData::resource_not_found(
+                    format!("Resource not found: {}", read.params.uri),
+                    None,
+                ))
+            }
+            ClientRequest::SubscribeRequest(subscribe)
+                if !policy.resources.allows(&subscribe.params.uri) =>
+            {
+                Some(ErrorData::resource_not_found(
+                    format!("Resource not found: {}", subscribe.params.uri),
+                    None,
+                ))
+            }
+            ClientRequest::GetPromptRequest(get) if !policy.prompts.allows(&get.params.name) => {
+                Some(ErrorData::invalid_params(
+                    format!("Unknown prompt: {}", get.params.name),
+                    None,
+                ))
+            }
+            ClientRequest::CompleteRequest(complete) => match &complete.params.r#ref {
+                Reference::Prompt(prompt) if !policy.prompts.allows(&prompt.name) => Some(
+                    ErrorData::invalid_params(format!("Unknown prompt: {}", prompt.name), None),
+                ),
+                Reference::Resource(resource) if !policy.resources.allows(&resource.uri) => {
+                    Some(ErrorData::resource_not_found(
+                        format!("Resource not found: {}", resource.uri),
+                        None,
+                    ))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        error.map(|error| {
+            tracing::debug!(id = %request.id, ?error, "request rejected by policy");
+            JsonRpcMessage::Error(JsonRpcError {
+                jsonrpc: JsonRpcVersion2_0,
+                id: request.id.clone(),
+                error,
+            })
+        })
+    }
+
+    /// Filters a message going from the MCP server to the client in place.
+    pub fn filter_response(&mut self, message: &mut ServerJsonRpcMessage) {
+        let id = match message {
+            JsonRpcMessage::Response(response) => &response.id,
+            JsonRpcMessage::Error(error) => &error.id,
+            _ => return,
+        };
+        let Some(kind) = self.pending.remove(id) else {
+            return;
+        };
+        let JsonRpcMessage::Response(response) = message else {
+            return;
+        };
+        let policy = &self.policy;
+        match (kind, &mut response.result) {
+            (PendingList::Tools, ServerResult::ListToolsResult(result)) => {
+                result.tools.retain(|tool| policy.tools.allows(&tool.name));
+            }
+            (PendingList::Resources, ServerResult::ListResourcesResult(result)) => {
+                result
+                    .resources
+                    .retain(|resource| policy.resources.allows(&resource.uri));
+            }
+            (PendingList::ResourceTemplates, ServerResult::ListResourceTemplatesResult(result)) => {
+                result
+                    .resource_templates
+                    .retain(|template| policy.resources.allows(&template.uri_template));
+            }
+            (PendingList::Prompts, ServerResult::ListPromptsResult(result)) => {
+                result
+                    .prompts
+                    .retain(|prompt| policy.prompts.allows(&prompt.name));
+            }
+            (kind, _) => {
+                // The entries can't be checked, don't let them through
+                tracing::warn!(?kind, "unexpected result type for a filtered list request");
+                *message = JsonRpcMessage::Error(JsonRpcError {
+                    jsonrpc: JsonRpcVersion2_0,
+                    id: response.id.clone(),
+                    error: ErrorData::internal_error(
+                        "unexpected response of the MCP server to a list request",
+                        None,
+                    ),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(entries: &[&str]) -> Optional<List<String>> {
+        Optional(Some(List(
+            entries.iter().map(ToString::to_string).collect(),
+        )))
+    }
+
+    fn filter(tools: AccessList, prompts: AccessList) -> PolicyFilter {
+        PolicyFilter::new(Arc::new(McpServerPolicy {
+            tools,
+            prompts,
+            ..Default::default()
+        }))
+    }
+
+    fn request(id: u64, method: &str, params: serde_json::Value) -> ClientJsonRpcMessage {
+        serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))
+        .unwrap()
+    }
+
+    fn response(id: u64, result: serde_json::Value) -> ServerJsonRpcMessage {
+        serde_json::from_value(serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+            .unwrap()
+    }
+
+    #[test]
+    fn deny_entries_take_precedence() {
+        let list = AccessList {
+            allow: list(&["get_*", "list"]),
+            deny: list(&["*secret*"]),
+        };
+        assert!(list.allows("get_issue"));
+        assert!(list.allows("list"));
+        assert!(!list.allows("create_issue"));
+        assert!(!list.allows("get_secret_token"));
+        assert!(AccessList::default().allows("anything"));
+    }
+
+    #[test]
+    fn hidden_entries_are_rejected_and_filtered() {
+        let tools = AccessList {
+            allow: list(&["get_*"]),
+            ..Default::default()
+        };
+        let prompts = AccessList {
+            deny: list(&["internal"]),
+            ..Default::default()
+        };
+        let mut filter = filter(tools, prompts);
+
+        let call = request(
+            1,
+            "tools/call",
+            serde_json::json!({ "name": "delete_repo" }),
+        );
+        assert!(filter.check_request(&call).is_some());
+        let call = request(2, "tools/call", serde_json::json!({ "name": "get_issue" }));
+        assert!(filter.check_request(&call).is_none());
+        let complete = request(
+            3,
+            "completion/complete",
+            serde_json::json!({
+                "ref": { "type": "ref/prompt", "name": "internal" },
+                "argument": { "name": "arg", "value": "" },
+            }),
+        );
+        assert!(filter.check_request(&complete).is_some());
+
+        assert!(
+            filter
+                .check_request(&request(4, "tools/list", serde_json::json!({})))
+                .is_none()
+        );
+        let mut listed = response(
+            4,
+            serde_json::json!({
+                "tools": [
+                    { "name": "get_issue", "description": "", "inputSchema": {} },
+                    { "name": "delete_repo", "description": "", "inputSchema": {} },
+                ],
+            }),
+        );
+        filter.filter_response(&mut listed);
+        let listed = serde_json::to_value(&listed).unwrap();
+        assert_eq!(
+            listed["result"]["tools"].as_array().unwrap().len(),
+            1,
+            "{listed}"
+        );
+        assert_eq!(listed["result"]["tools"][0]["name"], "get_issue");
+    }
+
+    #[test]
+    fn unfilterable_list_responses_are_replaced_with_an_error() {
+        let tools = AccessList {
+            deny: list(&["delete_*"]),
+            ..Default::default()
+        };
+        let mut filter = filter(tools, AccessList::default());
+        assert!(
+            filter
+                .check_request(&request(1, "tools/list", serde_json::json!({})))
+                .is_none()
+        );
+
+        let mut listed = response(
+            1,
+            serde_json::json!({ "unexpected": [{ "name": "delete_repo" }] }),
+        );
+        filter.filter_response(&mut listed);
+        let listed = serde_json::to_value(&listed).unwrap();
+        assert!(listed.get("result").is_none(), "{listed}");
+        assert_eq!(listed["id"], 1);
+        assert!(listed["error"]["code"].is_i64(), "{listed}");
+    }
+}
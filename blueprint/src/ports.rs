@@ -0,0 +1,192 @@
+//! Port allocation for the MCP servers.
+//!
+//! Ports are reserved by binding them and keeping the listener alive until it is handed to
+//! the SSE gateway, so no other process can take the port in between. Containers started
+//! without a gateway bind the host port themselves; for those the reservation is released
+//! right before the container starts and the start is retried on another port on conflict.
+//!
+//! By default the OS picks any free port, operators can restrict the ports to a range with
+//! `MCP_PORT_RANGE` (e.g. `20000-20999`).
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Error;
+
+/// How many times the start of an MCP server is attempted on a new port after a conflict
+pub const MAX_PORT_ATTEMPTS: usize = 3;
+
+/// Hands out ports to the MCP servers and tracks which server holds which port
+#[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PortAllocator {
+    /// The ports that can be allocated, any free port if `None`
+    pub range: Option<RangeInclusive<u16>>,
+    /// Mapping of the servers, keyed as `{service_id}/{name}`, to their allocated port
+    pub allocations: BTreeMap<String, u16>,
+    /// Where to resume scanning the range, so a port that just failed is not handed out again
+    #[serde(skip)]
+    next: u16,
+}
+
+impl PortAllocator {
+    /// Creates an allocator restricted to the range in `MCP_PORT_RANGE`, if set.
+    pub fn from_env() -> Result<Self, Error> {
+        let range = match std::env::var("MCP_PORT_RANGE") {
+            Ok(value) => Some(parse_range(&value).ok_or(Error::InvalidPortRange(value))?),
+            Err(_) => None,
+        };
+        Ok(Self {
+            range,
+            ..Default::default()
+        })
+    }
+
+    /// Reserves a port on localhost for the given server, replacing its previous allocation.
+    pub fn reserve(&mut self, key: &str) -> Result<PortReservation, Error> {
+        self.release(key);
+        let listener = match self.range.clone() {
+            Some(range) => self.bind_in_range(range)?,
+            None => TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?,
+        };
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        self.allocations.insert(key.to_string(), port);
+        blueprint_sdk::debug!(%key, %port, "Reserved port");
+        Ok(PortReservation {
+            port,
+            listener: Arc::new(Mutex::new(Some(listener))),
+        })
+    }
+
+    /// Releases the port allocated to the given server.
+    pub fn release(&mut self, key: &str) -> Option<u16> {
+        self.allocations.remove(key)
+    }
+
+    /// Releases the ports allocated to all the servers of the given service.
+    pub fn release_service(&mut self, service_id: u64) {
+        let prefix = format!("{service_id}/");
+        self.allocations
+            .retain(|key, _| key != &service_id.to_string() && !key.starts_with(&prefix));
+    }
+
+    /// Binds the first free port of the range, starting after the last one handed out.
+    fn bind_in_range(&mut self, range: RangeInclusive<u16>) -> Result<TcpListener, Error> {
+        let (start, end) = (*range.start(), *range.end());
+        let next = self.next.clamp(start, end);
+        let candidates = (next..=end).chain(start..next);
+        for port in candidates {
+            if self.allocations.values().any(|p| *p == port) {
+                continue;
+            }
+            if let Ok(listener) = TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+                self.next = port.checked_add(1).unwrap_or(start);
+                return Ok(listener);
+            }
+        }
+        Err(Error::PortsExhausted(start, end))
+    }
+}
+
+/// `start-end`, both inclusive
+fn parse_range(value: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = value.split_once('-')?;
+    let start: u16 = start.trim().parse().ok()?;
+    let end: u16 = end.trim().parse().ok()?;
+    (start > 0 && start <= end).then_some(start..=end)
+}
+
+/// A port reserved by a live listener
+///
+/// The listener is taken by the SSE gateway to serve on, or dropped with
+/// [`PortReservation::release`] when the port must be bound by another process.
+#[derive(Debug, Clone)]
+pub struct PortReservation {
+    port: u16,
+    listener: Arc<Mutex<Option<TcpListener>>>,
+}
+
+impl PortReservation {
+    /// The reserved port
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The reserved address on localhost
+    pub fn addr(&self) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, self.port).into()
+    }
+
+    /// Takes the listener holding the port, `None` if it was already taken or released.
+    pub fn take_listener(&self) -> Option<TcpListener> {
+        self.listener
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+
+    /// Closes the listener so the port can be bound by another process.
+    pub fn release(&self) {
+        drop(self.take_listener());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_are_held_until_released() {
+        let mut allocator = PortAllocator::default();
+        let reservation = allocator.reserve("1/default").unwrap();
+        assert_ne!(reservation.port(), 0);
+        assert!(TcpListener::bind(reservation.addr()).is_err());
+
+        let listener = reservation.take_listener().unwrap();
+        assert!(reservation.take_listener().is_none());
+        drop(listener);
+        assert_eq!(allocator.release("1/default"), Some(reservation.port()));
+    }
+
+    #[test]
+    fn ranges_are_scanned_past_the_allocated_ports() {
+        let reservation = PortAllocator::default().reserve("probe").unwrap();
+        let start = reservation.port();
+        reservation.release();
+        let Some(end) = start.checked_add(2) else {
+            return;
+        };
+        let mut allocator = PortAllocator {
+            range: Some(start..=end),
+            ..Default::default()
+        };
+
+        let mut ports = Vec::new();
+        for key in ["1/a", "1/b", "2/a"] {
+            match allocator.reserve(key) {
+                Ok(reservation) => ports.push(reservation.port()),
+                // Another process holds a port of the range
+                Err(_) => return,
+            }
+        }
+        assert_eq!(ports, [start, start + 1, end]);
+        assert!(matches!(
+            allocator.reserve("3/a"),
+            Err(Error::PortsExhausted(..))
+        ));
+
+        allocator.release_service(1);
+        assert_eq!(allocator.allocations.keys().collect::<Vec<_>>(), ["2/a"]);
+    }
+
+    #[test]
+    fn port_ranges_are_parsed() {
+        assert_eq!(parse_range("20000-20999"), Some(20000..=20999));
+        assert_eq!(parse_range(" 1 - 1 "), Some(1..=1));
+        for invalid in ["", "0-10", "10-1", "1-", "a-b", "1-70000"] {
+            assert_eq!(parse_range(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! Readiness probe of the MCP servers.
+//!
+//! A runner returns as soon as the MCP server is spawned, but `uvx`/`bunx` may still be
+//! resolving the package and a container may not be listening yet. Before the endpoint is
+//! returned, the server is probed until it accepts connections and answers an MCP
+//! `initialize` request, over SSE (`/sse`) or Streamable HTTP (`/mcp`), or until its HTTP
+//! health path answers with a success status when one is configured.
+//!
+//! Behind the gateway, each probe opens a session and spawns the MCP server for it. The probe
+//! sends the probe token of the gateway, so that its sessions are not audited and end with the
+//! probe instead of waiting for it to reconnect.
+//!
+//! The probe gives up after `MCP_READINESS_TIMEOUT` seconds, [`DEFAULT_READINESS_TIMEOUT`]
+//! when unset.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt, TryStreamExt};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::client::conn::http1::SendRequest;
+use hyper::{Method, Request, Response, StatusCode, header};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+
+use crate::error::Error;
+use crate::transport::PROBE_TOKEN_HEADER;
+
+/// How long an MCP server may take to become ready by default
+pub const DEFAULT_READINESS_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long to wait between two probes
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The id of the `initialize` request sent by the probe
+const PROBE_REQUEST_ID: u64 = 0;
+
+static READINESS_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    std::env::var("MCP_READINESS_TIMEOUT")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_READINESS_TIMEOUT)
+});
+
+/// Where the MCP server is reached
+#[derive(Debug, Clone)]
+pub enum ProbeTarget {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ProbeTarget {
+    /// The target of an endpoint handed out by the manager, `http://{addr}` or `unix://{path}`.
+    pub fn from_endpoint(endpoint: &str) -> Option<Self> {
+        if let Some(path) = endpoint.strip_prefix("unix://") {
+            return Some(Self::Unix(path.into()));
+        }
+        let addr = endpoint.strip_prefix("http://")?.trim_end_matches('/');
+        addr.parse().ok().map(Self::Tcp)
+    }
+}
+
+/// How the MCP server is checked
+#[derive(Debug, Clone)]
+pub enum Probe {
+    /// An MCP `initialize` request over SSE at the given path, or over Streamable HTTP at
+    /// `/mcp` if there is none, sending the probe token of the gateway, if any
+    Initialize {
+        sse_path: String,
+        probe_token: Option<String>,
+    },
+    /// A `GET` on the given path answering with a success status
+    Health { path: String },
+}
+
+/// Waits until the MCP server is ready, or stopped by `ct`.
+#[tracing::instrument(skip(ct))]
+pub async fn wait_ready(
+    target: &ProbeTarget,
+    probe: &Probe,
+    ct: &CancellationToken,
+) -> Result<(), Error> {
+    let timeout = *READINESS_TIMEOUT;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut last_error = String::from("not probed");
+    loop {
+        let attempt = tokio::time::timeout_at(deadline, probe_once(target, probe));
+        tokio::select! {
+            result = attempt => match result {
+                Ok(Ok(())) => {
+                    tracing::debug!("MCP server is ready");
+                    return Ok(());
+                }
+                Ok(Err(e)) => last_error = e,
+                Err(_) => return Err(Error::NotReady(timeout, last_error)),
+            },
+            () = ct.cancelled() => {
+                return Err(Error::NotReady(timeout, "the MCP server stopped".to_string()));
+            }
+        }
+        tracing::trace!(error = %last_error, "MCP server is not ready yet");
+        if tokio::time::Instant::now() + PROBE_INTERVAL >= deadline {
+            return Err(Error::NotReady(timeout, last_error));
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+async fn probe_once(target: &ProbeTarget, probe: &Probe) -> Result<(), String> {
+    match probe {
+        Probe::Health { path } => {
+            let response = send(target, get(path)).await?;
+            let status = response.status();
+            if status.is_success() {
+                Ok(())
+            } else {
+                Err(format!("health check answered {status}"))
+            }
+        }
+        Probe::Initialize {
+            sse_path,
+            probe_token,
+        } => {
+            let mut request = get(sse_path);
+            if let Some(token) = probe_token
+                && let Ok(token) = header::HeaderValue::from_str(token)
+            {
+                request.headers_mut().insert(PROBE_TOKEN_HEADER, token);
+            }
+            let response = send(target, request).await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return initialize_streamable_http(target).await;
+            }
+            initialize_sse(target, response).await
+        }
+    }
+}
+
+/// Sends the `initialize` request to the endpoint announced on the SSE stream and waits for
+/// its response on the stream.
+async fn initialize_sse(target: &ProbeTarget, response: Response<Incoming>) -> Result<(), String> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("SSE endpoint answered {status}"));
+    }
+    let mut events = SseReader::new(response.into_body());
+    let post_path = loop {
+        let (event, data) = events.next().await?;
+        if event == "endpoint" {
+            break path_of(&data).to_string();
+        }
+    };
+    let response = send(target, post(&post_path, initialize_request())).await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("message endpoint answered {status}"));
+    }
+    loop {
+        let (event, data) = events.next().await?;
+        if event == "message"
+            && let Some(result) = initialize_result(&data)
+        {
+            return result;
+        }
+    }
+}
+
+/// Sends the `initialize` request to the Streamable HTTP endpoint, answered with either a
+/// JSON body or an SSE stream.
+async fn initialize_streamable_http(target: &ProbeTarget) -> Result<(), String> {
+    let response = send(target, post("/mcp", initialize_request())).await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!(
+            "neither /sse nor /mcp are served, /mcp answered {status}"
+        ));
+    }
+    let is_sse = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if !is_sse {
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| e.to_string())?
+            .to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        return initialize_result(&body)
+            .unwrap_or_else(|| Err("unexpected initialize response".to_string()));
+    }
+    let mut events = SseReader::new(response.into_body());
+    loop {
+        let (_, data) = events.next().await?;
+        if let Some(result) = initialize_result(&data) {
+            return result;
+        }
+    }
+}
+
+fn initialize_request() -> Bytes {
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": PROBE_REQUEST_ID,
+        "method": "initialize",
+        "params": {
+            "protocolVersion": "2024-11-05",
+            "capabilities": {},
+            "clientInfo": {
+                "name": "mcp-blueprint-readiness",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+        },
+    });
+    Bytes::from(request.to_string())
+}
+
+/// The outcome of the `initialize` request, `None` if `data` is another message.
+fn initialize_result(data: &str) -> Option<Result<(), String>> {
+    let message: serde_json::Value = serde_json::from_str(data).ok()?;
+    if message.get("id")?.as_u64()? != PROBE_REQUEST_ID {
+        return None;
+    }
+    match message.get("error") {
+        Some(error) => Some(Err(format!("initialize failed: {error}"))),
+        None => Some(Ok(())),
+    }
+}
+
+/// The path and query of an absolute or relative URL.
+pub(crate) fn path_of(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => url,
+    }
+}
+
+pub(crate) fn get(path: &str) -> Request<Full<Bytes>> {
+    request(Method::GET, path, Bytes::new())
+}
+
+pub(crate) fn post(path: &str, body: Bytes) -> Request<Full<Bytes>> {
+    request(Method::POST, path, body)
+}
+
+fn request(method: Method, path: &str, body: Bytes) -> Request<Full<Bytes>> {
+    let mut request = Request::new(Full::new(body));
+    *request.method_mut() = method;
+    *request.uri_mut() = path
+        .parse()
+        .unwrap_or_else(|_| hyper::Uri::from_static("/"));
+    let headers = request.headers_mut();
+    headers.insert(header::HOST, header::HeaderValue::from_static("localhost"));
+    headers.insert(
+        header::ACCEPT,
+        header::HeaderValue::from_static("application/json, text/event-stream"),
+    );
+    headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    request
+}
+
+/// Sends a request on a new connection.
+pub(crate) async fn send(
+    target: &ProbeTarget,
+    request: Request<Full<Bytes>>,
+) -> Result<Response<Incoming>, String> {
+    let mut sender = match target {
+        ProbeTarget::Tcp(addr) => {
+            let stream = tokio::net::TcpStream::connect(addr)
+                .await
+                .map_err(|e| format!("failed to connect to {addr}: {e}"))?;
+            handshake(stream).await?
+        }
+        ProbeTarget::Unix(path) => {
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .map_err(|e| format!("failed to connect to {}: {e}", path.display()))?;
+            handshake(stream).await?
+        }
+    };
+    sender
+        .send_request(request)
+        .await
+        .map_err(|e| format!("request failed: {e}"))
+}
+
+async fn handshake<S>(stream: S) -> Result<SendRequest<Full<Bytes>>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| format!("HTTP handshake failed: {e}"))?;
+    tokio::spawn(async move {
+        // Upgraded connections are handed over to the router, see `router::forward`
+        let _ = connection.with_upgrades().await;
+    });
+    Ok(sender)
+}
+
+/// Reads `(event, data)` pairs from an SSE body.
+pub(crate) struct SseReader {
+    body: BoxStream<'static, Result<Bytes, String>>,
+    buffer: BytesMut,
+}
+
+impl SseReader {
+    pub(crate) fn new(body: Incoming) -> Self {
+        Self::from_stream(body.into_data_stream())
+    }
+
+    /// Reads the events from the chunks of a body.
+    pub(crate) fn from_stream<S, E>(chunks: S) -> Self
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: std::fmt::Display,
+    {
+        Self {
+            body: chunks
+                .map_err(|e| format!("failed to read the SSE stream: {e}"))
+                .boxed(),
+            buffer: BytesMut::new(),
+        }
+    }
+
+    pub(crate) async fn next(&mut self) -> Result<(String, String), String> {
+        loop {
+            if let Some(event) = self.parse_event() {
+                return Ok(event);
+            }
+            let data = self.body.next().await.ok_or("the SSE stream ended")??;
+            self.buffer.extend_from_slice(&data);
+        }
+    }
+
+    /// Parses the first complete event of the buffer, skipping comments (keep-alives).
+    fn parse_event(&mut self) -> Option<(String, String)> {
+        loop {
+            let text = std::str::from_utf8(&self.buffer)
+                .ok()?
+                .replace("\r\n", "\n");
+            let end = text.find("\n\n")?;
+            let block = text[..end].to_string();
+            let consumed = self.consumed_len(end);
+            self.buffer.advance(consumed);
+            let mut event = String::from("message");
+            let mut data = Vec::new();
+            for line in block.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    event = value.trim().to_string();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data.push(value.strip_prefix(' ').unwrap_or(value));
+                }
+            }
+            if !data.is_empty() {
+                return Some((event, data.join("\n")));
+            }
+        }
+    }
+
+    /// The length in the raw buffer of the first `end + 2` bytes of the normalized text.
+    fn consumed_len(&self, end: usize) -> usize {
+        let mut normalized = 0;
+        let mut raw = 0;
+        let bytes = &self.buffer[..];
+        while normalized < end + 2 && raw < bytes.len() {
+            if bytes[raw] == b'\r' && bytes.get(raw + 1) == Some(&b'\n') {
+                raw += 1;
+            }
+            raw += 1;
+            normalized += 1;
+        }
+        raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::audit::{AuditArguments, AuditConfig, AuditLog};
+    use crate::test_support::{fake_factory, temp_socket};
+    use crate::transport::{QueueMetrics, SseServer, SseServerConfig};
+
+    const PROBE_TOKEN: &str = "probe-token";
+
+    /// Serves the fake MCP server behind the gateway, auditing the sessions to `audit_path`.
+    async fn gateway(name: &str, audit_path: &std::path::Path) -> (ProbeTarget, QueueMetrics) {
+        let _ = std::fs::remove_file(audit_path);
+        let audit = AuditLog::open(AuditConfig {
+            path: audit_path.to_path_buf(),
+            arguments: AuditArguments::Hash,
+            redact: Vec::new(),
+            caller_headers: Vec::new(),
+        })
+        .await
+        .unwrap();
+        let socket = temp_socket(name);
+        let metrics = QueueMetrics::default();
+        let config = SseServerConfig {
+            unix_socket: Some(socket.clone()),
+            audit: Some(Arc::new(audit).for_service(1)),
+            probe_token: Some(PROBE_TOKEN.to_string()),
+            metrics: metrics.clone(),
+            tls: None,
+            ..SseServerConfig::new(([127, 0, 0, 1], 0).into())
+        };
+        SseServer::serve_with_config(config)
+            .await
+            .unwrap()
+            .forward(fake_factory);
+        (ProbeTarget::Unix(socket), metrics)
+    }
+
+    fn initialize_probe(probe_token: Option<&str>) -> Probe {
+        Probe::Initialize {
+            sse_path: "/sse".to_string(),
+            probe_token: probe_token.map(ToString::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn probe_sessions_end_with_the_probe_and_are_not_audited() {
+        let audit_path =
+            std::env::temp_dir().join(format!("mcp-probe-{}.jsonl", std::process::id()));
+        let (target, metrics) = gateway("probe", &audit_path).await;
+        let ct = CancellationToken::new();
+
+        wait_ready(&target, &initialize_probe(Some(PROBE_TOKEN)), &ct)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(metrics.snapshot().sessions, 0);
+        assert_eq!(std::fs::read_to_string(&audit_path).unwrap(), "");
+
+        // A client sending a wrong token gets a regular session
+        wait_ready(&target, &initialize_probe(Some("guessed")), &ct)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(metrics.snapshot().sessions, 1);
+        let audited = std::fs::read_to_string(&audit_path).unwrap();
+        assert!(audited.contains("\"method\":\"initialize\""), "{audited}");
+    }
+}
@@ -0,0 +1,194 @@
+//! Reconciliation of the MCP servers.
+//!
+//! An MCP server can exit on its own, a registration with the auth proxy can be lost, and a
+//! container can outlive the blueprint when it crashed. The reconciler periodically compares
+//! the state of the manager with the running containers, renews the bridge registrations and
+//! repairs the drift:
+//!
+//! - A server that exited, or whose container exited, is unregistered and marked failed.
+//! - A running server is registered again on every pass. Registering is idempotent, so a
+//!   registration the bridge lost, e.g. when it restarted or was unregistered by hand, is
+//!   restored within an interval.
+//! - The registration of a service without a running server is removed.
+//! - A container of the blueprint without a running Docker server is removed.
+//!
+//! The registrations are not listed from the bridge, so one of a service the manager doesn't
+//! know of, e.g. left by a previous run of the blueprint, stays until the service is started
+//! or stopped again.
+//!
+//! The queue depths of the gateways with open sessions are logged along the way; they are
+//! also served by the metrics endpoint (see [`crate::metrics`]).
+//!
+//! It runs every `MCP_RECONCILE_INTERVAL` seconds, [`DEFAULT_RECONCILE_INTERVAL`] when unset,
+//! and is disabled with `0`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use docktopus::bollard::container::ListContainersOptions;
+use docktopus::bollard::errors::Error as DockerError;
+
+use crate::error::Error;
+use crate::manager::docker::{
+    CONTAINER_NAME_PREFIX, DockerRunner, container_name, parse_container_name,
+};
+use crate::manager::{McpServerState, ServiceEntry};
+use crate::{McpRuntime, MyContext, registration};
+
+/// How often the MCP servers are reconciled by default
+pub const DEFAULT_RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Spawns the reconciler, unless the operator disabled it with `MCP_RECONCILE_INTERVAL=0`.
+pub fn spawn_reconciler(ctx: &MyContext) {
+    let interval = std::env::var("MCP_RECONCILE_INTERVAL")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .map_or(DEFAULT_RECONCILE_INTERVAL, Duration::from_secs);
+    if interval.is_zero() {
+        return;
+    }
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately, nothing has started yet
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            reconcile(&ctx).await;
+        }
+    });
+    tracing::info!(?interval, "MCP server reconciler started");
+}
+
+/// Compares the MCP servers with the bridge registrations and the containers once.
+#[tracing::instrument(skip(ctx))]
+pub async fn reconcile(ctx: &MyContext) {
+    for service_id in ctx.mcp_server_manager.service_ids() {
+        let Some(mut service) = ctx.mcp_server_manager.try_lock_service(service_id).await else {
+            continue;
+        };
+        if let Err(e) = reconcile_service(ctx, &mut service).await {
+            blueprint_sdk::warn!(error = %e, %service_id, "Failed to reconcile the MCP server");
+        }
+    }
+    if let Err(e) = remove_orphan_containers(ctx).await {
+        blueprint_sdk::debug!(error = %e, "Skipping the reconciliation of the containers");
+    }
+}
+
+async fn reconcile_service(ctx: &MyContext, service: &mut ServiceEntry) -> Result<(), Error> {
+    let mut exited = Vec::new();
+    for (name, server) in &service.servers {
+        let gone = match &server.server {
+            Some(server) if server.runtime == McpRuntime::Docker => {
+                !container_running(ctx, &container_name(service.service_id, name)).await?
+            }
+            Some(server) => server
+                .cancellation_token
+                .as_ref()
+                .is_none_or(|ct| ct.is_cancelled()),
+            None => false,
+        };
+        if gone {
+            exited.push(name.clone());
+        } else if let Some(server) = &server.server {
+            let queues = server.queue_metrics.snapshot();
+            if queues.sessions > 0 {
+                blueprint_sdk::info!(
+                    service_id = service.service_id,
+                    %name,
+                    ?queues,
+                    "MCP server queues"
+                );
+            }
+        }
+    }
+    for name in exited {
+        blueprint_sdk::warn!(service_id = service.service_id, %name, "MCP server exited");
+        ctx.mcp_server_manager.stop_server(service, &name).await?;
+        if let Some(server) = service.servers.get_mut(&name) {
+            server.state = McpServerState::Failed("the MCP server exited".to_string());
+        }
+    }
+    if !service.is_running() && service.endpoint.is_some() {
+        ctx.mcp_server_manager.stop_all(service).await?;
+    }
+
+    let running = service.is_running();
+    if running {
+        if service.registered {
+            blueprint_sdk::debug!(service_id = service.service_id, "Renewing the registration");
+        } else {
+            blueprint_sdk::info!(
+                service_id = service.service_id,
+                "Registering the MCP server again"
+            );
+        }
+        registration::register(ctx, service).await?;
+    } else if service.registered {
+        blueprint_sdk::info!(
+            service_id = service.service_id,
+            "Removing the stale registration"
+        );
+        registration::unregister(ctx, service).await?;
+    }
+    Ok(())
+}
+
+/// Whether the container is running or being restarted by its restart policy.
+async fn container_running(ctx: &MyContext, name: &str) -> Result<bool, Error> {
+    match ctx.docker.inspect_container(name, None).await {
+        Ok(container) => Ok(container
+            .state
+            .is_some_and(|state| state.running == Some(true) || state.restarting == Some(true))),
+        Err(DockerError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(false),
+        Err(e) => Err(Error::Io(std::io::Error::other(e))),
+    }
+}
+
+/// Removes the containers of the blueprint that don't belong to a running Docker server.
+async fn remove_orphan_containers(ctx: &MyContext) -> Result<(), Error> {
+    let options = ListContainersOptions {
+        all: true,
+        filters: HashMap::from([("name", vec![CONTAINER_NAME_PREFIX])]),
+        ..Default::default()
+    };
+    let containers = ctx
+        .docker
+        .list_containers(Some(options))
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    for container in containers {
+        let Some(id) = container.id else {
+            continue;
+        };
+        // The name filter matches substrings, only keep the names the blueprint gives
+        let names = container.names.unwrap_or_default();
+        let Some((service_id, name)) = names.iter().find_map(|name| parse_container_name(name))
+        else {
+            continue;
+        };
+        // Held while the container is removed, so it can't be started again meanwhile
+        let service = ctx.mcp_server_manager.try_lock_service(service_id).await;
+        let in_use = service
+            .as_ref()
+            .and_then(|service| service.servers.get(name))
+            .is_some_and(|server| {
+                server.state == McpServerState::Running
+                    && server
+                        .server
+                        .as_ref()
+                        .is_some_and(|server| server.runtime == McpRuntime::Docker)
+            });
+        if !in_use {
+            blueprint_sdk::info!(%service_id, %name, container = %id, "Removing orphan MCP server container");
+            DockerRunner
+                .remove_stale_container(&ctx.docker, &id)
+                .await?;
+        }
+    }
+    Ok(())
+}
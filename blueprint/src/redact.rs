@@ -0,0 +1,313 @@
+//! Redaction of sensitive values in tracing output.
+//!
+//! Environment variables, headers and arguments of the MCP servers routinely carry API keys
+//! and tokens. They are held in [`EnvVars`] and [`Args`], whose `Debug` implementations replace
+//! the sensitive values with [`REDACTED`], so they can be logged (e.g. with
+//! `mcp_blueprint=debug`) without leaking customer secrets.
+//!
+//! A key is sensitive if it matches one of the case-insensitive patterns (with `*` wildcards)
+//! configured by the operator in `MCP_REDACT_PATTERNS` (comma separated), or
+//! [`DEFAULT_SENSITIVE_PATTERNS`] when unset. Keys are normalized like environment variables,
+//! the `X-Api-Key` header and the `--api-key` flag are checked as `X_API_KEY` and `API_KEY`.
+//! Decrypted secret environment variables are always redacted.
+//!
+//! The same patterns redact the arguments recorded in the [audit log](crate::audit).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::LazyLock;
+
+use crate::policy::glob_match;
+
+/// The value shown in place of a sensitive value
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Key patterns considered sensitive by default
+pub const DEFAULT_SENSITIVE_PATTERNS: &[&str] = &[
+    "*TOKEN*",
+    "*KEY*",
+    "*SECRET*",
+    "*PASSWORD*",
+    "*CREDENTIAL*",
+    "AUTHORIZATION",
+];
+
+/// Set by the operator with `MCP_REDACT_PATTERNS`
+pub(crate) static SENSITIVE_PATTERNS: LazyLock<Vec<String>> =
+    LazyLock::new(|| match std::env::var("MCP_REDACT_PATTERNS") {
+        Ok(value) => parse_patterns(&value),
+        Err(_) => parse_patterns(&DEFAULT_SENSITIVE_PATTERNS.join(",")),
+    });
+
+/// Parses comma separated key patterns.
+pub(crate) fn parse_patterns(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_uppercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Returns `true` if the normalized `key` matches one of the patterns.
+pub(crate) fn matches_any(patterns: &[String], key: &str) -> bool {
+    let key = key.to_uppercase().replace('-', "_");
+    patterns.iter().any(|p| glob_match(p, &key))
+}
+
+/// Returns `true` if the value of `key` must not be logged.
+pub fn is_sensitive(key: &str) -> bool {
+    matches_any(&SENSITIVE_PATTERNS, key)
+}
+
+/// Environment variables of an MCP server
+///
+/// Values of sensitive keys, and of secrets inserted with [`EnvVars::insert_secret`],
+/// are redacted from the `Debug` output.
+#[derive(Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct EnvVars {
+    vars: BTreeMap<String, String>,
+    #[serde(skip)]
+    secrets: BTreeSet<String>,
+}
+
+impl EnvVars {
+    /// Inserts a variable whose value is never shown, whatever its name.
+    pub fn insert_secret(&mut self, key: String, value: String) {
+        self.secrets.insert(key.clone());
+        self.vars.insert(key, value);
+    }
+}
+
+impl Deref for EnvVars {
+    type Target = BTreeMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.vars
+    }
+}
+
+impl DerefMut for EnvVars {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.vars
+    }
+}
+
+impl FromIterator<(String, String)> for EnvVars {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Self {
+            vars: iter.into_iter().collect(),
+            secrets: BTreeSet::new(),
+        }
+    }
+}
+
+impl fmt::Debug for EnvVars {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.vars.iter().map(|(key, value)| {
+                if self.secrets.contains(key) || is_sensitive(key) {
+                    (key, REDACTED)
+                } else {
+                    (key, value.as_str())
+                }
+            }))
+            .finish()
+    }
+}
+
+/// Command line arguments of an MCP server
+///
+/// Values of sensitive flags (`--api-key=...` or `--api-key ...`) are redacted from the
+/// `Debug` output.
+#[derive(Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Args(Vec<String>);
+
+impl Args {
+    pub fn into_inner(self) -> Vec<String> {
+        self.0
+    }
+}
+
+impl Deref for Args {
+    type Target = Vec<String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Vec<String>> for Args {
+    fn from(args: Vec<String>) -> Self {
+        Self(args)
+    }
+}
+
+impl fmt::Debug for Args {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        let mut redact_next = false;
+        for arg in &self.0 {
+            if std::mem::take(&mut redact_next) && !arg.starts_with('-') {
+                list.entry(&REDACTED);
+                continue;
+            }
+            let Some(flag) = arg.strip_prefix('-') else {
+                list.entry(arg);
+                continue;
+            };
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (flag, None),
+            };
+            let key = name.trim_start_matches('-').replace('-', "_");
+            match value {
+                Some(_) if is_sensitive(&key) => {
+                    list.entry(&format!("-{name}={REDACTED}"));
+                }
+                None if is_sensitive(&key) => {
+                    redact_next = true;
+                    list.entry(arg);
+                }
+                _ => {
+                    list.entry(arg);
+                }
+            }
+        }
+        list.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use blueprint_sdk::tangle::extract::{List, Optional};
+
+    use super::*;
+    use crate::{McpRuntime, McpServerConfig};
+
+    /// The secret values, none of which may be logged
+    const SECRETS: [&str; 8] = [
+        "ghp_env_token",
+        "env_api_key",
+        "decrypted_secret",
+        "header_bearer_token",
+        "header_api_key",
+        "resolved_header_secret",
+        "arg_api_key",
+        "arg_token",
+    ];
+
+    /// Collects everything written by the subscriber
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture_logs(f: impl FnOnce()) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        String::from_utf8(captured.0.lock().unwrap().clone()).unwrap()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Optional<List<(String, String)>> {
+        let pairs = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Optional(Some(List(pairs)))
+    }
+
+    fn args() -> Vec<String> {
+        [
+            "--api-key",
+            "arg_api_key",
+            "--token=arg_token",
+            "--verbose",
+            "server.py",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    /// Logs the arguments of a runner the way the runners do when spawning the MCP server.
+    #[tracing::instrument(fields(%package, ?args, ?env_vars, ?headers, runtime = "test"))]
+    fn spawn(package: String, args: Args, env_vars: EnvVars, headers: EnvVars) {
+        tracing::debug!(?args, ?env_vars, ?headers, "spawning the MCP server");
+    }
+
+    #[test]
+    fn secrets_are_not_logged() {
+        let config = McpServerConfig {
+            runtime: McpRuntime::Python,
+            package: "mcp-server".to_string(),
+            args: Optional(Some(List(args()))),
+            env: pairs(&[
+                ("GITHUB_TOKEN", "ghp_env_token"),
+                ("OPENAI_API_KEY", "env_api_key"),
+                ("LOG_LEVEL", "debug"),
+            ]),
+            secret_env: pairs(&[("DB_PASSWORD", "<ciphertext>")]),
+            headers: pairs(&[
+                ("Authorization", "Bearer header_bearer_token"),
+                ("X-Api-Key", "header_api_key"),
+                ("X-Resolved", "${DB_PASSWORD}"),
+            ]),
+            ..Default::default()
+        };
+
+        let mut env_vars: EnvVars = config.env.0.clone().unwrap().0.into_iter().collect();
+        env_vars.insert_secret("DB_PASSWORD".to_string(), "decrypted_secret".to_string());
+        let mut headers = EnvVars::default();
+        headers.insert_secret(
+            "X-Resolved".to_string(),
+            "resolved_header_secret".to_string(),
+        );
+        headers.insert(
+            "Authorization".to_string(),
+            "Bearer header_bearer_token".to_string(),
+        );
+
+        let logs = capture_logs(|| {
+            tracing::info!(?config, "starting the MCP server");
+            spawn(
+                config.package.clone(),
+                Args::from(args()),
+                env_vars,
+                headers,
+            );
+        });
+        for secret in SECRETS {
+            assert!(!logs.contains(secret), "{secret} was logged:\n{logs}");
+        }
+        // Everything else is still there to debug with
+        for visible in [
+            "mcp-server",
+            "LOG_LEVEL",
+            "debug",
+            "--verbose",
+            "server.py",
+            "DB_PASSWORD",
+        ] {
+            assert!(logs.contains(visible), "{visible} is missing:\n{logs}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Registration of the MCP servers with the auth proxy of the bridge.
+//!
+//! The auth proxy only forwards requests of the service owners to the registered endpoints.
+//! Registrations are tracked in the [`ServiceEntry`] of each service, so a failed start or stop
+//! can be rolled back and the reconciliation can repair them.
+
+use blueprint_sdk::auth::models::ServiceOwnerModel;
+use blueprint_sdk::auth::types::KeyType;
+
+use crate::MyContext;
+use crate::error::Error;
+use crate::manager::ServiceEntry;
+
+/// The prefix of the API keys of the MCP servers
+const API_KEY_PREFIX: &str = "mcp_";
+
+/// Registers the endpoint of the running MCP server of the service, allowing its owner keys.
+pub async fn register(ctx: &MyContext, service: &mut ServiceEntry) -> Result<(), Error> {
+    let Some(endpoint) = service.endpoint.as_deref() else {
+        return Ok(());
+    };
+    let mut owners = Vec::with_capacity(2);
+    if let Some(owner) = &service.owner {
+        owners.push(ServiceOwnerModel {
+            key_type: KeyType::Sr25519 as _,
+            key_bytes: owner.0.to_vec(),
+        });
+    }
+    if let Some(ecdsa_owner) = &service.ecdsa_owner {
+        owners.push(ServiceOwnerModel {
+            key_type: KeyType::Ecdsa as _,
+            key_bytes: ecdsa_owner.clone(),
+        });
+    }
+    let bridge = ctx.env.bridge().await?;
+    bridge
+        .register_blueprint_service_proxy(
+            service.service_id,
+            Some(API_KEY_PREFIX),
+            endpoint,
+            &owners,
+        )
+        .await?;
+    service.registered = true;
+    blueprint_sdk::debug!(service_id = service.service_id, %endpoint, "Registered MCP server");
+    Ok(())
+}
+
+/// Unregisters the endpoint of the service.
+pub async fn unregister(ctx: &MyContext, service: &mut ServiceEntry) -> Result<(), Error> {
+    let bridge = ctx.env.bridge().await?;
+    bridge
+        .unregister_blueprint_service_proxy(service.service_id)
+        .await?;
+    service.registered = false;
+    blueprint_sdk::debug!(service_id = service.service_id, "Unregistered MCP server");
+    Ok(())
+}
@@ -0,0 +1,361 @@
+//! Routing of the named MCP servers of a service under their own path.
+//!
+//! The auth proxy of the bridge forwards the requests of a service to a single endpoint. When
+//! a service bundles several MCP servers, that endpoint is a router forwarding `/{name}/...`
+//! to the MCP server named `name`, with the prefix stripped, so e.g. `/github/sse` reaches
+//! `/sse` of the `github` server.
+//!
+//! The `endpoint` events of the SSE streams are rewritten with the prefix, so the clients
+//! post their messages back through the router, along with the `X-Forwarded-Prefix` and
+//! `X-Forwarded-Host` of the proxy in front, if any, and the `MCP_BASE_PATH` of the operator
+//! before the prefix rather than after it. WebSocket upgrades, e.g. of `/github/ws`,
+//! are passed through to the MCP server.
+//!
+//! `/sse`, `/message` and `/ws` of the router serve the [`Gateway`] merging all the MCP servers of
+//! the service into one.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use axum::Router;
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use futures::StreamExt;
+use http_body_util::{BodyExt, Full};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use tokio_util::sync::CancellationToken;
+
+use crate::gateway::Gateway;
+use crate::ports::PortReservation;
+use crate::readiness::{self, ProbeTarget};
+use crate::tls::{self, TlsListener};
+use crate::transport::{
+    BASE_PATH, MAX_BODY_SIZE, SseServer, SseServerConfig, bind_unix_socket, exceeds_limit,
+    public_url, spawn_server,
+};
+
+/// The endpoints of the MCP servers by name
+pub(crate) type Routes = Arc<RwLock<BTreeMap<String, String>>>;
+
+/// The router in front of the named MCP servers of a service
+#[derive(Debug)]
+pub struct ServiceRouter {
+    routes: Routes,
+    ct: CancellationToken,
+    endpoint: String,
+}
+
+impl ServiceRouter {
+    /// Serves the router on the reserved port.
+    pub fn serve_tcp(reservation: &PortReservation) -> std::io::Result<Self> {
+        let listener = match reservation.take_listener() {
+            Some(listener) => tokio::net::TcpListener::from_std(listener)?,
+            None => {
+                let listener = std::net::TcpListener::bind(reservation.addr())?;
+                listener.set_nonblocking(true)?;
+                tokio::net::TcpListener::from_std(listener)?
+            }
+        };
+        let router = Self::new(format!("http://{}", reservation.addr()));
+        let span = tracing::info_span!("service-router", bind_address = %reservation.addr());
+        match tls::OPERATOR_TLS.clone() {
+            Some(config) => {
+                let listener = TlsListener::new(listener, config)?;
+                spawn_server(listener, router.app(), router.ct.clone(), span, None);
+            }
+            None => spawn_server(listener, router.app(), router.ct.clone(), span, None),
+        }
+        Ok(router)
+    }
+
+    /// Serves the router on a Unix domain socket only reachable by the operator user.
+    pub fn serve_unix(path: PathBuf) -> std::io::Result<Self> {
+        let listener = bind_unix_socket(&path)?;
+        let router = Self::new(format!("unix://{}", path.display()));
+        let span = tracing::info_span!("service-router", bind_address = %path.display());
+        spawn_server(listener, router.app(), router.ct.clone(), span, Some(path));
+        Ok(router)
+    }
+
+    fn new(endpoint: String) -> Self {
+        Self {
+            routes: Default::default(),
+            ct: CancellationToken::new(),
+            endpoint,
+        }
+    }
+
+    fn app(&self) -> Router {
+        let mut config = SseServerConfig::new(SocketAddr::from(([127, 0, 0, 1], 0)));
+        config.ct = self.ct.clone();
+        let (gateway, gateway_router) = SseServer::new(config);
+        let routes = self.routes.clone();
+        gateway.with_session_service(move |headers| Gateway::new(routes.clone(), headers));
+        Router::new()
+            .fallback(forward)
+            .with_state(self.routes.clone())
+            .merge(gateway_router)
+    }
+
+    /// The endpoint the router is served on
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Replaces the endpoints of the MCP servers by name.
+    pub fn set_routes(&self, routes: BTreeMap<String, String>) {
+        *self.routes.write().unwrap_or_else(PoisonError::into_inner) = routes;
+    }
+
+    /// Stops routing to the named MCP server.
+    pub fn remove_route(&self, name: &str) {
+        self.routes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name);
+    }
+
+    /// Stops serving the router.
+    pub fn stop(&self) {
+        self.ct.cancel();
+    }
+}
+
+/// Forwards the request to the MCP server named by the first path segment.
+async fn forward(State(routes): State<Routes>, request: Request) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let Some((name, path)) = split_route(parts.uri.path()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let name = name.to_string();
+    let path_and_query = match parts.uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let endpoint = routes
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&name)
+        .cloned();
+    let Some(target) = endpoint.as_deref().and_then(ProbeTarget::from_endpoint) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // The MCP server would advertise the forwarded prefix before the route of the server
+    let prefix = public_url(&parts.headers, &format!("{}/{name}", *BASE_PATH));
+    parts.headers.remove("x-forwarded-prefix");
+    let upgrade = parts
+        .extensions
+        .remove::<OnUpgrade>()
+        .filter(|_| parts.headers.contains_key(header::UPGRADE));
+
+    let body = match axum::body::to_bytes(body, *MAX_BODY_SIZE).await {
+        Ok(body) => body,
+        Err(e) if exceeds_limit(&e) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let mut upstream = hyper::Request::new(Full::new(body));
+    *upstream.method_mut() = parts.method;
+    *upstream.uri_mut() = match path_and_query.parse() {
+        Ok(uri) => uri,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    *upstream.headers_mut() = parts.headers;
+    let headers = upstream.headers_mut();
+    if upgrade.is_none() {
+        headers.remove(header::CONNECTION);
+    }
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::HOST, HeaderValue::from_static("localhost"));
+
+    let mut response = match readiness::send(&target, upstream).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!(error = %e, server = %name, "failed to forward the request");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS
+        && let Some(upgrade) = upgrade
+    {
+        let upstream = hyper::upgrade::on(&mut response);
+        tokio::spawn(async move {
+            match tokio::try_join!(upgrade, upstream) {
+                Ok((client, server)) => {
+                    let _ = tokio::io::copy_bidirectional(
+                        &mut TokioIo::new(client),
+                        &mut TokioIo::new(server),
+                    )
+                    .await;
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, server = %name, "failed to upgrade the connection")
+                }
+            }
+        });
+        let (parts, _) = response.into_parts();
+        return Response::from_parts(parts, Body::empty());
+    }
+    let (mut parts, body) = response.into_parts();
+    let is_sse = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if !is_sse {
+        return Response::from_parts(parts, Body::new(body));
+    }
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let mut rewriter = EndpointRewriter::new(prefix, BASE_PATH.clone());
+    let stream = body
+        .into_data_stream()
+        .map(move |chunk| chunk.map(|chunk| rewriter.push(&chunk)));
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+/// Splits `/{name}/{path}` into the server name and the path on the server.
+fn split_route(path: &str) -> Option<(&str, &str)> {
+    let rest = path.strip_prefix('/')?;
+    let (name, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    (!name.is_empty()).then_some((name, path))
+}
+
+/// Prefixes the path announced by the `endpoint` events of an SSE stream with the route of
+/// the MCP server, as reached by the client.
+struct EndpointRewriter {
+    prefix: String,
+    /// The base path the MCP server advertises, already part of the prefix
+    base_path: String,
+    /// The incomplete line at the end of the last chunk
+    line: Vec<u8>,
+    /// Whether the lines belong to an `endpoint` event
+    in_endpoint: bool,
+}
+
+impl EndpointRewriter {
+    fn new(prefix: String, base_path: String) -> Self {
+        Self {
+            prefix,
+            base_path,
+            line: Vec::new(),
+            in_endpoint: false,
+        }
+    }
+
+    /// Rewrites the complete lines of the chunk, keeping the incomplete one for the next.
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        let mut out = Vec::with_capacity(chunk.len());
+        for &byte in chunk {
+            self.line.push(byte);
+            if byte == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                self.rewrite_line(&line, &mut out);
+            }
+        }
+        Bytes::from(out)
+    }
+
+    fn rewrite_line(&mut self, line: &[u8], out: &mut Vec<u8>) {
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim_end_matches(['\r', '\n']);
+        if text.is_empty() {
+            self.in_endpoint = false;
+        } else if let Some(event) = text.strip_prefix("event:") {
+            self.in_endpoint = event.trim() == "endpoint";
+        } else if self.in_endpoint
+            && let Some(data) = text.strip_prefix("data:")
+        {
+            // Relative paths already resolve under the prefix of the SSE path
+            let path = readiness::path_of(data.strip_prefix(' ').unwrap_or(data));
+            let path = path
+                .strip_prefix(self.base_path.as_str())
+                .filter(|rest| rest.starts_with('/'))
+                .unwrap_or(path);
+            if path.starts_with('/') {
+                out.extend_from_slice(format!("data: {}{path}\n", self.prefix).as_bytes());
+                return;
+            }
+        }
+        out.extend_from_slice(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{SseClient, initialize, serve_fake, temp_socket};
+
+    #[tokio::test]
+    async fn requests_are_routed_by_server_name() {
+        let socket = serve_fake("routed", |_| {}).await;
+        let router = ServiceRouter::serve_unix(temp_socket("router")).unwrap();
+        router.set_routes(BTreeMap::from([(
+            "fake".to_string(),
+            format!("unix://{}", socket.display()),
+        )]));
+        let target = ProbeTarget::from_endpoint(router.endpoint()).unwrap();
+
+        let mut client = SseClient::connect(&target, "/fake/sse").await;
+        assert!(
+            client.endpoint.starts_with("/fake/message?sessionId="),
+            "{}",
+            client.endpoint
+        );
+        let initialized = client.request(initialize(1)).await;
+        assert_eq!(initialized["result"]["serverInfo"]["name"], "fake");
+
+        let response = readiness::send(&target, readiness::get("/other/sse"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        router.remove_route("fake");
+        let (status, _) = client.post(&initialize(2)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        router.stop();
+    }
+
+    #[test]
+    fn endpoint_events_are_rewritten_across_chunks() {
+        let mut rewriter = EndpointRewriter::new("/fake".to_string(), String::new());
+        let chunks = [
+            "event: endpoint\ndata: /mess",
+            "age?sessionId=1\n\nevent: message\ndata: /message\n\n",
+            "event: endpoint\r\ndata: relative?sessionId=2\r\n\r\n",
+        ];
+        let rewritten: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| rewriter.push(chunk.as_bytes()))
+            .collect();
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            "event: endpoint\ndata: /fake/message?sessionId=1\n\n\
+             event: message\ndata: /message\n\n\
+             event: endpoint\r\ndata: relative?sessionId=2\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn base_path_is_moved_before_the_route() {
+        let mut rewriter = EndpointRewriter::new(
+            "https://example.com/proxy/mcp/fake".to_string(),
+            "/mcp".to_string(),
+        );
+        let rewritten = rewriter.push(
+            b"event: endpoint\ndata: http://localhost/mcp/message?sessionId=1\n\n\
+              event: endpoint\ndata: /mcpx/message?sessionId=2\n\n",
+        );
+        assert_eq!(
+            String::from_utf8(rewritten.to_vec()).unwrap(),
+            "event: endpoint\ndata: https://example.com/proxy/mcp/fake/message?sessionId=1\n\n\
+             event: endpoint\ndata: https://example.com/proxy/mcp/fake/mcpx/message?sessionId=2\n\n"
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! Secret environment variables encrypted to the operator's key.
+//!
+//! The request params are stored on-chain, so plaintext `env` values (API keys of GitHub,
+//! Slack, ... MCP servers) are public. Secret values are instead encrypted to the operator's
+//! ECDSA (secp256k1) key, the one registered on-chain in the operator preferences, and are only
+//! decrypted inside [`McpServerManager::start_servers`](crate::manager::McpServerManager::start_servers).
+//!
+//! # Ciphertext format
+//!
+//! A secret value is the hex encoding of `ephemeral_public_key || nonce || ciphertext`:
+//!
+//! 1. Generate an ephemeral secp256k1 key pair, `ephemeral_public_key` is its 33 bytes SEC1
+//!    compressed encoding.
+//! 2. Compute the ECDH shared secret with the operator public key and derive a 32 bytes key
+//!    using HKDF-SHA256 with the ephemeral public key as salt and [`SECRET_ENV_INFO`] as info.
+//! 3. Encrypt the value with ChaCha20-Poly1305 using a random 12 bytes `nonce` and
+//!    `{blueprint_id}:{service_id}:{name}` as associated data, where `name` is the environment
+//!    variable name, so a ciphertext can't be moved to another variable or another service.
+//!
+//! Values supplied on-chain and off-chain are bound the same way.
+//!
+//! # Off-chain secrets
+//!
+//! Secrets can also be supplied after the service request, without going on-chain, through the
+//! secrets endpoint enabled by the operator with `MCP_SECRETS_ADDR`:
+//!
+//! - `GET /v1/operator/public-key` returns the hex encoded operator public key.
+//! - `PUT /v1/services/{service_id}/secrets` stores encrypted values for the service, they are
+//!   applied the next time the MCP server starts. The body is
+//!   `{ "env": [["NAME", "<ciphertext>"]], "expiresAt": <unix ms>, "signature": "<hex>" }`
+//!   where `signature` is the sr25519 signature of the service owner over
+//!   `mcp-blueprint/secrets/v1:{service_id}:{expiresAt}:{NAME=ciphertext,...}`.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use blueprint_sdk::contexts::tangle::TangleClientContext;
+use blueprint_sdk::crypto::k256::K256Ecdsa;
+use blueprint_sdk::keystore::backends::Backend;
+use blueprint_sdk::runner::config::BlueprintEnvironment;
+use blueprint_sdk::tangle_subxt::tangle_testnet_runtime::api;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use futures::TryFutureExt;
+use k256::ecdsa::SigningKey;
+use sha2::Sha256;
+
+use crate::MyContext;
+use crate::error::Error;
+
+/// The HKDF info used to derive the encryption key of a secret value
+pub const SECRET_ENV_INFO: &[u8] = b"mcp-blueprint/secret-env/v1";
+
+/// The prefix of the message signed by the service owner when supplying secrets off-chain
+const SECRETS_SIGNING_PREFIX: &str = "mcp-blueprint/secrets/v1";
+
+/// How far in the future a signed secrets request may expire
+const MAX_SIGNATURE_VALIDITY: Duration = Duration::from_secs(60 * 60);
+
+const PUBLIC_KEY_LEN: usize = 33;
+const NONCE_LEN: usize = 12;
+
+/// The service a secret value is encrypted for
+#[derive(Debug, Clone, Copy)]
+pub struct SecretScope {
+    /// The blueprint the service is an instance of
+    pub blueprint_id: u64,
+    /// The service the MCP server is run for
+    pub service_id: u64,
+}
+
+impl SecretScope {
+    /// The scope of a service of this blueprint.
+    pub fn from_env(env: &BlueprintEnvironment, service_id: u64) -> Result<Self, Error> {
+        let settings = env
+            .protocol_settings
+            .tangle()
+            .map_err(|_| Error::MissingBlueprintId)?;
+        Ok(Self {
+            blueprint_id: settings.blueprint_id,
+            service_id,
+        })
+    }
+
+    /// The associated data of the secret value of the environment variable `name`.
+    fn associated_data(&self, name: &str) -> String {
+        format!("{}:{}:{name}", self.blueprint_id, self.service_id)
+    }
+}
+
+/// The operator's secp256k1 key, used to decrypt secret values
+pub struct OperatorKey(SigningKey);
+
+impl OperatorKey {
+    /// Loads the first local ECDSA key from the operator keystore.
+    pub fn from_env(env: &BlueprintEnvironment) -> Result<Self, Error> {
+        let keystore = env.keystore();
+        let public = keystore.first_local::<K256Ecdsa>()?;
+        let secret = keystore.get_secret::<K256Ecdsa>(&public)?;
+        Ok(Self(secret.0))
+    }
+
+    /// The hex encoded, SEC1 compressed public key the secrets are encrypted to.
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.0.verifying_key().to_sec1_bytes())
+    }
+
+    /// Decrypts the secret value of the environment variable `name` of the service of `scope`.
+    pub fn decrypt(
+        &self,
+        scope: SecretScope,
+        name: &str,
+        ciphertext: &str,
+    ) -> Result<String, Error> {
+        let invalid = || Error::InvalidSecret(name.to_string());
+        let bytes = hex::decode(ciphertext.trim_start_matches("0x")).map_err(|_| invalid())?;
+        if bytes.len() < PUBLIC_KEY_LEN + NONCE_LEN {
+            return Err(invalid());
+        }
+        let (ephemeral, rest) = bytes.split_at(PUBLIC_KEY_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let ephemeral = k256::PublicKey::from_sec1_bytes(ephemeral).map_err(|_| invalid())?;
+        let shared = k256::ecdh::diffie_hellman(self.0.as_nonzero_scalar(), ephemeral.as_affine());
+        let mut key = [0u8; 32];
+        shared
+            .extract::<Sha256>(Some(&bytes[..PUBLIC_KEY_LEN]))
+            .expand(SECRET_ENV_INFO, &mut key)
+            .map_err(|_| invalid())?;
+        let plaintext = ChaCha20Poly1305::new(&key.into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: scope.associated_data(name).as_bytes(),
+                },
+            )
+            .map_err(|_| invalid())?;
+        String::from_utf8(plaintext).map_err(|_| invalid())
+    }
+
+    /// Decrypts all the given secret environment variables.
+    pub fn decrypt_all(
+        &self,
+        scope: SecretScope,
+        secrets: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        secrets
+            .into_iter()
+            .map(|(name, ciphertext)| {
+                let value = self.decrypt(scope, &name, &ciphertext)?;
+                Ok((name, value))
+            })
+            .collect()
+    }
+}
+
+/// Encrypted secrets supplied off-chain, by service id
+///
+/// Values stay encrypted until the MCP server is started.
+pub type SecretStore = Arc<tokio::sync::RwLock<BTreeMap<u64, BTreeMap<String, String>>>>;
+
+/// Spawns the secrets endpoint if the operator enabled it with `MCP_SECRETS_ADDR`.
+pub async fn spawn_secrets_endpoint(ctx: &MyContext) -> Result<(), Error> {
+    let Ok(bind) = std::env::var("MCP_SECRETS_ADDR") else {
+        return Ok(());
+    };
+    let bind: SocketAddr = bind.parse()?;
+    // Fail early if the operator has no key to decrypt the secrets with
+    OperatorKey::from_env(&ctx.env)?;
+    let router = Router::new()
+        .route("/v1/operator/public-key", get(public_key_handler))
+        .route(
+            "/v1/services/{service_id}/secrets",
+            put(put_secrets_handler),
+        )
+        .with_state(ctx.clone());
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    tracing::info!(%bind, "secrets endpoint listening");
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            tracing::error!(error = %e, "secrets endpoint shutdown with error");
+        }
+    });
+    Ok(())
+}
+
+async fn public_key_handler(State(ctx): State<MyContext>) -> Result<String, StatusCode> {
+    OperatorKey::from_env(&ctx.env)
+        .map(|key| key.public_key_hex())
+        .map_err(|e| {
+            tracing::error!(error = %e, "failed to load the operator key");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PutSecretsRequest {
+    env: Vec<(String, String)>,
+    expires_at: u64,
+    signature: String,
+}
+
+async fn put_secrets_handler(
+    State(ctx): State<MyContext>,
+    Path(service_id): Path<u64>,
+    Json(request): Json<PutSecretsRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let max_expiry = now + MAX_SIGNATURE_VALIDITY.as_millis() as u64;
+    if request.expires_at < now || request.expires_at > max_expiry {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "expiresAt must be within the next hour".to_string(),
+        ));
+    }
+
+    let owner = service_owner(&ctx, service_id)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    let message = signing_message(service_id, request.expires_at, &request.env);
+    verify_owner_signature(&owner, message.as_bytes(), &request.signature)
+        .map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+
+    // Reject values the operator can't decrypt now rather than when starting the server
+    let internal = |e: Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let key = OperatorKey::from_env(&ctx.env).map_err(internal)?;
+    let scope = SecretScope::from_env(&ctx.env, service_id).map_err(internal)?;
+    for (name, ciphertext) in &request.env {
+        key.decrypt(scope, name, ciphertext)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
+
+    let names: Vec<&String> = request.env.iter().map(|(name, _)| name).collect();
+    tracing::info!(%service_id, ?names, "stored off-chain secrets");
+    ctx.secrets
+        .write()
+        .await
+        .entry(service_id)
+        .or_default()
+        .extend(request.env);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The message the service owner signs to supply secrets off-chain.
+fn signing_message(service_id: u64, expires_at: u64, env: &[(String, String)]) -> String {
+    let env = env
+        .iter()
+        .map(|(name, ciphertext)| format!("{name}={ciphertext}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("{SECRETS_SIGNING_PREFIX}:{service_id}:{expires_at}:{env}")
+}
+
+fn verify_owner_signature(owner: &[u8; 32], message: &[u8], signature: &str) -> Result<(), String> {
+    let signature = hex::decode(signature.trim_start_matches("0x"))
+        .map_err(|_| "signature is not hex encoded".to_string())?;
+    let signature = schnorrkel::Signature::from_bytes(&signature)
+        .map_err(|e| format!("invalid signature: {e}"))?;
+    let public = schnorrkel::PublicKey::from_bytes(owner)
+        .map_err(|e| format!("invalid owner public key: {e}"))?;
+    public
+        .verify_simple(b"substrate", message, &signature)
+        .map_err(|_| "signature does not match the service owner".to_string())
+}
+
+/// Fetches the owner account of the service instance at the latest block.
+async fn service_owner(ctx: &MyContext, service_id: u64) -> Result<[u8; 32], Error> {
+    let client = ctx
+        .env
+        .tangle_client()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?;
+    let instance = client
+        .storage()
+        .at_latest()
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?
+        .fetch(&api::storage().services().instances(service_id))
+        .map_err(Into::into)
+        .map_err(Error::Sdk)
+        .await?
+        .ok_or(Error::ServiceNotFound(service_id))?;
+    Ok(instance.owner.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCOPE: SecretScope = SecretScope {
+        blueprint_id: 1,
+        service_id: 7,
+    };
+
+    fn operator_key() -> OperatorKey {
+        OperatorKey(SigningKey::from_slice(&[1; 32]).unwrap())
+    }
+
+    /// Encrypts `value` to the operator key the way the service owner does.
+    fn encrypt(scope: SecretScope, name: &str, value: &str) -> String {
+        let operator = k256::PublicKey::from(operator_key().0.verifying_key());
+        let ephemeral = k256::SecretKey::from_slice(&[2; 32]).unwrap();
+        let ephemeral_public = ephemeral.public_key().to_sec1_bytes();
+        let shared =
+            k256::ecdh::diffie_hellman(ephemeral.to_nonzero_scalar(), operator.as_affine());
+        let mut key = [0u8; 32];
+        shared
+            .extract::<Sha256>(Some(&ephemeral_public))
+            .expand(SECRET_ENV_INFO, &mut key)
+            .unwrap();
+        let nonce = [3; NONCE_LEN];
+        let ciphertext = ChaCha20Poly1305::new(&key.into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: value.as_bytes(),
+                    aad: scope.associated_data(name).as_bytes(),
+                },
+            )
+            .unwrap();
+        hex::encode([&ephemeral_public[..], &nonce, &ciphertext].concat())
+    }
+
+    #[test]
+    fn decrypts_the_secret_of_its_service() {
+        let ciphertext = encrypt(SCOPE, "API_TOKEN", "ghp_secret");
+        let value = operator_key().decrypt(SCOPE, "API_TOKEN", &ciphertext);
+        assert_eq!(value.unwrap(), "ghp_secret");
+    }
+
+    #[test]
+    fn rejects_the_secret_of_another_variable() {
+        let ciphertext = encrypt(SCOPE, "API_TOKEN", "ghp_secret");
+        assert!(operator_key().decrypt(SCOPE, "OTHER", &ciphertext).is_err());
+    }
+
+    #[test]
+    fn rejects_the_secret_of_another_service() {
+        let ciphertext = encrypt(SCOPE, "API_TOKEN", "ghp_secret");
+        let other_service = SecretScope {
+            service_id: 8,
+            ..SCOPE
+        };
+        let other_blueprint = SecretScope {
+            blueprint_id: 2,
+            ..SCOPE
+        };
+        let key = operator_key();
+        assert!(
+            key.decrypt(other_service, "API_TOKEN", &ciphertext)
+                .is_err()
+        );
+        assert!(
+            key.decrypt(other_blueprint, "API_TOKEN", &ciphertext)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn verifies_the_owner_signature() {
+        let owner = schnorrkel::MiniSecretKey::from_bytes(&[4; 32])
+            .unwrap()
+            .expand_to_keypair(schnorrkel::ExpansionMode::Ed25519);
+        let env = [("API_TOKEN".to_string(), "ff".to_string())];
+        let message = signing_message(7, 1000, &env);
+        let signature = hex::encode(
+            owner
+                .sign_simple(b"substrate", message.as_bytes())
+                .to_bytes(),
+        );
+        let public = owner.public.to_bytes();
+        assert!(verify_owner_signature(&public, message.as_bytes(), &signature).is_ok());
+        let other = signing_message(8, 1000, &env);
+        assert!(verify_owner_signature(&public, other.as_bytes(), &signature).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Helpers shared by the tests: an in-process MCP server to forward the sessions to, and a
+//! client of the SSE endpoints.
+
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::{Response, StatusCode};
+use rmcp::model::{ClientJsonRpcMessage, ServerJsonRpcMessage};
+use serde_json::{Value, json};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::{CancellationToken, DropGuard, PollSender};
+
+use crate::readiness::{self, ProbeTarget, SseReader};
+use crate::transport::{SseServer, SseServerConfig};
+
+/// The transport of a session of the [`fake_mcp_server`]
+pub(crate) struct FakeTransport {
+    stream: ReceiverStream<ServerJsonRpcMessage>,
+    sink: PollSender<ClientJsonRpcMessage>,
+    _guard: Option<DropGuard>,
+}
+
+impl Sink<ClientJsonRpcMessage> for FakeTransport {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sink.poll_ready_unpin(cx).map_err(io::Error::other)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: ClientJsonRpcMessage) -> io::Result<()> {
+        self.sink.start_send_unpin(item).map_err(io::Error::other)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sink.poll_flush_unpin(cx).map_err(io::Error::other)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sink.poll_close_unpin(cx).map_err(io::Error::other)
+    }
+}
+
+impl Stream for FakeTransport {
+    type Item = ServerJsonRpcMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+/// Starts a session of an in-process MCP server, as spawned by the factory of
+/// [`SseServer::forward`](crate::transport::SseServer::forward).
+///
+/// It answers `initialize`, lists the `echo`, `crash` and `hang` tools, answers `echo` with
+/// its arguments, exits on `crash` and never answers `hang`.
+pub(crate) fn fake_mcp_server() -> FakeTransport {
+    let (client_tx, mut client_rx) = tokio::sync::mpsc::channel::<ClientJsonRpcMessage>(16);
+    let (server_tx, server_rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        while let Some(message) = client_rx.recv().await {
+            let message = serde_json::to_value(&message).unwrap();
+            let id = &message["id"];
+            let result = match message["method"].as_str() {
+                Some("initialize") => json!({
+                    "protocolVersion": "2024-11-05",
+                    "capabilities": { "tools": {} },
+                    "serverInfo": { "name": "fake", "version": "1.0.0" },
+                }),
+                Some("tools/list") => {
+                    let tools: Vec<Value> = ["echo", "crash", "hang"]
+                        .iter()
+                        .map(|name| json!({ "name": name, "description": "", "inputSchema": { "type": "object" } }))
+                        .collect();
+                    json!({ "tools": tools })
+                }
+                Some("tools/call") => match message["params"]["name"].as_str() {
+                    Some("echo") => json!({
+                        "content": [{
+                            "type": "text",
+                            "text": message["params"]["arguments"].to_string(),
+                        }],
+                        "isError": false,
+                    }),
+                    Some("crash") => return,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let response = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+            let response = serde_json::from_value(response).unwrap();
+            if server_tx.send(response).await.is_err() {
+                return;
+            }
+        }
+    });
+    FakeTransport {
+        stream: ReceiverStream::new(server_rx),
+        sink: PollSender::new(client_tx),
+        _guard: None,
+    }
+}
+
+/// Starts a session of the [`fake_mcp_server`] cancelling `closed` once its transport is
+/// dropped, as the child process of a STDIO server is killed then.
+pub(crate) fn tracked_fake_mcp_server(closed: CancellationToken) -> FakeTransport {
+    FakeTransport {
+        _guard: Some(closed.drop_guard()),
+        ..fake_mcp_server()
+    }
+}
+
+/// The factory of [`SseServer::forward`](crate::transport::SseServer::forward) spawning the
+/// [`fake_mcp_server`].
+pub(crate) fn fake_factory() -> futures::future::Ready<io::Result<FakeTransport>> {
+    futures::future::ready(Ok(fake_mcp_server()))
+}
+
+/// Serves the [`fake_mcp_server`] on a new Unix domain socket.
+pub(crate) async fn serve_fake(
+    name: &str,
+    configure: impl FnOnce(&mut SseServerConfig),
+) -> PathBuf {
+    let socket = temp_socket(name);
+    let mut config = SseServerConfig {
+        unix_socket: Some(socket.clone()),
+        tls: None,
+        ..SseServerConfig::new(([127, 0, 0, 1], 0).into())
+    };
+    configure(&mut config);
+    SseServer::serve_with_config(config)
+        .await
+        .unwrap()
+        .forward(fake_factory);
+    socket
+}
+
+/// A Unix domain socket path in a new temporary directory.
+pub(crate) fn temp_socket(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "mcp-blueprint-test-{name}-{}",
+        uuid::Uuid::new_v4()
+    ));
+    dir.join("mcp.sock")
+}
+
+/// The `initialize` request of a client.
+pub(crate) fn initialize(id: u64) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": "initialize",
+        "params": {
+            "protocolVersion": "2024-11-05",
+            "capabilities": {},
+            "clientInfo": { "name": "test", "version": "1.0.0" },
+        },
+    })
+}
+
+/// A `tools/call` request of a client.
+pub(crate) fn call_tool(id: u64, name: &str, arguments: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": "tools/call",
+        "params": { "name": name, "arguments": arguments },
+    })
+}
+
+/// An open SSE session of a client
+pub(crate) struct SseClient {
+    target: ProbeTarget,
+    events: SseReader,
+    /// The message endpoint advertised by the server, with the session id
+    pub endpoint: String,
+}
+
+impl SseClient {
+    /// Opens a session on the SSE endpoint at `path`.
+    pub async fn connect(target: &ProbeTarget, path: &str) -> Self {
+        let response = readiness::send(target, readiness::get(path)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        Self::from_response(target, response).await
+    }
+
+    /// Reads the `endpoint` event of the SSE stream of a new session.
+    pub async fn from_response(target: &ProbeTarget, response: Response<Incoming>) -> Self {
+        let mut events = SseReader::new(response.into_body());
+        let (event, endpoint) = events.next().await.unwrap();
+        assert_eq!(event, "endpoint");
+        Self {
+            target: target.clone(),
+            events,
+            endpoint,
+        }
+    }
+
+    /// Posts a message to the advertised endpoint, returning the status and the body.
+    pub async fn post(&self, message: &Value) -> (StatusCode, String) {
+        let path = readiness::path_of(&self.endpoint);
+        let request = readiness::post(path, message.to_string().into());
+        let response = readiness::send(&self.target, request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// The next message sent on the SSE stream.
+    pub async fn next_message(&mut self) -> Value {
+        let next = async {
+            loop {
+                let (event, data) = self.events.next().await.unwrap();
+                if event == "message" {
+                    return serde_json::from_str(&data).unwrap();
+                }
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), next)
+            .await
+            .expect("no message within 5s")
+    }
+
+    /// Posts a request and waits for its response on the SSE stream.
+    pub async fn request(&mut self, message: Value) -> Value {
+        let (status, body) = self.post(&message).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        loop {
+            let response = self.next_message().await;
+            if response["id"] == message["id"] {
+                return response;
+            }
+        }
+    }
+}
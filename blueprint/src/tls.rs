@@ -0,0 +1,498 @@
+//! TLS termination of the SSE servers.
+//!
+//! The endpoints are plain HTTP on the loopback interface, meant to be reached through the
+//! auth proxy of the bridge. Operators reaching them otherwise can have them served over TLS
+//! with `MCP_TLS_CERT` and `MCP_TLS_KEY`, the paths of a PEM certificate chain and of its
+//! private key. The files are checked every [`RELOAD_INTERVAL`] and reloaded when they
+//! change, so a renewed certificate is used for the new connections without a restart.
+//!
+//! With `MCP_TLS_CLIENT_CA`, the path of PEM CA certificates, the clients must present a
+//! certificate issued by one of them (mutual TLS), authenticating them without the auth proxy.
+//!
+//! Plain connections are closed. The readiness probe and the router of the named servers reach
+//! the MCP servers on a Unix domain socket instead, see
+//! [`SseServerConfig::internal_socket`](crate::transport::SseServerConfig::internal_socket).
+//! Since every endpoint is bound to the loopback interface, the operator may let loopback peers,
+//! like the auth proxy of the bridge, speak plain HTTP with `MCP_TLS_PLAIN_LOOPBACK=true`; never
+//! with a client CA, as it would bypass the client certificates.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+
+/// How often the certificate files are checked for changes
+#[cfg(not(test))]
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+#[cfg(test)]
+pub const RELOAD_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of handshaken connections waiting to be served
+const ACCEPT_BACKLOG: usize = 64;
+
+/// The content type of the first record sent by a TLS client
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// The TLS configuration set by the operator. Half of it fails to load rather than serving
+/// plain HTTP.
+pub(crate) static OPERATOR_TLS: LazyLock<Option<TlsConfig>> = LazyLock::new(|| {
+    let path = |name| {
+        std::env::var_os(name)
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from)
+    };
+    let (cert_path, key_path) = (path("MCP_TLS_CERT"), path("MCP_TLS_KEY"));
+    if cert_path.is_none() && key_path.is_none() {
+        return None;
+    }
+    if cert_path.is_none() || key_path.is_none() {
+        tracing::error!("MCP_TLS_CERT and MCP_TLS_KEY must be set together");
+    }
+    let client_ca_path = path("MCP_TLS_CLIENT_CA");
+    let plain_loopback =
+        std::env::var("MCP_TLS_PLAIN_LOOPBACK").is_ok_and(|s| matches!(s.trim(), "1" | "true"));
+    if plain_loopback && client_ca_path.is_some() {
+        tracing::error!("Ignoring MCP_TLS_PLAIN_LOOPBACK, the clients must present a certificate");
+    }
+    Some(TlsConfig {
+        cert_path: cert_path.unwrap_or_default(),
+        key_path: key_path.unwrap_or_default(),
+        plain_loopback: plain_loopback && client_ca_path.is_none(),
+        client_ca_path,
+    })
+});
+
+/// Where the certificates of an SSE server served over TLS are read from
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// The PEM certificate chain of the server, its own certificate first
+    pub cert_path: PathBuf,
+    /// The PEM private key of the certificate
+    pub key_path: PathBuf,
+    /// The PEM CA certificates the clients must present a certificate of, if any
+    pub client_ca_path: Option<PathBuf>,
+    /// Whether loopback peers may speak plain HTTP, never with a client CA
+    pub plain_loopback: bool,
+}
+
+impl TlsConfig {
+    fn load(&self) -> io::Result<Arc<ServerConfig>> {
+        let certs = read_certs(&self.cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| invalid_file(&self.key_path, e))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots.add(cert).map_err(|e| invalid_file(path, e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .map_err(|e| invalid_file(path, e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid_file(&self.cert_path, e))?;
+        // WebSocket upgrades need HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    /// The modification times of the files, to reload them when they change.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            Some(&self.cert_path),
+            Some(&self.key_path),
+            self.client_ca_path.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+    }
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| invalid_file(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid_file(path, "no certificate found"));
+    }
+    Ok(certs)
+}
+
+fn invalid_file(path: &Path, error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid TLS file {}: {error}", path.display()),
+    )
+}
+
+/// A TCP listener terminating TLS on the accepted connections
+#[derive(Debug)]
+pub struct TlsListener {
+    connections: mpsc::Receiver<(MaybeTlsStream, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Accepts the connections of `listener` with the certificates of `config`, failing if
+    /// they can't be loaded.
+    pub fn new(listener: TcpListener, config: TlsConfig) -> io::Result<Self> {
+        let acceptor = TlsAcceptor::from(config.load()?);
+        let local_addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(accept_connections(listener, config, acceptor, tx));
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = MaybeTlsStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Accepts the connections until the listener is dropped, reloading the certificates when
+/// their files change.
+async fn accept_connections(
+    listener: TcpListener,
+    config: TlsConfig,
+    mut acceptor: TlsAcceptor,
+    tx: mpsc::Sender<(MaybeTlsStream, SocketAddr)>,
+) {
+    let mut modified = config.modified();
+    let mut reload = tokio::time::interval(RELOAD_INTERVAL);
+    reload.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            () = tx.closed() => return,
+            _ = reload.tick() => {
+                let current = config.modified();
+                if current == modified {
+                    continue;
+                }
+                // A half-written pair fails to load and is retried once the other file changes
+                modified = current;
+                match config.load() {
+                    Ok(server_config) => {
+                        acceptor = TlsAcceptor::from(server_config);
+                        tracing::info!("reloaded the TLS certificates");
+                    }
+                    Err(e) => {
+                        tracing::warn!(error = %e, "failed to reload the TLS certificates, keeping the previous ones");
+                    }
+                }
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    tokio::spawn(handshake(
+                        stream,
+                        peer,
+                        acceptor.clone(),
+                        config.plain_loopback && config.client_ca_path.is_none(),
+                        tx.clone(),
+                    ));
+                }
+                Err(e) => {
+                    // Out of file descriptors, most likely; give the open connections time
+                    tracing::error!(error = %e, "failed to accept a connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            },
+        }
+    }
+}
+
+async fn handshake(
+    stream: TcpStream,
+    peer: SocketAddr,
+    acceptor: TlsAcceptor,
+    plain_loopback: bool,
+    tx: mpsc::Sender<(MaybeTlsStream, SocketAddr)>,
+) {
+    let accepted = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let mut first = [0; 1];
+        stream.peek(&mut first).await?;
+        if first[0] == TLS_HANDSHAKE_RECORD {
+            let stream = acceptor.accept(stream).await?;
+            Ok(MaybeTlsStream::Tls(Box::new(stream)))
+        } else if plain_loopback && peer.ip().to_canonical().is_loopback() {
+            Ok(MaybeTlsStream::Plain(stream))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "plain HTTP without TLS",
+            ))
+        }
+    })
+    .await;
+    match accepted {
+        Ok(Ok(stream)) => {
+            let _ = tx.send((stream, peer)).await;
+        }
+        Ok(Err(e)) => tracing::debug!(%peer, error = %e, "TLS handshake failed"),
+        Err(_) => tracing::debug!(%peer, "TLS handshake timed out"),
+    }
+}
+
+/// A connection over TLS, or in plain text from a loopback peer when allowed
+#[derive(Debug)]
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use hyper_util::rt::TokioIo;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        KeyUsagePurpose,
+    };
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    use super::*;
+    use crate::ports::PortAllocator;
+    use crate::readiness::{self, ProbeTarget};
+    use crate::test_support::{SseClient, fake_factory, temp_socket};
+    use crate::transport::{SseServer, SseServerConfig};
+
+    struct Ca {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new(name: &str) -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.key_usages = vec![
+                KeyUsagePurpose::KeyCertSign,
+                KeyUsagePurpose::DigitalSignature,
+            ];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// Issues a certificate for `name`, returning it and its key in PEM.
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (String, String) {
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn client(&self, identity: Option<(String, String)>) -> TlsConnector {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.cert.der().clone()).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let builder = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match identity {
+                Some((cert, key)) => {
+                    let certs = CertificateDer::pem_slice_iter(cert.as_bytes())
+                        .collect::<Result<_, _>>()
+                        .unwrap();
+                    let key = PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap();
+                    builder.with_client_auth_cert(certs, key).unwrap()
+                }
+                None => builder.with_no_client_auth(),
+            };
+            TlsConnector::from(Arc::new(config))
+        }
+    }
+
+    /// Serves the fake MCP server over TLS, returning its address and its internal socket.
+    async fn serve(config: TlsConfig) -> (SocketAddr, PathBuf) {
+        let reservation = PortAllocator::default().reserve("tls").unwrap();
+        let internal_socket = temp_socket("tls-internal");
+        let config = SseServerConfig {
+            reservation: Some(reservation.clone()),
+            tls: Some(config),
+            internal_socket: Some(internal_socket.clone()),
+            ..SseServerConfig::new(reservation.addr())
+        };
+        SseServer::serve_with_config(config)
+            .await
+            .unwrap()
+            .forward(fake_factory);
+        (reservation.addr(), internal_socket)
+    }
+
+    /// Opens an SSE stream, over TLS with `tls`, returning the `endpoint` event.
+    async fn open_sse(addr: SocketAddr, tls: Option<TlsConnector>) -> io::Result<String> {
+        let tcp = TcpStream::connect(addr).await?;
+        let response = match tls {
+            Some(tls) => {
+                let name = ServerName::try_from("localhost").unwrap();
+                let stream = tls.connect(name, tcp).await?;
+                send(TokioIo::new(stream)).await
+            }
+            None => send(TokioIo::new(tcp)).await,
+        };
+        let mut body = response.map_err(io::Error::other)?.into_body();
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?
+            .map_err(io::Error::other)?;
+        let data = frame.into_data().unwrap_or_default();
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// The certificate the server presents to a new connection.
+    async fn served_certificate(addr: SocketAddr, tls: TlsConnector) -> CertificateDer<'static> {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = tls.connect(name, tcp).await.unwrap();
+        stream.get_ref().1.peer_certificates().unwrap()[0]
+            .clone()
+            .into_owned()
+    }
+
+    async fn send<T>(io: TokioIo<T>) -> hyper::Result<hyper::Response<hyper::body::Incoming>>
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(io).await?;
+        tokio::spawn(connection);
+        sender.send_request(readiness::get("/sse")).await
+    }
+
+    #[tokio::test]
+    async fn clients_must_speak_tls_and_present_a_certificate() {
+        let dir = temp_socket("tls").with_file_name("certs");
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = Ca::new("ca");
+        let (cert, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        std::fs::write(dir.join("cert.pem"), &cert).unwrap();
+        std::fs::write(dir.join("key.pem"), key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.cert.pem()).unwrap();
+        let config = |client_ca_path: Option<PathBuf>, plain_loopback| TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            client_ca_path,
+            plain_loopback,
+        };
+
+        let (addr, internal_socket) = serve(config(None, false)).await;
+        let endpoint = open_sse(addr, Some(ca.client(None))).await.unwrap();
+        assert!(endpoint.contains("event: endpoint"), "{endpoint}");
+        assert!(open_sse(addr, None).await.is_err());
+        // The operator reaches the server on its internal socket
+        SseClient::connect(&ProbeTarget::Unix(internal_socket), "/sse").await;
+
+        // A renewed certificate is served to the new connections
+        let der = |pem: &str| CertificateDer::from_pem_slice(pem.as_bytes()).unwrap();
+        assert_eq!(served_certificate(addr, ca.client(None)).await, der(&cert));
+        let (renewed, key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        std::fs::write(dir.join("key.pem"), key).unwrap();
+        std::fs::write(dir.join("cert.pem"), &renewed).unwrap();
+        let reloaded = tokio::time::timeout(Duration::from_secs(5), async {
+            while served_certificate(addr, ca.client(None)).await != der(&renewed) {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+            }
+        })
+        .await;
+        assert!(reloaded.is_ok(), "the renewed certificate wasn't reloaded");
+
+        let (addr, _) = serve(config(None, true)).await;
+        let endpoint = open_sse(addr, None).await.unwrap();
+        assert!(endpoint.contains("event: endpoint"), "{endpoint}");
+
+        // Mutual TLS, never bypassed by plain connections
+        let (addr, _) = serve(config(Some(dir.join("ca.pem")), true)).await;
+        assert!(open_sse(addr, None).await.is_err());
+        assert!(open_sse(addr, Some(ca.client(None))).await.is_err());
+        let other = Ca::new("other").issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        assert!(open_sse(addr, Some(ca.client(Some(other)))).await.is_err());
+        let client = ca.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        let endpoint = open_sse(addr, Some(ca.client(Some(client)))).await.unwrap();
+        assert!(endpoint.contains("event: endpoint"), "{endpoint}");
+    }
+}
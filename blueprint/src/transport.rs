@@ -5,14 +5,30 @@
 //! See: https://github.com/supercorp-ai/supergateway/blob/bcbf9b0bc8eb7505fce38ff57862414ee3ba8be2/src/gateways/stdioToSse.ts
 //! And: https://github.com/modelcontextprotocol/rust-sdk/blob/01eedb77704fd32d66dea455431b29a03923bdf4/crates/rmcp/src/transport/sse_server.rs
 
-use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LazyLock, Mutex, PoisonError,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
 use axum::{
     Json, Router,
-    extract::{Query, State},
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{
+        NestedPath, Query, Request, State,
+        rejection::{NestedPathRejection, QueryRejection},
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
     response::{
-        Response,
+        IntoResponse, Response,
         sse::{Event, KeepAlive, Sse},
     },
     routing::{get, post},
@@ -27,12 +43,20 @@ use tracing::Instrument;
 
 use rmcp::{
     RoleServer, Service,
-    model::ClientJsonRpcMessage,
+    model::{
+        ClientJsonRpcMessage, ErrorCode, ErrorData, JsonRpcError, JsonRpcMessage, JsonRpcRequest,
+        JsonRpcResponse, JsonRpcVersion2_0, RequestId, ServerJsonRpcMessage,
+    },
     service::{RxJsonRpcMessage, TxJsonRpcMessage},
 };
 
-type TxStore =
-    Arc<tokio::sync::RwLock<HashMap<SessionId, tokio::sync::mpsc::Sender<ClientJsonRpcMessage>>>>;
+use crate::audit::{ServiceAudit, SessionAudit};
+use crate::cors::{self, CorsPolicy};
+use crate::policy::{McpServerPolicy, PolicyFilter};
+use crate::ports::PortReservation;
+use crate::tls::{self, TlsConfig, TlsListener};
+
+type TxStore = Arc<tokio::sync::RwLock<HashMap<SessionId, Arc<SseSession>>>>;
 #[allow(dead_code)]
 pub type TransportReceiver = ReceiverStream<RxJsonRpcMessage<RoleServer>>;
 
@@ -42,35 +66,623 @@ pub fn session_id() -> SessionId {
     uuid::Uuid::new_v4().to_string().into()
 }
 
+/// The header a readiness probe sends the probe token of the server in
+pub const PROBE_TOKEN_HEADER: &str = "x-mcp-probe-token";
+
 pub const DEFAULT_AUTO_PING_INTERVAL: Duration = Duration::from_secs(15);
+/// The number of events kept per SSE session to be replayed to a reconnecting client
+pub const DEFAULT_REPLAY_BUFFER: usize = 64;
+/// How long an SSE session outlives its stream, waiting for the client to reconnect
+pub const DEFAULT_RECONNECT_GRACE: Duration = Duration::from_secs(30);
+/// How long a session may go without a message before it's terminated
+pub const DEFAULT_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// The capacity of the message queues of a session by default
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+/// How many new sessions may wait for their upstream transport by default
+pub const DEFAULT_MAX_PENDING_SESSIONS: usize = 32;
+/// How long a message may wait for room in the queue of its session by default
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// When a client rejected because of a full queue should retry, in seconds
+const RETRY_AFTER_SECS: &str = "1";
+/// The maximum size of a message posted by a client by default
+pub const DEFAULT_MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+/// The JSON-RPC error code of the errors of the transport, from the range reserved for servers
+const TRANSPORT_ERROR: ErrorCode = ErrorCode(-32000);
+/// The prefix of the ids the requests of a batch are forwarded with, refused in the ids of the
+/// clients
+const BATCH_ID_PREFIX: &str = "mcp-gateway-batch:";
+
+/// Parses a setting of the operator from the environment.
+fn env_setting<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|s| s.trim().parse().ok())
+}
+
+/// Set by the operator with `MCP_SESSION_IDLE_TIMEOUT` in seconds, `0` disables it
+static SESSION_IDLE_TIMEOUT: LazyLock<Option<Duration>> = LazyLock::new(|| {
+    let timeout = env_setting("MCP_SESSION_IDLE_TIMEOUT")
+        .map_or(DEFAULT_SESSION_IDLE_TIMEOUT, Duration::from_secs);
+    (!timeout.is_zero()).then_some(timeout)
+});
+
+/// Set by the operator with `MCP_SESSION_MAX_LIFETIME` in seconds, unlimited when unset or `0`
+static MAX_SESSION_LIFETIME: LazyLock<Option<Duration>> = LazyLock::new(|| {
+    env_setting("MCP_SESSION_MAX_LIFETIME")
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+});
+
+/// Set by the operator with `MCP_QUEUE_CAPACITY`
+static QUEUE_CAPACITY: LazyLock<usize> = LazyLock::new(|| {
+    env_setting("MCP_QUEUE_CAPACITY")
+        .filter(|&capacity| capacity > 0)
+        .unwrap_or(DEFAULT_QUEUE_CAPACITY)
+});
+
+/// Set by the operator with `MCP_MAX_PENDING_SESSIONS`
+static MAX_PENDING_SESSIONS: LazyLock<usize> = LazyLock::new(|| {
+    env_setting("MCP_MAX_PENDING_SESSIONS")
+        .filter(|&max| max > 0)
+        .unwrap_or(DEFAULT_MAX_PENDING_SESSIONS)
+});
+
+/// Set by the operator with `MCP_MAX_BODY_SIZE` in bytes
+pub(crate) static MAX_BODY_SIZE: LazyLock<usize> = LazyLock::new(|| {
+    env_setting("MCP_MAX_BODY_SIZE")
+        .filter(|&size| size > 0)
+        .unwrap_or(DEFAULT_MAX_BODY_SIZE)
+});
+
+/// Set by the operator with `MCP_BASE_PATH`, without a trailing `/`, e.g. `/mcp` when the
+/// proxy in front strips it from the requests
+pub(crate) static BASE_PATH: LazyLock<String> = LazyLock::new(|| {
+    let path: String = env_setting("MCP_BASE_PATH").unwrap_or_default();
+    let path = path.trim_end_matches('/');
+    if !path.is_empty() && !is_path(path) {
+        tracing::warn!(%path, "Ignoring MCP_BASE_PATH, it must be an absolute path");
+        return String::new();
+    }
+    path.to_string()
+});
+
+/// Set by the operator with `MCP_SEND_TIMEOUT` in seconds
+static SEND_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
+    env_setting("MCP_SEND_TIMEOUT").map_or(DEFAULT_SEND_TIMEOUT, Duration::from_secs)
+});
+
+/// The depths of the queues of an SSE server, shared with the operator to tune their sizes
+#[derive(Debug, Clone, Default)]
+pub struct QueueMetrics(Arc<QueueCounters>);
+
+#[derive(Debug, Default)]
+struct QueueCounters {
+    sessions: AtomicUsize,
+    pending_sessions: AtomicUsize,
+    client_messages: AtomicUsize,
+    server_messages: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// A snapshot of the [`QueueMetrics`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct QueueDepths {
+    /// The open sessions
+    pub sessions: usize,
+    /// The new sessions waiting for their upstream transport
+    pub pending_sessions: usize,
+    /// The messages of the clients waiting to be forwarded upstream
+    pub client_messages: usize,
+    /// The messages waiting to be sent to the clients
+    pub server_messages: usize,
+    /// The messages rejected with `503 Service Unavailable`, or a busy error over a WebSocket,
+    /// because of a full queue
+    pub rejected: u64,
+}
+
+impl QueueMetrics {
+    pub fn snapshot(&self) -> QueueDepths {
+        QueueDepths {
+            sessions: self.0.sessions.load(Ordering::Relaxed),
+            pending_sessions: self.0.pending_sessions.load(Ordering::Relaxed),
+            client_messages: self.0.client_messages.load(Ordering::Relaxed),
+            server_messages: self.0.server_messages.load(Ordering::Relaxed),
+            rejected: self.0.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// `503 Service Unavailable`, the client retrying once there's room in the queue.
+    fn unavailable(&self, id: Option<&RequestId>) -> Response {
+        self.0.rejected.fetch_add(1, Ordering::Relaxed);
+        let mut response = error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            id,
+            TRANSPORT_ERROR,
+            "the server is busy, retry later",
+        );
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            header::HeaderValue::from_static(RETRY_AFTER_SECS),
+        );
+        response
+    }
+}
+
+/// A JSON-RPC error object, its `id` being `null` when the request couldn't be identified
+fn error_object(id: Option<&RequestId>, code: ErrorCode, message: &str) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code.0, "message": message },
+    })
+}
+
+/// An HTTP error carrying a JSON-RPC error object.
+fn error_response(
+    status: StatusCode,
+    id: Option<&RequestId>,
+    code: ErrorCode,
+    message: &str,
+) -> Response {
+    (status, Json(error_object(id, code, message))).into_response()
+}
+
+/// Whether reading a body failed because it exceeds the size limit, rather than because of
+/// the connection.
+pub(crate) fn exceeds_limit(error: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if error.is::<http_body_util::LengthLimitError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// The id of a request, answered by the MCP server
+fn request_id(message: &ClientJsonRpcMessage) -> Option<&RequestId> {
+    match message {
+        JsonRpcMessage::Request(JsonRpcRequest { id, .. }) => Some(id),
+        _ => None,
+    }
+}
+
+/// Whether the id is one the requests of a batch are forwarded with, which a client could
+/// otherwise use to take the response of a batch
+fn is_reserved_id(id: &RequestId) -> bool {
+    matches!(id, RequestId::String(id) if id.starts_with(BATCH_ID_PREFIX))
+}
+
+/// The id of the request answered by a response
+fn response_id(message: &ServerJsonRpcMessage) -> Option<&RequestId> {
+    match message {
+        JsonRpcMessage::Response(JsonRpcResponse { id, .. })
+        | JsonRpcMessage::Error(JsonRpcError { id, .. }) => Some(id),
+        _ => None,
+    }
+}
+
+/// An event of an SSE session, its id and the serialized message
+type SessionEvent = (u64, Arc<str>);
+
+/// An SSE session, outliving the stream of the client for the reconnect grace period so the
+/// client can resume it with `Last-Event-ID`.
+struct SseSession {
+    from_client: tokio::sync::mpsc::Sender<ClientJsonRpcMessage>,
+    /// Terminates the session, see [`SseServerTransport::ct`]
+    ct: CancellationToken,
+    events: Mutex<EventLog>,
+    /// Notified when the client attaches a new stream
+    attached: tokio::sync::Notify,
+    /// The batches posted by the client and not fully answered yet
+    batches: Mutex<Batches>,
+}
+
+/// The batches of messages posted by the client, each answered in a single event once all its
+/// requests are answered
+///
+/// The requests of a batch are forwarded with an id unique to the batch, so a response can't
+/// end up in another batch reusing the id. The ids start with [`BATCH_ID_PREFIX`], which the
+/// ids of the clients can't.
+#[derive(Default)]
+struct Batches {
+    /// The number of the last batch opened
+    last: u64,
+    /// The batches by number
+    open: HashMap<u64, Batch>,
+    /// The number of the batch and the id given by the client of the requests not answered yet,
+    /// by the id they were forwarded with
+    requests: HashMap<RequestId, (u64, RequestId)>,
+}
+
+struct Batch {
+    /// The number of requests not answered yet
+    pending: usize,
+    responses: Vec<serde_json::Value>,
+}
+
+/// The events sent to the client of an SSE session
+struct EventLog {
+    /// The id of the last event, `0` being the `endpoint` event
+    last_id: u64,
+    /// The last events, replayed to a reconnecting client
+    replay: VecDeque<SessionEvent>,
+    capacity: usize,
+    /// The stream of the client, if connected
+    stream: Option<tokio::sync::mpsc::Sender<SessionEvent>>,
+}
+
+impl SseSession {
+    fn new(
+        from_client: tokio::sync::mpsc::Sender<ClientJsonRpcMessage>,
+        ct: CancellationToken,
+        replay_buffer: usize,
+    ) -> Self {
+        Self {
+            from_client,
+            ct,
+            events: Mutex::new(EventLog {
+                last_id: 0,
+                replay: VecDeque::with_capacity(replay_buffer),
+                capacity: replay_buffer,
+                stream: None,
+            }),
+            attached: tokio::sync::Notify::new(),
+            batches: Mutex::new(Batches::default()),
+        }
+    }
+
+    fn events(&self) -> std::sync::MutexGuard<'_, EventLog> {
+        self.events.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replaces the stream of the client, returning the events after `last_id` to replay
+    /// before the ones received on the new stream.
+    fn attach(
+        &self,
+        session_id: &str,
+        last_id: u64,
+        capacity: usize,
+    ) -> (Vec<SessionEvent>, tokio::sync::mpsc::Receiver<SessionEvent>) {
+        let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(capacity);
+        let mut events = self.events();
+        let oldest = events
+            .replay
+            .front()
+            .map_or(events.last_id.saturating_add(1), |(id, _)| *id);
+        if last_id.saturating_add(1) < oldest {
+            tracing::warn!(
+                %session_id,
+                lost = oldest - last_id - 1,
+                "events no longer in the replay buffer are lost"
+            );
+        }
+        let replayed = events
+            .replay
+            .iter()
+            .filter(|(id, _)| *id > last_id)
+            .cloned()
+            .collect();
+        events.stream = Some(stream_tx);
+        drop(events);
+        self.attached.notify_one();
+        (replayed, stream_rx)
+    }
+
+    /// Records the message as the next event, returning the stream of the client to send it
+    /// on, if connected.
+    fn push(
+        &self,
+        data: Arc<str>,
+    ) -> (
+        SessionEvent,
+        Option<tokio::sync::mpsc::Sender<SessionEvent>>,
+    ) {
+        let mut events = self.events();
+        events.last_id += 1;
+        let event = (events.last_id, data);
+        if events.capacity > 0 {
+            if events.replay.len() == events.capacity {
+                events.replay.pop_front();
+            }
+            events.replay.push_back(event.clone());
+        }
+        (event, events.stream.clone())
+    }
+
+    fn stream(&self) -> Option<tokio::sync::mpsc::Sender<SessionEvent>> {
+        self.events().stream.clone()
+    }
+
+    /// Sends the event to the client, recording it for replay.
+    async fn send(&self, data: Arc<str>) {
+        let (event, stream) = self.push(data);
+        if let Some(stream) = stream {
+            // A closed stream is only the client reconnecting, the event is replayed then
+            let _ = stream.send(event).await;
+        }
+    }
+
+    fn batches(&self) -> std::sync::MutexGuard<'_, Batches> {
+        self.batches.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits for the responses to the requests among the `messages` of a batch, `responses`
+    /// holding the errors of its invalid messages. The requests are given the id they are
+    /// forwarded with, their ids must be unique within the batch.
+    ///
+    /// Returns the batch right away when it has no request to wait for.
+    fn open_batch(
+        &self,
+        messages: &mut [ClientJsonRpcMessage],
+        responses: Vec<serde_json::Value>,
+    ) -> Option<serde_json::Value> {
+        let mut batches = self.batches();
+        batches.last += 1;
+        let number = batches.last;
+        let mut pending = 0;
+        for message in messages {
+            if let JsonRpcMessage::Request(JsonRpcRequest { id, .. }) = message {
+                let forwarded = RequestId::String(format!("{BATCH_ID_PREFIX}{number}/{id}").into());
+                let id = std::mem::replace(id, forwarded.clone());
+                batches.requests.insert(forwarded, (number, id));
+                pending += 1;
+            }
+        }
+        if pending == 0 {
+            return (!responses.is_empty()).then_some(serde_json::Value::Array(responses));
+        }
+        batches.open.insert(number, Batch { pending, responses });
+        None
+    }
+
+    /// Whether the request was forwarded as part of a batch waiting for its responses
+    fn in_batch(&self, id: &RequestId) -> bool {
+        self.batches().requests.contains_key(id)
+    }
+
+    /// Adds the response to the batch of its request, with the id given by the client,
+    /// returning the batch once all its requests are answered.
+    fn answer(&self, id: &RequestId, mut response: serde_json::Value) -> Option<serde_json::Value> {
+        let mut batches = self.batches();
+        let (number, id) = batches.requests.remove(id)?;
+        response["id"] = serde_json::to_value(&id).unwrap_or_default();
+        let batch = batches.open.get_mut(&number)?;
+        batch.pending -= 1;
+        batch.responses.push(response);
+        if batch.pending > 0 {
+            return None;
+        }
+        let batch = batches.open.remove(&number)?;
+        Some(serde_json::Value::Array(batch.responses))
+    }
+}
+
+/// The id of an event of an SSE session, identifying the session to resume with
+/// `Last-Event-ID`
+fn event_id(session_id: &str, id: u64) -> String {
+    format!("{session_id}:{id}")
+}
+
+/// Parses the session and the event id from the `Last-Event-ID` of a reconnecting client.
+fn last_event_id(headers: &HeaderMap) -> Option<(&str, u64)> {
+    let value = headers.get("last-event-id")?.to_str().ok()?;
+    let (session_id, id) = value.rsplit_once(':')?;
+    Some((session_id, id.parse().ok()?))
+}
+
+/// The URL of `path` as reached by the client through a proxy, prefixed with its
+/// `X-Forwarded-Prefix` and made absolute with its `X-Forwarded-Host` and `X-Forwarded-Proto`.
+///
+/// The headers only change the endpoint advertised to the client that sent them; values that
+/// aren't a path or a host are ignored.
+pub(crate) fn public_url(headers: &HeaderMap, path: &str) -> String {
+    let forwarded = |name: &str| {
+        let value = headers.get(name)?.to_str().ok()?;
+        // The first proxy is the one the client reached
+        let value = value.split(',').next()?.trim();
+        (!value.is_empty()).then_some(value)
+    };
+    let prefix = forwarded("x-forwarded-prefix")
+        .filter(|prefix| is_path(prefix))
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    let host = forwarded("x-forwarded-host").filter(|host| {
+        host.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-.:[]".contains(&b))
+    });
+    match host {
+        Some(host) => {
+            let scheme = match forwarded("x-forwarded-proto") {
+                Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+                _ => "http",
+            };
+            format!("{scheme}://{host}{prefix}{path}")
+        }
+        None => format!("{prefix}{path}"),
+    }
+}
+
+/// Whether the value is an absolute path, without a query.
+fn is_path(value: &str) -> bool {
+    value.starts_with('/')
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@%/".contains(&b))
+}
 
 #[derive(Clone)]
 struct App {
     txs: TxStore,
-    transport_tx: tokio::sync::mpsc::UnboundedSender<SseServerTransport>,
+    transport_tx: tokio::sync::mpsc::Sender<SseServerTransport>,
+    /// The path the server is publicly reached under, without a trailing `/`
+    base_path: Arc<str>,
     post_path: Arc<str>,
     sse_ping_interval: Duration,
+    audit: Option<ServiceAudit>,
+    replay_buffer: usize,
+    reconnect_grace: Duration,
+    session_idle_timeout: Option<Duration>,
+    max_session_lifetime: Option<Duration>,
+    queue_capacity: usize,
+    send_timeout: Duration,
+    max_body_size: usize,
+    metrics: QueueMetrics,
+    probe_token: Option<Arc<str>>,
+    ct: CancellationToken,
 }
 
 impl App {
     pub fn new(
-        post_path: String,
-        sse_ping_interval: Duration,
-    ) -> (
-        Self,
-        tokio::sync::mpsc::UnboundedReceiver<SseServerTransport>,
-    ) {
-        let (transport_tx, transport_rx) = tokio::sync::mpsc::unbounded_channel();
+        config: &SseServerConfig,
+    ) -> (Self, tokio::sync::mpsc::Receiver<SseServerTransport>) {
+        let (transport_tx, transport_rx) =
+            tokio::sync::mpsc::channel(config.max_pending_sessions.max(1));
         (
             Self {
                 txs: Default::default(),
                 transport_tx,
-                post_path: post_path.into(),
-                sse_ping_interval,
+                base_path: config.base_path.trim_end_matches('/').into(),
+                post_path: config.post_path.clone().into(),
+                sse_ping_interval: config.sse_keep_alive.unwrap_or(DEFAULT_AUTO_PING_INTERVAL),
+                audit: config.audit.clone(),
+                replay_buffer: config.replay_buffer,
+                reconnect_grace: config.reconnect_grace,
+                session_idle_timeout: config.session_idle_timeout,
+                max_session_lifetime: config.max_session_lifetime,
+                queue_capacity: config.queue_capacity.max(1),
+                send_timeout: config.send_timeout,
+                max_body_size: config.max_body_size,
+                metrics: config.metrics.clone(),
+                probe_token: config.probe_token.as_deref().map(Into::into),
+                ct: config.ct.clone(),
             },
             transport_rx,
         )
     }
+
+    /// The `endpoint` event telling the client where to post the messages of the session, as
+    /// reached through the router it's nested in and the proxies in front.
+    fn endpoint(&self, headers: &HeaderMap, nested: Option<&NestedPath>, session: &str) -> Event {
+        let nested = nested.map_or("", |nested| nested.as_str().trim_end_matches('/'));
+        let path = format!(
+            "{}{nested}{}?sessionId={session}",
+            self.base_path, self.post_path
+        );
+        Event::default()
+            .event("endpoint")
+            .data(public_url(headers, &path))
+    }
+
+    /// Whether the request comes from the readiness probe of the manager.
+    fn is_probe(&self, headers: &HeaderMap) -> bool {
+        let token = headers
+            .get(PROBE_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok());
+        self.probe_token.is_some() && token == self.probe_token.as_deref()
+    }
+
+    /// Creates the transport of a new session opened with `headers`, terminated once it
+    /// expires.
+    fn transport(
+        &self,
+        session_id: SessionId,
+        headers: HeaderMap,
+        probe: bool,
+        from_client_rx: tokio::sync::mpsc::Receiver<ClientJsonRpcMessage>,
+        to_client_tx: tokio::sync::mpsc::Sender<ServerJsonRpcMessage>,
+    ) -> SseServerTransport {
+        let caller = self
+            .audit
+            .as_ref()
+            .and_then(|audit| audit.caller_identity(&headers));
+        let ct = self.ct.child_token();
+        let last_activity = Arc::new(Mutex::new(tokio::time::Instant::now()));
+        self.metrics.0.sessions.fetch_add(1, Ordering::Relaxed);
+        if self.session_idle_timeout.is_some() || self.max_session_lifetime.is_some() {
+            tokio::spawn(expire_session(
+                session_id.clone(),
+                ct.clone(),
+                last_activity.clone(),
+                self.session_idle_timeout,
+                self.max_session_lifetime,
+            ));
+        }
+        SseServerTransport {
+            stream: ReceiverStream::new(from_client_rx),
+            sink: PollSender::new(to_client_tx),
+            session_id,
+            tx_store: self.txs.clone(),
+            caller,
+            headers,
+            probe,
+            ct,
+            last_activity,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Hands the transport of a new session over to the server, unless too many sessions are
+    /// already waiting for their upstream transport.
+    #[allow(clippy::result_large_err)]
+    fn offer(&self, transport: SseServerTransport) -> Result<(), Response> {
+        let pending_sessions = &self.metrics.0.pending_sessions;
+        pending_sessions.fetch_add(1, Ordering::Relaxed);
+        let result = self.transport_tx.try_send(transport);
+        if result.is_err() {
+            pending_sessions.fetch_sub(1, Ordering::Relaxed);
+        }
+        match result {
+            Ok(()) => Ok(()),
+            Err(tokio::sync::mpsc::error::TrySendError::Full(transport)) => {
+                tracing::warn!(session_id = %transport.session_id, "too many pending sessions");
+                Err(self.metrics.unavailable(None))
+            }
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
+                tracing::warn!("send transport out error");
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "fail to send out transport, it seems server is closed",
+                )
+                    .into_response())
+            }
+        }
+    }
+}
+
+/// Terminates the session once it went without a message for `idle_timeout`, or lived for
+/// `max_lifetime`.
+async fn expire_session(
+    session_id: SessionId,
+    ct: CancellationToken,
+    last_activity: Arc<Mutex<tokio::time::Instant>>,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+) {
+    let end_of_life = max_lifetime.map(|max_lifetime| tokio::time::Instant::now() + max_lifetime);
+    loop {
+        let idle_deadline = idle_timeout.map(|idle_timeout| {
+            *last_activity.lock().unwrap_or_else(PoisonError::into_inner) + idle_timeout
+        });
+        let Some(deadline) = idle_deadline.into_iter().chain(end_of_life).min() else {
+            return;
+        };
+        tokio::select! {
+            () = tokio::time::sleep_until(deadline) => {}
+            () = ct.cancelled() => return,
+        }
+        let now = tokio::time::Instant::now();
+        if end_of_life.is_some_and(|end_of_life| now >= end_of_life) {
+            tracing::info!(%session_id, "session reached its maximum lifetime");
+            break;
+        }
+        let last_activity = *last_activity.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle_timeout.is_some_and(|idle_timeout| now >= last_activity + idle_timeout) {
+            tracing::info!(%session_id, "session idle for too long");
+            break;
+        }
+    }
+    ct.cancel();
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -81,82 +693,739 @@ pub struct PostEventQuery {
 
 async fn post_event_handler(
     State(app): State<App>,
-    Query(PostEventQuery { session_id }): Query<PostEventQuery>,
-    Json(message): Json<ClientJsonRpcMessage>,
-) -> Result<StatusCode, StatusCode> {
-    tracing::debug!(session_id, ?message, "new client message");
-    let tx = {
-        let rg = app.txs.read().await;
-        rg.get(session_id.as_str())
-            .ok_or(StatusCode::NOT_FOUND)?
-            .clone()
+    query: Result<Query<PostEventQuery>, QueryRejection>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, Response> {
+    let Ok(Query(PostEventQuery { session_id })) = query else {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            None,
+            ErrorCode::INVALID_REQUEST,
+            "missing sessionId",
+        ));
     };
-    if tx.send(message).await.is_err() {
-        tracing::error!("send message error");
-        return Err(StatusCode::GONE);
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"));
+    if !is_json {
+        return Err(error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            None,
+            ErrorCode::INVALID_REQUEST,
+            "Content-Type must be application/json",
+        ));
+    }
+    let body = match axum::body::to_bytes(body, app.max_body_size).await {
+        Ok(body) => body,
+        Err(e) if exceeds_limit(&e) => {
+            return Err(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                None,
+                ErrorCode::INVALID_REQUEST,
+                &format!("the message exceeds {} bytes", app.max_body_size),
+            ));
+        }
+        Err(e) => {
+            tracing::debug!(session_id, error = %e, "failed to read the message");
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                None,
+                ErrorCode::INVALID_REQUEST,
+                "failed to read the message",
+            ));
+        }
+    };
+    let session = app
+        .txs
+        .read()
+        .await
+        .get(session_id.as_str())
+        .cloned()
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::NOT_FOUND,
+                None,
+                TRANSPORT_ERROR,
+                "session not found",
+            )
+        })?;
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            None,
+            ErrorCode::PARSE_ERROR,
+            "Parse error",
+        ));
+    };
+    if let serde_json::Value::Array(batch) = value {
+        return post_batch(&app, &session_id, &session, batch).await;
+    }
+    let Ok(message) = serde_json::from_value::<ClientJsonRpcMessage>(value) else {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            None,
+            ErrorCode::INVALID_REQUEST,
+            "Invalid Request",
+        ));
+    };
+    tracing::debug!(session_id, ?message, "new client message");
+    let id = request_id(&message).cloned();
+    if id.as_ref().is_some_and(is_reserved_id) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            id.as_ref(),
+            ErrorCode::INVALID_REQUEST,
+            "reserved request id",
+        ));
+    }
+    match app.deliver(&session, message).await {
+        Ok(()) => Ok(StatusCode::ACCEPTED),
+        Err(StatusCode::SERVICE_UNAVAILABLE) => {
+            tracing::warn!(session_id, "the queue of the session is full");
+            Err(app.metrics.unavailable(id.as_ref()))
+        }
+        Err(status) => Err(error_response(
+            status,
+            id.as_ref(),
+            TRANSPORT_ERROR,
+            "the session is closed",
+        )),
+    }
+}
+
+/// Fans the messages of a batch out to the MCP server, the responses to its requests being
+/// sent to the client in a single event once all are answered.
+async fn post_batch(
+    app: &App,
+    session_id: &str,
+    session: &SseSession,
+    batch: Vec<serde_json::Value>,
+) -> Result<StatusCode, Response> {
+    if batch.is_empty() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            None,
+            ErrorCode::INVALID_REQUEST,
+            "Invalid Request",
+        ));
+    }
+    let mut messages = Vec::with_capacity(batch.len());
+    let mut responses = Vec::new();
+    let mut ids = HashSet::new();
+    for value in batch {
+        match serde_json::from_value::<ClientJsonRpcMessage>(value) {
+            // The responses couldn't be told apart
+            Ok(message) if request_id(&message).is_some_and(is_reserved_id) => {
+                responses.push(error_object(
+                    request_id(&message),
+                    ErrorCode::INVALID_REQUEST,
+                    "reserved request id",
+                ))
+            }
+            Ok(message) if request_id(&message).is_some_and(|id| !ids.insert(id.clone())) => {
+                responses.push(error_object(
+                    request_id(&message),
+                    ErrorCode::INVALID_REQUEST,
+                    "duplicate request id in the batch",
+                ))
+            }
+            Ok(message) => messages.push(message),
+            Err(_) => responses.push(error_object(
+                None,
+                ErrorCode::INVALID_REQUEST,
+                "Invalid Request",
+            )),
+        }
+    }
+    if let Some(answered) = session.open_batch(&mut messages, responses) {
+        session.send(answered.to_string().into()).await;
+    }
+    for message in messages {
+        tracing::debug!(session_id, ?message, "new client message");
+        let id = request_id(&message).cloned();
+        let Err(status) = app.deliver(session, message).await else {
+            continue;
+        };
+        tracing::warn!(session_id, %status, "failed to deliver a message of the batch");
+        // A notification is lost, a request is answered with the error
+        let Some(id) = id else {
+            continue;
+        };
+        let message = match status {
+            StatusCode::SERVICE_UNAVAILABLE => "the server is busy, retry later",
+            _ => "the session is closed",
+        };
+        let error = error_object(Some(&id), TRANSPORT_ERROR, message);
+        if let Some(answered) = session.answer(&id, error) {
+            session.send(answered.to_string().into()).await;
+        }
     }
     Ok(StatusCode::ACCEPTED)
 }
 
+impl App {
+    /// Queues the message for the MCP server, waiting up to the send timeout for room in the
+    /// queue of the session.
+    ///
+    /// Fails with `410 Gone` when the session is closed, and `503 Service Unavailable` when
+    /// its queue stayed full.
+    async fn deliver(
+        &self,
+        session: &SseSession,
+        message: ClientJsonRpcMessage,
+    ) -> Result<(), StatusCode> {
+        let client_messages = &self.metrics.0.client_messages;
+        client_messages.fetch_add(1, Ordering::Relaxed);
+        let result = match tokio::time::timeout(
+            self.send_timeout,
+            session.from_client.send(message),
+        )
+        .await
+        {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(_)) => {
+                tracing::error!("send message error");
+                Err(StatusCode::GONE)
+            }
+            Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+        };
+        client_messages.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+}
+
+/// Rejects the requests of the browsers on origins that aren't allowed, which could otherwise
+/// reach the loopback endpoints through DNS rebinding.
+async fn check_origin(
+    State(cors): State<Arc<CorsPolicy>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(origin) = request.headers().get(header::ORIGIN)
+        && !cors.allows(origin)
+    {
+        tracing::warn!(?origin, "rejected a request from a disallowed origin");
+        return error_response(
+            StatusCode::FORBIDDEN,
+            None,
+            TRANSPORT_ERROR,
+            "origin not allowed",
+        );
+    }
+    next.run(request).await
+}
+
+/// Terminates the session, tearing down its upstream transport.
+async fn delete_session_handler(
+    State(app): State<App>,
+    Query(PostEventQuery { session_id }): Query<PostEventQuery>,
+) -> StatusCode {
+    let Some(session) = app.txs.write().await.remove(session_id.as_str()) else {
+        return StatusCode::NOT_FOUND;
+    };
+    tracing::info!(session_id, "session terminated by the client");
+    session.ct.cancel();
+    StatusCode::NO_CONTENT
+}
+
+/// Opens a new session, or resumes the one of a client reconnecting with `Last-Event-ID`.
+///
+/// A reconnecting client isn't sent the id of the `endpoint` event again, which would have it
+/// replay the whole buffer on its next reconnection.
 async fn sse_handler(
     State(app): State<App>,
-) -> Result<Sse<impl Stream<Item = Result<Event, io::Error>>>, Response<String>> {
+    nested: Result<NestedPath, NestedPathRejection>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, io::Error>>>, Response> {
+    let nested = nested.ok();
+    if let Some((session_id, last_id)) = last_event_id(&headers) {
+        let resumed = app
+            .txs
+            .read()
+            .await
+            .get_key_value(session_id)
+            .map(|(session_id, session)| (session_id.clone(), session.clone()));
+        match resumed {
+            Some((session, sse_session)) => {
+                tracing::info!(%session, last_id, "sse reconnection");
+                let (replayed, stream_rx) =
+                    sse_session.attach(&session, last_id, app.queue_capacity);
+                let endpoint = app.endpoint(&headers, nested.as_ref(), &session);
+                let stream = app.event_stream(session, endpoint, replayed, stream_rx);
+                return Ok(
+                    Sse::new(stream).keep_alive(KeepAlive::new().interval(app.sse_ping_interval))
+                );
+            }
+            None => tracing::info!(session_id, "sse session expired, starting a new one"),
+        }
+    }
+
     let session = session_id();
-    tracing::info!(%session, "sse connection");
-    use tokio_stream::{StreamExt, wrappers::ReceiverStream};
-    use tokio_util::sync::PollSender;
-    let (from_client_tx, from_client_rx) = tokio::sync::mpsc::channel(64);
-    let (to_client_tx, to_client_rx) = tokio::sync::mpsc::channel(64);
-    let to_client_tx_clone = to_client_tx.clone();
+    let probe = app.is_probe(&headers);
+    tracing::info!(%session, probe, "sse connection");
+    let (from_client_tx, from_client_rx) = tokio::sync::mpsc::channel(app.queue_capacity);
+    let (to_client_tx, to_client_rx) = tokio::sync::mpsc::channel(app.queue_capacity);
 
+    let transport = app.transport(
+        session.clone(),
+        headers.clone(),
+        probe,
+        from_client_rx,
+        to_client_tx,
+    );
+    let sse_session = Arc::new(SseSession::new(
+        from_client_tx,
+        transport.ct.clone(),
+        app.replay_buffer,
+    ));
     app.txs
         .write()
         .await
-        .insert(session.clone(), from_client_tx);
-    let session = session.clone();
-    let stream = ReceiverStream::new(from_client_rx);
-    let sink = PollSender::new(to_client_tx);
-    let transport = SseServerTransport {
-        stream,
-        sink,
-        session_id: session.clone(),
-        tx_store: app.txs.clone(),
-    };
-    let transport_send_result = app.transport_tx.send(transport);
-    if transport_send_result.is_err() {
-        tracing::warn!("send transport out error");
-        let mut response =
-            Response::new("fail to send out transport, it seems server is closed".to_string());
-        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        .insert(session.clone(), sse_session.clone());
+    if let Err(response) = app.offer(transport) {
+        app.txs.write().await.remove(&session);
         return Err(response);
     }
-    let post_path = app.post_path.as_ref();
-    let ping_interval = app.sse_ping_interval;
-    let stream = futures::stream::once(futures::future::ok(
-        Event::default()
-            .event("endpoint")
-            .data(format!("{post_path}?sessionId={session}")),
-    ))
-    .chain(ReceiverStream::new(to_client_rx).map(|message| {
-        match serde_json::to_string(&message) {
-            Ok(bytes) => Ok(Event::default().event("message").data(&bytes)),
-            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-        }
-    }));
-
-    tokio::spawn(async move {
-        // Wait for connection closure
-        to_client_tx_clone.closed().await;
-
-        // Clean up session
-        let session_id = session.clone();
-        let tx_store = app.txs.clone();
-        let mut txs = tx_store.write().await;
-        txs.remove(&session_id);
-        tracing::debug!(%session_id, "Closed session and cleaned up resources");
+    let (replayed, stream_rx) = sse_session.attach(&session, 0, app.queue_capacity);
+    let endpoint = app
+        .endpoint(&headers, nested.as_ref(), &session)
+        .id(event_id(&session, 0));
+    let stream = app.event_stream(session.clone(), endpoint, replayed, stream_rx);
+    // The probe doesn't reconnect, its session ends with its stream
+    let reconnect_grace = if probe {
+        Duration::ZERO
+    } else {
+        app.reconnect_grace
+    };
+    tokio::spawn(pump_session(
+        app.clone(),
+        session,
+        sse_session,
+        to_client_rx,
+        reconnect_grace,
+    ));
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(app.sse_ping_interval)))
+}
+
+impl App {
+    /// The SSE stream of a client, the `endpoint` event and the replayed events followed by the
+    /// ones sent on the stream.
+    fn event_stream(
+        &self,
+        session: SessionId,
+        endpoint: Event,
+        replayed: Vec<SessionEvent>,
+        stream_rx: tokio::sync::mpsc::Receiver<SessionEvent>,
+    ) -> impl Stream<Item = Result<Event, io::Error>> + use<> {
+        futures::stream::once(futures::future::ok(endpoint)).chain(
+            futures::stream::iter(replayed)
+                .chain(ReceiverStream::new(stream_rx))
+                .map(move |(id, data)| {
+                    Ok(Event::default()
+                        .event("message")
+                        .id(event_id(&session, id))
+                        .data(&*data))
+                }),
+        )
+    }
+}
+
+/// Sends the messages of the server to the stream of the client, ending the session when the
+/// server is done, the session is terminated or the client didn't reconnect within the grace
+/// period.
+async fn pump_session(
+    app: App,
+    session_id: SessionId,
+    session: Arc<SseSession>,
+    mut to_client_rx: tokio::sync::mpsc::Receiver<ServerJsonRpcMessage>,
+    reconnect_grace: Duration,
+) {
+    // Started when the client disconnects, the messages of the server don't extend it
+    let grace = tokio::time::sleep(reconnect_grace);
+    tokio::pin!(grace);
+    let mut detached = false;
+    loop {
+        let stream = session.stream();
+        if detached && stream.as_ref().is_some_and(|stream| !stream.is_closed()) {
+            detached = false;
+        }
+        let message = tokio::select! {
+            message = to_client_rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            () = session.attached.notified() => continue,
+            () = session.ct.cancelled() => break,
+            () = closed(stream), if !detached => {
+                detached = true;
+                grace.as_mut().reset(tokio::time::Instant::now() + reconnect_grace);
+                continue;
+            }
+            () = &mut grace, if detached => {
+                tracing::debug!(%session_id, "sse client did not reconnect");
+                break;
+            }
+        };
+        app.metrics
+            .0
+            .server_messages
+            .fetch_sub(1, Ordering::Relaxed);
+        let data = match response_id(&message).filter(|id| session.in_batch(id)) {
+            // The responses to a batch are sent together
+            Some(id) => serde_json::to_value(&message)
+                .map(|value| session.answer(id, value).map(|batch| batch.to_string())),
+            None => serde_json::to_string(&message).map(Some),
+        };
+        match data {
+            // A client that stopped reading must not hold the session once it's terminated
+            Ok(Some(data)) => tokio::select! {
+                () = session.send(data.into()) => {}
+                () = session.ct.cancelled() => break,
+            },
+            Ok(None) => {}
+            Err(e) => tracing::error!(error = %e, "failed to serialize the message"),
+        }
+    }
+
+    // Dropping the session closes the stream of the client and the transport
+    app.metrics
+        .0
+        .server_messages
+        .fetch_sub(to_client_rx.len(), Ordering::Relaxed);
+    app.txs.write().await.remove(&session_id);
+    tracing::debug!(%session_id, "Closed session and cleaned up resources");
+}
+
+/// Completes once the stream of the client is closed, right away without one.
+async fn closed(stream: Option<tokio::sync::mpsc::Sender<SessionEvent>>) {
+    if let Some(stream) = stream {
+        stream.closed().await;
+    }
+}
+
+async fn ws_handler(State(app): State<App>, headers: HeaderMap, ws: WebSocketUpgrade) -> Response {
+    if app.transport_tx.capacity() == 0 {
+        tracing::warn!("too many pending sessions");
+        return app.metrics.unavailable(None);
+    }
+    let session = session_id();
+    tracing::info!(%session, "websocket connection");
+    // A message is limited like a posted one, a larger one closes the WebSocket
+    ws.max_message_size(app.max_body_size)
+        .max_frame_size(app.max_body_size)
+        .on_upgrade(move |socket| ws_session(socket, app, session, headers))
+}
+
+/// Carries the messages of the session over the WebSocket, one JSON-RPC message per frame.
+async fn ws_session(socket: WebSocket, app: App, session: SessionId, headers: HeaderMap) {
+    let (from_client_tx, from_client_rx) = tokio::sync::mpsc::channel(app.queue_capacity);
+    let (to_client_tx, mut to_client_rx) = tokio::sync::mpsc::channel(app.queue_capacity);
+    let transport = app.transport(
+        session.clone(),
+        headers,
+        false,
+        from_client_rx,
+        to_client_tx,
+    );
+    if app.offer(transport).is_err() {
+        return;
+    }
+
+    let (mut ws_sink, mut ws_stream) = futures::StreamExt::split(socket);
+    let mut ping = tokio::time::interval(app.sse_ping_interval);
+    // The first tick completes immediately
+    ping.tick().await;
+    loop {
+        tokio::select! {
+            message = to_client_rx.recv() => {
+                let Some(message) = message else {
+                    break;
+                };
+                app.metrics.0.server_messages.fetch_sub(1, Ordering::Relaxed);
+                let text = match serde_json::to_string(&message) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        tracing::error!(error = %e, "failed to serialize the message");
+                        continue;
+                    }
+                };
+                if ws_sink.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+            frame = ws_stream.next() => {
+                let data = match frame {
+                    Some(Ok(Message::Text(text))) => Bytes::from(text),
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    // Pongs answer our pings, pings are answered by the WebSocket itself
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        tracing::debug!(error = %e, "websocket error");
+                        break;
+                    }
+                };
+                let message = match serde_json::from_slice::<serde_json::Value>(&data) {
+                    Ok(value) => serde_json::from_value::<ClientJsonRpcMessage>(value)
+                        .map_err(|_| (ErrorCode::INVALID_REQUEST, "Invalid Request")),
+                    Err(_) => Err((ErrorCode::PARSE_ERROR, "Parse error")),
+                };
+                match message {
+                    Ok(message) => {
+                        tracing::debug!(%session, ?message, "new client message");
+                        let id = request_id(&message).cloned();
+                        let client_messages = &app.metrics.0.client_messages;
+                        client_messages.fetch_add(1, Ordering::Relaxed);
+                        // The messages of the server aren't sent meanwhile, a full queue
+                        // would otherwise hold both directions for good
+                        let sent = tokio::time::timeout(
+                            app.send_timeout,
+                            from_client_tx.send(message),
+                        )
+                        .await;
+                        match sent {
+                            Ok(Ok(())) => {}
+                            Ok(Err(_)) => {
+                                client_messages.fetch_sub(1, Ordering::Relaxed);
+                                break;
+                            }
+                            Err(_) => {
+                                client_messages.fetch_sub(1, Ordering::Relaxed);
+                                app.metrics.0.rejected.fetch_add(1, Ordering::Relaxed);
+                                tracing::warn!(%session, "session queue full, message rejected");
+                                let error = error_object(
+                                    id.as_ref(),
+                                    TRANSPORT_ERROR,
+                                    "the server is busy, retry later",
+                                )
+                                .to_string();
+                                if ws_sink.send(Message::text(error)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Err((code, reason)) => {
+                        tracing::warn!(%session, reason, "invalid client message");
+                        let error = error_object(None, code, reason).to_string();
+                        if ws_sink.send(Message::text(error)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                if ws_sink.send(Message::Ping(Bytes::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    app.metrics
+        .0
+        .server_messages
+        .fetch_sub(to_client_rx.len(), Ordering::Relaxed);
+    let _ = ws_sink.close().await;
+    tracing::debug!(%session, "Closed websocket session");
+}
+
+/// Forwards the messages of the session to the upstream transport and back until either side
+/// closes or the session is terminated.
+///
+/// The requests still pending when the upstream side closes are answered with an error, and
+/// the upstream transport is dropped, killing the child process of a STDIO server. A client
+/// or a server that stopped reading doesn't hold it: every send gives up once the session is
+/// terminated, and the pending requests are only answered within `send_timeout`.
+async fn bridge<Si, St>(
+    transport: SseServerTransport,
+    (upstream_sink, upstream_stream): (Si, St),
+    mut policy: PolicyFilter,
+    mut audit: Option<SessionAudit>,
+    send_timeout: Duration,
+) where
+    Si: Sink<ClientJsonRpcMessage, Error = io::Error>,
+    St: Stream<Item = ServerJsonRpcMessage>,
+{
+    let session_id = transport.session_id.clone();
+    let ct = transport.ct.clone();
+    let (client_sink, client_stream) =
+        IntoTransport::<RoleServer, _, ()>::into_transport(transport);
+    // Pin the streams and sinks so they implement Unpin
+    let mut upstream_sink = Box::pin(upstream_sink);
+    let mut upstream_stream = Box::pin(upstream_stream);
+    let mut client_sink = Box::pin(client_sink);
+    let mut client_stream = Box::pin(client_stream);
+    // The requests forwarded upstream and not answered yet
+    let mut pending = HashSet::new();
+
+    let reason = loop {
+        tokio::select! {
+            () = ct.cancelled() => break "the session was terminated",
+            message = upstream_stream.next() => {
+                let Some(mut message) = message else {
+                    break "the MCP server closed the connection";
+                };
+                if let JsonRpcMessage::Response(JsonRpcResponse { id, .. })
+                | JsonRpcMessage::Error(JsonRpcError { id, .. }) = &message
+                {
+                    pending.remove(id);
+                }
+                if let Some(audit) = &mut audit {
+                    audit.on_response(&message, false);
+                }
+                policy.filter_response(&mut message);
+                match send_unless_cancelled(&mut client_sink, message, &ct).await {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => {
+                        tracing::debug!(error = %e, %session_id, "client gone");
+                        return;
+                    }
+                    None => break "the session was terminated",
+                }
+            }
+            message = client_stream.next() => {
+                let Some(message) = message else {
+                    tracing::debug!(%session_id, "client closed the session");
+                    return;
+                };
+                if let Some(audit) = &mut audit {
+                    audit.on_request(&message);
+                }
+                // A second request with the id of a pending one would take its response, e.g.
+                // a `tools/list` response past the policy filter
+                let rejection = match &message {
+                    JsonRpcMessage::Request(JsonRpcRequest { id, .. }) if pending.contains(id) => {
+                        Some(JsonRpcMessage::Error(JsonRpcError {
+                            jsonrpc: JsonRpcVersion2_0,
+                            id: id.clone(),
+                            error: ErrorData::invalid_request("duplicate request id", None),
+                        }))
+                    }
+                    _ => policy.check_request(&message),
+                };
+                if let Some(rejection) = rejection {
+                    if let Some(audit) = &mut audit {
+                        audit.on_response(&rejection, true);
+                    }
+                    match send_unless_cancelled(&mut client_sink, rejection, &ct).await {
+                        Some(Ok(())) => continue,
+                        Some(Err(e)) => {
+                            tracing::debug!(error = %e, %session_id, "client gone");
+                            return;
+                        }
+                        None => break "the session was terminated",
+                    }
+                }
+                if let JsonRpcMessage::Request(JsonRpcRequest { id, .. }) = &message {
+                    pending.insert(id.clone());
+                }
+                match send_unless_cancelled(&mut upstream_sink, message, &ct).await {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => {
+                        tracing::error!(error = %e, %session_id, "send message error");
+                        break "the MCP server is unavailable";
+                    }
+                    None => break "the session was terminated",
+                }
+            }
+        }
+    };
+
+    tracing::info!(%session_id, reason, "closing the session");
+    let flush = async {
+        for id in pending {
+            let error = JsonRpcMessage::Error(JsonRpcError {
+                jsonrpc: JsonRpcVersion2_0,
+                id,
+                error: ErrorData::internal_error(reason, None),
+            });
+            if let Some(audit) = &mut audit {
+                audit.on_response(&error, false);
+            }
+            if client_sink.send(error).await.is_err() {
+                break;
+            }
+        }
+        let _ = upstream_sink.close().await;
+    };
+    if tokio::time::timeout(send_timeout, flush).await.is_err() {
+        tracing::debug!(%session_id, "gave up answering the pending requests");
+    }
+}
+
+/// Sends the message, unless the session is terminated first.
+async fn send_unless_cancelled<S, T>(
+    sink: &mut S,
+    message: T,
+    ct: &CancellationToken,
+) -> Option<Result<(), S::Error>>
+where
+    S: Sink<T> + Unpin,
+{
+    tokio::select! {
+        result = sink.send(message) => Some(result),
+        () = ct.cancelled() => None,
+    }
+}
+
+/// Serves the router until `ct` is cancelled, then removes the Unix domain socket, if any.
+pub(crate) fn spawn_server<L>(
+    listener: L,
+    router: Router,
+    ct: CancellationToken,
+    span: tracing::Span,
+    unix_socket: Option<PathBuf>,
+) where
+    L: axum::serve::Listener,
+    L::Addr: std::fmt::Debug,
+{
+    let server = axum::serve(listener, router).with_graceful_shutdown(async move {
+        ct.cancelled().await;
+        tracing::info!("sse server cancelled");
     });
+    tokio::spawn(
+        async move {
+            if let Err(e) = server.await {
+                tracing::error!(error = %e, "sse server shutdown with error");
+            }
+            if let Some(path) = unix_socket {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        .instrument(span),
+    );
+}
+
+/// Creates the parent directory of a Unix domain socket, only accessible by the current
+/// user, and removes a socket left over at `path`.
+pub fn prepare_unix_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Binds a Unix domain socket only accessible by the current user.
+pub fn bind_unix_socket(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
 
-    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(ping_interval)))
+    prepare_unix_socket(path)?;
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
 }
 
 pub struct SseServerTransport {
@@ -164,6 +1433,36 @@ pub struct SseServerTransport {
     sink: PollSender<TxJsonRpcMessage<RoleServer>>,
     session_id: SessionId,
     tx_store: TxStore,
+    /// The caller identity as seen from the auth proxy, only extracted when auditing
+    caller: Option<Arc<str>>,
+    /// The request headers of the connection that opened the session
+    headers: HeaderMap,
+    /// Whether the session is opened by the readiness probe, which is not audited
+    probe: bool,
+    /// Terminates the session, and with it the upstream transport serving it
+    ct: CancellationToken,
+    /// When the last message of the session was sent or received
+    last_activity: Arc<Mutex<tokio::time::Instant>>,
+    metrics: QueueMetrics,
+}
+
+impl Drop for SseServerTransport {
+    fn drop(&mut self) {
+        let counters = &self.metrics.0;
+        counters.sessions.fetch_sub(1, Ordering::Relaxed);
+        counters
+            .client_messages
+            .fetch_sub(self.stream.as_ref().len(), Ordering::Relaxed);
+    }
+}
+
+impl SseServerTransport {
+    fn touch(&self) {
+        *self
+            .last_activity
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = tokio::time::Instant::now();
+    }
 }
 
 impl Sink<TxJsonRpcMessage<RoleServer>> for SseServerTransport {
@@ -182,9 +1481,14 @@ impl Sink<TxJsonRpcMessage<RoleServer>> for SseServerTransport {
         mut self: std::pin::Pin<&mut Self>,
         item: TxJsonRpcMessage<RoleServer>,
     ) -> Result<(), Self::Error> {
-        self.sink
-            .start_send_unpin(item)
-            .map_err(std::io::Error::other)
+        self.touch();
+        let metrics = self.metrics.clone();
+        metrics.0.server_messages.fetch_add(1, Ordering::Relaxed);
+        let result = self.sink.start_send_unpin(item);
+        if result.is_err() {
+            metrics.0.server_messages.fetch_sub(1, Ordering::Relaxed);
+        }
+        result.map_err(std::io::Error::other)
     }
 
     fn poll_flush(
@@ -223,7 +1527,15 @@ impl Stream for SseServerTransport {
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
         use futures::StreamExt;
-        self.stream.poll_next_unpin(cx)
+        let poll = self.stream.poll_next_unpin(cx);
+        if let std::task::Poll::Ready(Some(_)) = &poll {
+            self.touch();
+            self.metrics
+                .0
+                .client_messages
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+        poll
     }
 }
 
@@ -232,57 +1544,155 @@ pub struct SseServerConfig {
     pub bind: SocketAddr,
     pub sse_path: String,
     pub post_path: String,
+    /// The path of the WebSocket endpoint, carrying the same messages as the SSE endpoints
+    pub ws_path: String,
     pub ct: CancellationToken,
     pub sse_keep_alive: Option<Duration>,
+    /// The number of events kept per SSE session to be replayed to a reconnecting client
+    pub replay_buffer: usize,
+    /// How long an SSE session outlives its stream, waiting for the client to reconnect
+    pub reconnect_grace: Duration,
+    /// Terminates the sessions that went without a message for this long, see
+    /// `MCP_SESSION_IDLE_TIMEOUT`
+    pub session_idle_timeout: Option<Duration>,
+    /// Terminates the sessions once they lived for this long, see `MCP_SESSION_MAX_LIFETIME`
+    pub max_session_lifetime: Option<Duration>,
+    /// The capacity of the message queues of a session, see `MCP_QUEUE_CAPACITY`
+    pub queue_capacity: usize,
+    /// How many new sessions may wait for their upstream transport before the next ones are
+    /// rejected, see `MCP_MAX_PENDING_SESSIONS`
+    pub max_pending_sessions: usize,
+    /// How long a message may wait for room in the queue of its session before it's rejected,
+    /// see `MCP_SEND_TIMEOUT`
+    pub send_timeout: Duration,
+    /// The maximum size of a message posted by a client, see `MCP_MAX_BODY_SIZE`
+    pub max_body_size: usize,
+    /// The depths of the queues, shared with the operator
+    pub metrics: QueueMetrics,
+    /// The access policy enforced on the forwarded messages
+    pub policy: Arc<McpServerPolicy>,
+    /// The browser origins allowed to reach the server, see the `cors` module
+    pub cors: Arc<CorsPolicy>,
+    /// Where the forwarded requests are audited, if enabled
+    pub audit: Option<ServiceAudit>,
+    /// The reserved port to serve on, `bind` is bound instead if it's `None` or already taken
+    pub reservation: Option<PortReservation>,
+    /// Serve on this Unix domain socket instead of TCP, only reachable by the operator user
+    pub unix_socket: Option<PathBuf>,
+    /// Serve TCP over TLS with these certificates, see the `tls` module
+    pub tls: Option<TlsConfig>,
+    /// A Unix domain socket also serving the TCP endpoint, in plain HTTP, for the readiness
+    /// probe and the router to reach the server without a client certificate when TCP is
+    /// served over TLS
+    pub internal_socket: Option<PathBuf>,
+    /// The path the server is publicly reached under, prefixed to the advertised message
+    /// endpoint, e.g. `/mcp` when a proxy strips it; `X-Forwarded-Prefix` is prefixed to it.
+    /// See `MCP_BASE_PATH`
+    pub base_path: String,
+    /// The secret the readiness probe sends in [`PROBE_TOKEN_HEADER`]: its sessions are not
+    /// audited and end as soon as their stream closes
+    pub probe_token: Option<String>,
+}
+
+impl SseServerConfig {
+    /// Creates a config with the default paths, the session limits set by the operator and no
+    /// access policy.
+    pub fn new(bind: SocketAddr) -> Self {
+        Self {
+            bind,
+            sse_path: "/sse".to_string(),
+            post_path: "/message".to_string(),
+            ws_path: "/ws".to_string(),
+            ct: CancellationToken::new(),
+            sse_keep_alive: None,
+            replay_buffer: DEFAULT_REPLAY_BUFFER,
+            reconnect_grace: DEFAULT_RECONNECT_GRACE,
+            session_idle_timeout: *SESSION_IDLE_TIMEOUT,
+            max_session_lifetime: *MAX_SESSION_LIFETIME,
+            queue_capacity: *QUEUE_CAPACITY,
+            max_pending_sessions: *MAX_PENDING_SESSIONS,
+            send_timeout: *SEND_TIMEOUT,
+            max_body_size: *MAX_BODY_SIZE,
+            metrics: QueueMetrics::default(),
+            policy: Default::default(),
+            cors: cors::OPERATOR_POLICY.clone(),
+            audit: None,
+            reservation: None,
+            unix_socket: None,
+            tls: tls::OPERATOR_TLS.clone(),
+            internal_socket: None,
+            base_path: BASE_PATH.clone(),
+            probe_token: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct SseServer {
-    transport_rx: tokio::sync::mpsc::UnboundedReceiver<SseServerTransport>,
+    transport_rx: tokio::sync::mpsc::Receiver<SseServerTransport>,
     pub config: SseServerConfig,
 }
 
 impl SseServer {
     pub async fn serve(bind: SocketAddr) -> io::Result<Self> {
-        Self::serve_with_config(SseServerConfig {
-            bind,
-            sse_path: "/sse".to_string(),
-            post_path: "/message".to_string(),
-            ct: CancellationToken::new(),
-            sse_keep_alive: None,
-        })
-        .await
+        Self::serve_with_config(SseServerConfig::new(bind)).await
     }
     pub async fn serve_with_config(config: SseServerConfig) -> io::Result<Self> {
         let (sse_server, service) = Self::new(config);
-        let listener = tokio::net::TcpListener::bind(sse_server.config.bind).await?;
         let ct = sse_server.config.ct.child_token();
-        let server = axum::serve(listener, service).with_graceful_shutdown(async move {
-            ct.cancelled().await;
-            tracing::info!("sse server cancelled");
-        });
-        tokio::spawn(
-            async move {
-                if let Err(e) = server.await {
-                    tracing::error!(error = %e, "sse server shutdown with error");
+        if let Some(path) = sse_server.config.unix_socket.clone() {
+            // The reserved port is not served, free it
+            if let Some(reservation) = &sse_server.config.reservation {
+                reservation.release();
+            }
+            let listener = bind_unix_socket(&path)?;
+            let span = tracing::info_span!("sse-server", bind_address = %path.display());
+            spawn_server(listener, service, ct, span, Some(path));
+        } else {
+            let reserved = sse_server
+                .config
+                .reservation
+                .as_ref()
+                .and_then(PortReservation::take_listener);
+            let listener = match reserved {
+                Some(listener) => tokio::net::TcpListener::from_std(listener)?,
+                None => tokio::net::TcpListener::bind(sse_server.config.bind).await?,
+            };
+            if let Some(path) = sse_server.config.internal_socket.clone() {
+                let listener = bind_unix_socket(&path)?;
+                let span = tracing::info_span!("sse-server", bind_address = %path.display());
+                spawn_server(listener, service.clone(), ct.clone(), span, Some(path));
+            }
+            let span = tracing::info_span!("sse-server", bind_address = %sse_server.config.bind);
+            match sse_server.config.tls.clone() {
+                Some(config) => {
+                    let listener = TlsListener::new(listener, config)?;
+                    spawn_server(listener, service, ct, span, None);
                 }
+                None => spawn_server(listener, service, ct, span, None),
             }
-            .instrument(tracing::info_span!("sse-server", bind_address = %sse_server.config.bind)),
-        );
+        }
         Ok(sse_server)
     }
 
-    /// Warning: This function creates a new SseServer instance with the provided configuration.
-    /// `App.post_path` may be incorrect if using `Router` as an embedded router.
+    /// Creates the server and its router, which can be nested in another one: the advertised
+    /// message endpoint includes the path it's nested at, `config.base_path` and the forwarded
+    /// prefix and host of a proxy, see [`public_url`].
     pub fn new(config: SseServerConfig) -> (SseServer, Router) {
-        let (app, transport_rx) = App::new(
-            config.post_path.clone(),
-            config.sse_keep_alive.unwrap_or(DEFAULT_AUTO_PING_INTERVAL),
-        );
+        let (app, transport_rx) = App::new(&config);
         let router = Router::new()
             .route(&config.sse_path, get(sse_handler))
-            .route(&config.post_path, post(post_event_handler))
-            .with_state(app);
+            .route(
+                &config.post_path,
+                post(post_event_handler).delete(delete_session_handler),
+            )
+            .route(&config.ws_path, get(ws_handler))
+            .with_state(app)
+            .layer(config.cors.layer())
+            .layer(middleware::from_fn_with_state(
+                config.cors.clone(),
+                check_origin,
+            ));
 
         let server = SseServer {
             transport_rx,
@@ -292,18 +1702,27 @@ impl SseServer {
         (server, router)
     }
 
-    #[allow(dead_code)]
-    pub fn with_service<S, F>(mut self, service_provider: F) -> CancellationToken
+    pub fn with_service<S, F>(self, service_provider: F) -> CancellationToken
     where
         S: Service<RoleServer>,
         F: Fn() -> S + Send + 'static,
+    {
+        self.with_session_service(move |_| service_provider())
+    }
+
+    /// Serves each session with the service created from the request headers of the
+    /// connection that opened it.
+    pub fn with_session_service<S, F>(mut self, service_provider: F) -> CancellationToken
+    where
+        S: Service<RoleServer>,
+        F: Fn(&HeaderMap) -> S + Send + 'static,
     {
         use rmcp::service::ServiceExt;
         let ct = self.config.ct.clone();
         tokio::spawn(async move {
             while let Some(transport) = self.next_transport().await {
-                let service = service_provider();
-                let ct = self.config.ct.child_token();
+                let service = service_provider(&transport.headers);
+                let ct = transport.ct.clone();
                 tokio::spawn(async move {
                     let server = service.serve_with_ct(transport, ct).await?;
                     server.waiting().await?;
@@ -323,41 +1742,27 @@ impl SseServer {
         let ct = self.config.ct.clone();
         tokio::spawn(async move {
             while let Some(transport) = self.next_transport().await {
-                let ct = self.config.ct.child_token();
-                let inner_transport = match factory().await {
-                    Ok(inner_transport) => inner_transport.into_transport(),
-                    Err(e) => {
-                        tracing::error!(error = %e, "create transport error");
-                        continue;
-                    }
-                };
+                let policy = PolicyFilter::new(self.config.policy.clone());
+                let send_timeout = self.config.send_timeout;
+                let audit = self
+                    .config
+                    .audit
+                    .as_ref()
+                    .filter(|_| !transport.probe)
+                    .map(|audit| {
+                        audit.session(transport.session_id.clone(), transport.caller.clone())
+                    });
+                let upstream = factory();
                 tokio::spawn(async move {
-                    let (stdio_sink, stdio_stream) = inner_transport;
-                    let (sse_sink, sse_stream) =
-                        IntoTransport::<RoleServer, _, ()>::into_transport(transport);
-                    // Pin the streams and sinks so they implement Unpin
-                    let mut stdio_sink = Box::pin(stdio_sink);
-                    let mut sse_sink = Box::pin(sse_sink);
-                    let mut stdio_stream = Box::pin(stdio_stream.fuse());
-                    let mut sse_stream = Box::pin(sse_stream.fuse());
-                    // forward the stream to the sink and the sink to the stream
-                    while !ct.is_cancelled() {
-                        tokio::select! {
-                            Some(message) = stdio_stream.next() => {
-                                if let Err(e) = sse_sink.send(message).await {
-                                    tracing::error!(error = %e, "send message error");
-                                    break;
-                                }
-                            }
-                            Some(message) = sse_stream.next() => {
-                                if let Err(e) = stdio_sink.send(message).await {
-                                    tracing::error!(error = %e, "send message error");
-                                    break;
-                                }
-                            }
+                    let upstream = match upstream.await {
+                        Ok(upstream) => upstream.into_transport(),
+                        Err(e) => {
+                            // Dropping the transport ends the session of the client
+                            tracing::error!(error = %e, session_id = %transport.session_id, "create transport error");
+                            return;
                         }
-                    }
-                    tokio::io::Result::Ok(())
+                    };
+                    bridge(transport, upstream, policy, audit, send_timeout).await;
                 });
             }
         });
@@ -370,7 +1775,24 @@ impl SseServer {
     }
 
     pub async fn next_transport(&mut self) -> Option<SseServerTransport> {
-        self.transport_rx.recv().await
+        let transport = self.transport_rx.recv().await;
+        if transport.is_some() {
+            self.on_transport_taken();
+        }
+        transport
+    }
+
+    /// The depths of the queues of the server
+    pub fn metrics(&self) -> QueueDepths {
+        self.config.metrics.snapshot()
+    }
+
+    fn on_transport_taken(&self) {
+        self.config
+            .metrics
+            .0
+            .pending_sessions
+            .fetch_sub(1, Ordering::Relaxed);
     }
 }
 
@@ -381,6 +1803,724 @@ impl Stream for SseServer {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.transport_rx.poll_recv(cx)
+        let poll = self.transport_rx.poll_recv(cx);
+        if let std::task::Poll::Ready(Some(_)) = &poll {
+            self.on_transport_taken();
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use blueprint_sdk::tangle::extract::{List, Optional};
+    use futures::StreamExt;
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    use super::*;
+    use crate::policy::AccessList;
+    use crate::readiness::{self, ProbeTarget, SseReader};
+    use crate::test_support::{
+        SseClient, call_tool, fake_factory, initialize, serve_fake, temp_socket,
+        tracked_fake_mcp_server,
+    };
+
+    /// Sends a request on a path of the server, returning the status and the JSON body.
+    async fn send(
+        target: &ProbeTarget,
+        method: Method,
+        path: &str,
+        content_type: &str,
+        body: String,
+    ) -> (StatusCode, Value) {
+        let mut request = readiness::post(path, body.into());
+        *request.method_mut() = method;
+        request.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_str(content_type).unwrap(),
+        );
+        let response = readiness::send(target, request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    /// Deletes the session of the client.
+    async fn delete(target: &ProbeTarget, client: &SseClient) -> StatusCode {
+        let path = readiness::path_of(&client.endpoint);
+        let (status, _) = send(
+            target,
+            Method::DELETE,
+            path,
+            "application/json",
+            String::new(),
+        )
+        .await;
+        status
+    }
+
+    /// Waits for the metrics to settle on `expected`.
+    async fn settled(metrics: &QueueMetrics, expected: QueueDepths) {
+        for _ in 0..100 {
+            if metrics.snapshot() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(metrics.snapshot(), expected);
+    }
+
+    #[tokio::test]
+    async fn pending_requests_are_answered_when_the_server_exits() {
+        let metrics = QueueMetrics::default();
+        let socket = serve_fake("bridge-exit", |config| config.metrics = metrics.clone()).await;
+        let target = ProbeTarget::Unix(socket);
+        let mut client = SseClient::connect(&target, "/sse").await;
+        let initialized = client.request(initialize(1)).await;
+        assert_eq!(initialized["result"]["serverInfo"]["name"], "fake");
+
+        for (id, tool) in [(2, "hang"), (3, "crash")] {
+            let (status, body) = client
+                .post(&call_tool(id, tool, serde_json::json!({})))
+                .await;
+            assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        }
+        let mut answered = Vec::new();
+        for _ in 0..2 {
+            let error = client.next_message().await;
+            assert_eq!(
+                error["error"]["message"], "the MCP server closed the connection",
+                "{error}"
+            );
+            answered.push(error["id"].as_u64().unwrap());
+        }
+        answered.sort_unstable();
+        assert_eq!(answered, [2, 3]);
+
+        // The session ended with the server
+        let (status, _) = client.post(&initialize(4)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        settled(&metrics, QueueDepths::default()).await;
+    }
+
+    #[tokio::test]
+    async fn requests_reusing_a_pending_id_are_rejected() {
+        let policy = McpServerPolicy {
+            tools: AccessList {
+                deny: Optional(Some(List(vec!["crash".to_string()]))),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let socket = serve_fake("pending-ids", |config| config.policy = Arc::new(policy)).await;
+        let target = ProbeTarget::Unix(socket);
+        let mut client = SseClient::connect(&target, "/sse").await;
+        client.request(initialize(1)).await;
+
+        // Answering the pending call would let the response to the list through unfiltered
+        let (status, body) = client
+            .post(&call_tool(2, "hang", serde_json::json!({})))
+            .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        let list = |id| serde_json::json!({ "jsonrpc": "2.0", "id": id, "method": "tools/list" });
+        let rejected = client.request(list(2)).await;
+        assert_eq!(rejected["error"]["code"], -32600, "{rejected}");
+
+        let listed = client.request(list(3)).await;
+        let names: Vec<&str> = listed["result"]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|tool| tool["name"].as_str())
+            .collect();
+        assert_eq!(names, ["echo", "hang"], "{listed}");
+    }
+
+    #[tokio::test]
+    async fn deleting_the_session_closes_the_server() {
+        let metrics = QueueMetrics::default();
+        let socket = serve_fake("bridge-delete", |config| config.metrics = metrics.clone()).await;
+        let target = ProbeTarget::Unix(socket);
+        let mut client = SseClient::connect(&target, "/sse").await;
+        client.request(initialize(1)).await;
+
+        assert_eq!(delete(&target, &client).await, StatusCode::NO_CONTENT);
+        settled(&metrics, QueueDepths::default()).await;
+        let (status, _) = client.post(&initialize(2)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(delete(&target, &client).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_stalled_session_closes_the_server() {
+        let socket = temp_socket("bridge-stalled");
+        let config = SseServerConfig {
+            unix_socket: Some(socket.clone()),
+            tls: None,
+            queue_capacity: 1,
+            send_timeout: Duration::from_millis(200),
+            ..SseServerConfig::new(([127, 0, 0, 1], 0).into())
+        };
+        let closed = CancellationToken::new();
+        let factory = {
+            let closed = closed.clone();
+            move || futures::future::ready(Ok(tracked_fake_mcp_server(closed.clone())))
+        };
+        SseServer::serve_with_config(config)
+            .await
+            .unwrap()
+            .forward(factory);
+        let target = ProbeTarget::Unix(socket);
+        let mut client = SseClient::connect(&target, "/sse").await;
+        client.request(initialize(1)).await;
+
+        // The client stops reading, the responses fill its stream and both session queues
+        let text = "x".repeat(256 * 1024);
+        for id in 2..18 {
+            let echo = call_tool(id, "echo", serde_json::json!({ "text": text }));
+            client.post(&echo).await;
+        }
+        assert_eq!(delete(&target, &client).await, StatusCode::NO_CONTENT);
+        tokio::time::timeout(Duration::from_secs(5), closed.cancelled())
+            .await
+            .expect("the MCP server of the deleted session is still running");
+    }
+
+    #[tokio::test]
+    async fn sessions_resume_from_the_last_event_id() {
+        let socket = serve_fake("resume", |config| {
+            config.reconnect_grace = Duration::from_millis(500);
+        })
+        .await;
+        let target = ProbeTarget::Unix(socket);
+        let mut client = SseClient::connect(&target, "/sse").await;
+        let session = client
+            .endpoint
+            .split("sessionId=")
+            .nth(1)
+            .unwrap()
+            .to_string();
+        client.request(initialize(1)).await;
+        let endpoint = client.endpoint.clone();
+        drop(client);
+
+        // Answered while the client is away, and replayed once it's back
+        let echo = call_tool(2, "echo", serde_json::json!({ "text": "missed" }));
+        let path = readiness::path_of(&endpoint);
+        let (status, _) = send(
+            &target,
+            Method::POST,
+            path,
+            "application/json",
+            echo.to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut request = readiness::get("/sse");
+        request.headers_mut().insert(
+            "last-event-id",
+            header::HeaderValue::from_str(&format!("{session}:1")).unwrap(),
+        );
+        let response = readiness::send(&target, request).await.unwrap();
+        let mut client = SseClient::from_response(&target, response).await;
+        assert_eq!(client.endpoint, endpoint);
+        let replayed = client.next_message().await;
+        assert_eq!(replayed["id"], 2, "{replayed}");
+        assert!(replayed.to_string().contains("missed"), "{replayed}");
+        drop(client);
+
+        // The session expires once the grace is over, the next one is a new session
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        let (status, _) = send(
+            &target,
+            Method::POST,
+            path,
+            "application/json",
+            echo.to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let mut request = readiness::get("/sse");
+        request.headers_mut().insert(
+            "last-event-id",
+            header::HeaderValue::from_str(&format!("{session}:2")).unwrap(),
+        );
+        let response = readiness::send(&target, request).await.unwrap();
+        let client = SseClient::from_response(&target, response).await;
+        assert!(!client.endpoint.contains(&session), "{}", client.endpoint);
+    }
+
+    #[tokio::test]
+    async fn full_queues_are_rejected_with_retry_after() {
+        let socket = temp_socket("backpressure");
+        let metrics = QueueMetrics::default();
+        let config = SseServerConfig {
+            unix_socket: Some(socket.clone()),
+            tls: None,
+            queue_capacity: 1,
+            max_pending_sessions: 1,
+            send_timeout: Duration::from_millis(200),
+            metrics: metrics.clone(),
+            ..SseServerConfig::new(([127, 0, 0, 1], 0).into())
+        };
+        // Nobody takes the transports of the sessions yet
+        let mut server = SseServer::serve_with_config(config).await.unwrap();
+        let target = ProbeTarget::Unix(socket);
+
+        let client = SseClient::connect(&target, "/sse").await;
+        let response = readiness::send(&target, readiness::get("/sse"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], RETRY_AFTER_SECS);
+        let depths = metrics.snapshot();
+        assert_eq!((depths.sessions, depths.pending_sessions), (1, 1));
+
+        let (status, _) = client.post(&initialize(1)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let (status, body) = client.post(&initialize(2)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("\"id\":2"), "{body}");
+        let depths = metrics.snapshot();
+        assert_eq!((depths.client_messages, depths.rejected), (1, 2));
+
+        let transport = server.next_transport().await.unwrap();
+        assert_eq!(metrics.snapshot().pending_sessions, 0);
+        drop(transport);
+        let expected = QueueDepths {
+            rejected: 2,
+            ..Default::default()
+        };
+        assert_eq!(metrics.snapshot(), expected);
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_when_idle_or_too_old() {
+        let socket = serve_fake("expiry", |config| {
+            config.session_idle_timeout = Some(Duration::from_millis(300));
+            config.max_session_lifetime = Some(Duration::from_millis(1000));
+        })
+        .await;
+        let target = ProbeTarget::Unix(socket);
+
+        let client = SseClient::connect(&target, "/sse").await;
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let (status, _) = client.post(&initialize(1)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Activity keeps the session alive until its maximum lifetime
+        let client = SseClient::connect(&target, "/sse").await;
+        let started = tokio::time::Instant::now();
+        let initialized = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/initialized",
+        });
+        while started.elapsed() < Duration::from_millis(800) {
+            let (status, _) = client.post(&initialized).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let (status, _) = client.post(&initialized).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn invalid_posts_are_answered_with_json_rpc_errors() {
+        let socket = serve_fake("post-errors", |config| config.max_body_size = 1024).await;
+        let target = ProbeTarget::Unix(socket);
+        let client = SseClient::connect(&target, "/sse").await;
+        let path = readiness::path_of(&client.endpoint);
+        let json = "application/json";
+
+        let cases = [
+            (
+                path,
+                json,
+                "{nope".to_string(),
+                StatusCode::BAD_REQUEST,
+                -32700,
+            ),
+            (
+                path,
+                json,
+                "[]".to_string(),
+                StatusCode::BAD_REQUEST,
+                -32600,
+            ),
+            (
+                path,
+                json,
+                r#"{"id":1}"#.to_string(),
+                StatusCode::BAD_REQUEST,
+                -32600,
+            ),
+            (
+                path,
+                "text/plain",
+                "{}".to_string(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                -32600,
+            ),
+            (
+                path,
+                json,
+                "x".repeat(2048),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                -32600,
+            ),
+            (
+                "/message?sessionId=nope",
+                json,
+                initialize(1).to_string(),
+                StatusCode::NOT_FOUND,
+                -32000,
+            ),
+            (
+                "/message",
+                json,
+                initialize(1).to_string(),
+                StatusCode::BAD_REQUEST,
+                -32600,
+            ),
+        ];
+        for (path, content_type, body, expected, code) in cases {
+            let (status, error) = send(&target, Method::POST, path, content_type, body).await;
+            assert_eq!(status, expected, "{error}");
+            assert_eq!(error["jsonrpc"], "2.0");
+            assert_eq!(error["error"]["code"], code, "{error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_are_answered_together() {
+        let socket = serve_fake("batch", |_| {}).await;
+        let target = ProbeTarget::Unix(socket);
+        let mut client = SseClient::connect(&target, "/sse").await;
+        client.request(initialize(1)).await;
+
+        let batch = serde_json::json!([
+            call_tool(2, "echo", serde_json::json!({})),
+            { "bad": 1 },
+            {
+                "jsonrpc": "2.0",
+                "method": "notifications/progress",
+                "params": { "progressToken": 1, "progress": 1 },
+            },
+            { "jsonrpc": "2.0", "id": "x", "method": "tools/list" },
+        ]);
+        let path = readiness::path_of(&client.endpoint);
+        let content_type = "application/json; charset=utf-8";
+        let (status, _) = send(&target, Method::POST, path, content_type, batch.to_string()).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let responses = client.next_message().await;
+        let responses = responses.as_array().unwrap();
+        assert_eq!(responses.len(), 3, "{responses:?}");
+        assert_eq!(responses[0]["error"]["code"], -32600);
+        assert!(responses.iter().any(|response| response["id"] == 2));
+        assert!(responses.iter().any(|response| response["id"] == "x"));
+
+        // A batch of invalid messages only
+        let (status, _) = client.post(&serde_json::json!([1])).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let responses = client.next_message().await;
+        assert_eq!(responses[0]["error"]["code"], -32600, "{responses}");
+    }
+
+    #[tokio::test]
+    async fn batches_reusing_request_ids_get_their_own_responses() {
+        let socket = serve_fake("batch-ids", |_| {}).await;
+        let target = ProbeTarget::Unix(socket);
+        let mut client = SseClient::connect(&target, "/sse").await;
+        client.request(initialize(1)).await;
+
+        // The first batch waits for `hang` while the second reuses its ids
+        let first = serde_json::json!([
+            call_tool(2, "echo", serde_json::json!({ "text": "first" })),
+            call_tool(3, "hang", serde_json::json!({})),
+        ]);
+        let (status, _) = client.post(&first).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let second = serde_json::json!([
+            call_tool(2, "echo", serde_json::json!({ "text": "second" })),
+            call_tool(2, "echo", serde_json::json!({ "text": "duplicate" })),
+        ]);
+        let (status, _) = client.post(&second).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let responses = client.next_message().await;
+        let responses = responses.as_array().unwrap();
+        assert_eq!(responses.len(), 2, "{responses:?}");
+        let duplicate = responses
+            .iter()
+            .find(|response| response.get("error").is_some())
+            .unwrap();
+        assert_eq!(duplicate["id"], 2);
+        assert_eq!(duplicate["error"]["code"], -32600);
+        let echoed = responses
+            .iter()
+            .find(|response| response.get("result").is_some())
+            .unwrap();
+        assert_eq!(echoed["id"], 2);
+        assert!(echoed.to_string().contains("second"), "{echoed}");
+
+        // A single request reusing the id isn't taken for the pending one of the first batch
+        let echoed = client
+            .request(call_tool(
+                3,
+                "echo",
+                serde_json::json!({ "text": "single" }),
+            ))
+            .await;
+        assert_eq!(echoed["id"], 3);
+        assert!(echoed.to_string().contains("single"), "{echoed}");
+
+        // Nor is one using the id the pending request was forwarded with
+        let forwarded = format!("{BATCH_ID_PREFIX}1/3");
+        let mut single = call_tool(0, "echo", serde_json::json!({}));
+        single["id"] = forwarded.clone().into();
+        let (status, body) = client.post(&single).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("reserved request id"), "{body}");
+        let (status, _) = client.post(&serde_json::json!([single])).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let responses = client.next_message().await;
+        assert_eq!(responses[0]["id"], forwarded.as_str(), "{responses}");
+        assert_eq!(responses[0]["error"]["code"], -32600, "{responses}");
+    }
+
+    #[tokio::test]
+    async fn only_bodies_over_the_limit_are_too_large() {
+        let body = Body::from(vec![b'x'; 16]);
+        let error = axum::body::to_bytes(body, 8).await.unwrap_err();
+        assert!(exceeds_limit(&error));
+
+        let reset = futures::stream::iter([Err::<Bytes, _>(io::Error::other("reset"))]);
+        let error = axum::body::to_bytes(Body::from_stream(reset), 8)
+            .await
+            .unwrap_err();
+        assert!(!exceeds_limit(&error));
+    }
+
+    #[tokio::test]
+    async fn unix_sockets_are_only_reachable_by_the_operator() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let ct = CancellationToken::new();
+        let socket = serve_fake("permissions", |config| config.ct = ct.clone()).await;
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&socket), 0o600);
+        assert_eq!(mode(socket.parent().unwrap()), 0o700);
+        let target = ProbeTarget::Unix(socket.clone());
+        let response = readiness::send(&target, readiness::get("/sse"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        ct.cancel();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn websocket_messages_are_limited_and_parse_errors_answered() {
+        let socket = serve_fake("ws-limits", |config| config.max_body_size = 1024).await;
+        let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/ws", stream)
+            .await
+            .unwrap();
+
+        for (message, code) in [("not json", -32700), (r#"{"id":1}"#, -32600)] {
+            ws.send(WsMessage::text(message)).await.unwrap();
+            let reply = ws.next().await.unwrap().unwrap();
+            let reply: Value = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+            assert_eq!(reply["error"]["code"], code, "{reply}");
+        }
+
+        ws.send(WsMessage::text("x".repeat(2048))).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match ws.next().await {
+                    Some(Ok(WsMessage::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        })
+        .await;
+        assert!(
+            closed.is_ok(),
+            "the oversized message didn't close the WebSocket"
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_clients_outpacing_the_server_are_answered_busy() {
+        let socket = serve_fake("ws-busy", |config| {
+            config.queue_capacity = 1;
+            config.send_timeout = Duration::from_millis(200);
+        })
+        .await;
+        let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/ws", stream)
+            .await
+            .unwrap();
+
+        // Nothing is read until every call is sent, both queues fill up
+        for id in 1..=20 {
+            let call = call_tool(id, "echo", serde_json::json!({}));
+            ws.send(WsMessage::text(call.to_string())).await.unwrap();
+        }
+        let mut answered = HashSet::new();
+        let mut busy = 0;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while answered.len() < 20 {
+                let reply = ws.next().await.unwrap().unwrap();
+                let Ok(text) = reply.to_text() else { continue };
+                let reply: Value = serde_json::from_str(text).unwrap();
+                if reply["error"]["code"] == TRANSPORT_ERROR.0 {
+                    busy += 1;
+                }
+                answered.insert(reply["id"].as_u64().unwrap());
+            }
+        })
+        .await
+        .expect("the session stopped answering");
+        assert!(busy > 0);
+    }
+
+    #[tokio::test]
+    async fn detached_sessions_expire_while_the_server_talks() {
+        let socket = serve_fake("grace", |config| {
+            config.reconnect_grace = Duration::from_millis(300);
+        })
+        .await;
+        let target = ProbeTarget::Unix(socket);
+        let response = readiness::send(&target, readiness::get("/sse"))
+            .await
+            .unwrap();
+        let mut events = SseReader::new(response.into_body());
+        let post_path = loop {
+            let (event, data) = events.next().await.unwrap();
+            if event == "endpoint" {
+                break readiness::path_of(&data).to_string();
+            }
+        };
+        drop(events);
+
+        // Each call is answered while the client is away, the session expires anyway
+        let call = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "tools/call",
+            "params": { "name": "echo", "arguments": {} },
+        })
+        .to_string();
+        let started = tokio::time::Instant::now();
+        let status = loop {
+            let request = readiness::post(&post_path, call.clone().into());
+            let status = readiness::send(&target, request).await.unwrap().status();
+            if status != StatusCode::ACCEPTED || started.elapsed() > Duration::from_secs(3) {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Opens a session on the SSE endpoint at `path`, with the headers of a proxy in front.
+    async fn connect_with(
+        target: &ProbeTarget,
+        path: &str,
+        headers: &[(&'static str, &str)],
+    ) -> SseClient {
+        let mut request = readiness::get(path);
+        for &(name, value) in headers {
+            request.headers_mut().insert(
+                header::HeaderName::from_static(name),
+                header::HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        let response = readiness::send(target, request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        SseClient::from_response(target, response).await
+    }
+
+    #[tokio::test]
+    async fn nested_routers_advertise_the_base_path_and_forwarded_prefix() {
+        let socket = temp_socket("nested");
+        let mut config = SseServerConfig::new(([127, 0, 0, 1], 0).into());
+        config.base_path = "/mcp/".to_string();
+        let (server, router) = SseServer::new(config);
+        server.forward(fake_factory);
+        let listener = bind_unix_socket(&socket).unwrap();
+        let app = Router::new().nest("/api", router);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let target = ProbeTarget::Unix(socket);
+
+        // The proxy strips the base path, the nested prefix is kept
+        let mut client = connect_with(&target, "/api/sse", &[]).await;
+        assert!(
+            client.endpoint.starts_with("/mcp/api/message?sessionId="),
+            "{}",
+            client.endpoint
+        );
+        let (status, body) = send(
+            &target,
+            Method::POST,
+            &client.endpoint.replacen("/mcp", "", 1),
+            "application/json",
+            initialize(1).to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{body}");
+        let initialized = client.next_message().await;
+        assert_eq!(initialized["result"]["serverInfo"]["name"], "fake");
+        let session = client
+            .endpoint
+            .split("sessionId=")
+            .nth(1)
+            .unwrap()
+            .to_string();
+
+        let proxied = [
+            ("x-forwarded-prefix", "/gateway/, /other"),
+            ("x-forwarded-host", "example.com:8443"),
+            ("x-forwarded-proto", "HTTPS"),
+        ];
+        let client = connect_with(&target, "/api/sse", &proxied).await;
+        assert!(
+            client
+                .endpoint
+                .starts_with("https://example.com:8443/gateway/mcp/api/message?sessionId="),
+            "{}",
+            client.endpoint
+        );
+
+        // Invalid forwarded values are ignored
+        let spoofed = [("x-forwarded-prefix", "evil"), ("x-forwarded-host", "a/b")];
+        let client = connect_with(&target, "/api/sse", &spoofed).await;
+        assert!(
+            client.endpoint.starts_with("/mcp/api/message?sessionId="),
+            "{}",
+            client.endpoint
+        );
+
+        // A resumed session is advertised under the prefix it is reached by now
+        let last_event_id = format!("{session}:0");
+        let resumed = [
+            ("last-event-id", last_event_id.as_str()),
+            ("x-forwarded-prefix", "/p"),
+        ];
+        let client = connect_with(&target, "/api/sse", &resumed).await;
+        assert_eq!(
+            client.endpoint,
+            format!("/p/mcp/api/message?sessionId={session}")
+        );
     }
 }
@@ -0,0 +1,960 @@
+//! Validation of the [`McpServerConfig`]s before starting any runtime.
+//!
+//! The config is deserialized from the request params as is, so an empty package or an
+//! unknown runtime would otherwise only fail once a port is allocated, or opaquely inside
+//! `uvx`, `bunx` or Docker. All the problems found are reported at once.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::{AccessList, McpRuntime, McpServerConfig, SupportedTransportAdapter};
+
+/// Maximum number of arguments passed to the MCP server
+pub const MAX_ARGS: usize = 64;
+/// Maximum length of a single argument, in bytes
+pub const MAX_ARG_LEN: usize = 4096;
+/// Maximum number of environment variables, including secrets
+pub const MAX_ENV_VARS: usize = 128;
+/// Maximum length of a single environment variable value, in bytes
+pub const MAX_ENV_VALUE_LEN: usize = 32 * 1024;
+/// Maximum number of headers sent to a remote MCP server
+pub const MAX_HEADERS: usize = 32;
+/// Maximum length of a single header value, in bytes
+pub const MAX_HEADER_VALUE_LEN: usize = 8 * 1024;
+/// Headers set by the blueprint when talking to a remote MCP server
+pub const RESERVED_HEADER_NAMES: &[&str] = &[
+    "host",
+    "accept",
+    "connection",
+    "content-length",
+    "content-type",
+    "transfer-encoding",
+    "mcp-session-id",
+];
+/// Maximum number of MCP servers of a service
+pub const MAX_SERVERS: usize = 16;
+/// Maximum length of the name of an MCP server
+pub const MAX_SERVER_NAME_LEN: usize = 32;
+/// Server names taken by the paths of the gateway on the service router
+pub const RESERVED_SERVER_NAMES: &[&str] = &["sse", "message", "ws"];
+/// Environment variables set by the blueprint, or that could hijack the runtime
+pub const RESERVED_ENV_NAMES: &[&str] = &[
+    "PORT",
+    "PATH",
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+    "DYLD_INSERT_LIBRARIES",
+];
+
+/// A single problem found in the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The runtime is missing or unknown
+    UnknownRuntime,
+    /// The package is empty
+    EmptyPackage,
+    /// The package is not valid for the runtime
+    InvalidPackage {
+        runtime: McpRuntime,
+        package: String,
+        reason: &'static str,
+    },
+    /// The transport adapter can't be used with the runtime
+    UnsupportedTransport {
+        runtime: McpRuntime,
+        transport_adapter: SupportedTransportAdapter,
+    },
+    /// An access policy is set but no gateway is in front of the MCP server
+    PolicyRequiresGateway,
+    /// An access policy entry is empty
+    EmptyPolicyEntry { field: &'static str },
+    /// The health path is not an absolute path
+    InvalidHealthPath { path: String },
+    /// Too many arguments
+    TooManyArgs { count: usize, max: usize },
+    /// An argument is too long
+    ArgTooLong {
+        index: usize,
+        len: usize,
+        max: usize,
+    },
+    /// Too many environment variables
+    TooManyEnvVars { count: usize, max: usize },
+    /// An environment variable name is not a valid name
+    InvalidEnvName { name: String },
+    /// An environment variable name is reserved
+    ReservedEnvName { name: String },
+    /// An environment variable is set more than once
+    DuplicateEnvName { name: String },
+    /// An environment variable value is too long
+    EnvValueTooLong {
+        name: String,
+        len: usize,
+        max: usize,
+    },
+    /// Headers are set but the runtime is not remote
+    HeadersRequireRemote,
+    /// Too many headers
+    TooManyHeaders { count: usize, max: usize },
+    /// A header name is not a valid name
+    InvalidHeaderName { name: String },
+    /// A header name is reserved
+    ReservedHeaderName { name: String },
+    /// A header value is too long or contains control characters
+    InvalidHeaderValue { name: String },
+    /// A header value uses a placeholder that isn't a secret environment variable
+    UnknownHeaderSecret { name: String, secret: String },
+    /// Too many MCP servers in the service
+    TooManyServers { count: usize, max: usize },
+    /// A server name can't be used as a path segment
+    InvalidServerName { name: String },
+    /// A server name is taken by the gateway
+    ReservedServerName { name: String },
+    /// A server name is used more than once
+    DuplicateServerName { name: String },
+    /// A problem in the config of a named server
+    Server {
+        name: String,
+        error: Box<ValidationError>,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownRuntime => write!(f, "unknown runtime"),
+            Self::EmptyPackage => write!(f, "package must not be empty"),
+            Self::InvalidPackage {
+                runtime,
+                package,
+                reason,
+            } => write!(f, "invalid {runtime:?} package `{package}`: {reason}"),
+            Self::UnsupportedTransport {
+                runtime,
+                transport_adapter,
+            } => write!(
+                f,
+                "transport adapter {transport_adapter:?} is not supported by the {runtime:?} runtime"
+            ),
+            Self::PolicyRequiresGateway => write!(
+                f,
+                "an access policy requires the StdioToSSE transport adapter"
+            ),
+            Self::EmptyPolicyEntry { field } => write!(f, "empty entry in policy {field}"),
+            Self::InvalidHealthPath { path } => {
+                write!(f, "health path `{path}` must start with `/`")
+            }
+            Self::TooManyArgs { count, max } => {
+                write!(f, "too many args: {count}, at most {max} are allowed")
+            }
+            Self::ArgTooLong { index, len, max } => {
+                write!(
+                    f,
+                    "arg {index} is {len} bytes long, at most {max} are allowed"
+                )
+            }
+            Self::TooManyEnvVars { count, max } => {
+                write!(f, "too many env vars: {count}, at most {max} are allowed")
+            }
+            Self::InvalidEnvName { name } => write!(f, "invalid env var name `{name}`"),
+            Self::ReservedEnvName { name } => write!(f, "env var `{name}` is reserved"),
+            Self::DuplicateEnvName { name } => write!(f, "env var `{name}` is set more than once"),
+            Self::EnvValueTooLong { name, len, max } => write!(
+                f,
+                "env var `{name}` value is {len} bytes long, at most {max} are allowed"
+            ),
+            Self::HeadersRequireRemote => write!(f, "headers require the Remote runtime"),
+            Self::TooManyHeaders { count, max } => {
+                write!(f, "too many headers: {count}, at most {max} are allowed")
+            }
+            Self::InvalidHeaderName { name } => write!(f, "invalid header name `{name}`"),
+            Self::ReservedHeaderName { name } => write!(f, "header `{name}` is reserved"),
+            Self::InvalidHeaderValue { name } => write!(
+                f,
+                "header `{name}` must be at most {MAX_HEADER_VALUE_LEN} bytes without control characters"
+            ),
+            Self::UnknownHeaderSecret { name, secret } => write!(
+                f,
+                "header `{name}` uses `${{{secret}}}` which is not set in secretEnv"
+            ),
+            Self::TooManyServers { count, max } => {
+                write!(f, "too many servers: {count}, at most {max} are allowed")
+            }
+            Self::InvalidServerName { name } => write!(
+                f,
+                "invalid server name `{name}`: expected at most {MAX_SERVER_NAME_LEN} lowercase letters, digits, `-` or `_`"
+            ),
+            Self::ReservedServerName { name } => {
+                write!(f, "server name `{name}` is reserved")
+            }
+            Self::DuplicateServerName { name } => {
+                write!(f, "server `{name}` is defined more than once")
+            }
+            Self::Server { name, error } => write!(f, "server `{name}`: {error}"),
+        }
+    }
+}
+
+/// All the problems found in a config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Validates the config, returning every problem found.
+pub fn validate(config: &McpServerConfig) -> Result<(), ValidationErrors> {
+    let mut errors = Vec::new();
+    validate_package(config, &mut errors);
+    validate_transport(config, &mut errors);
+    validate_args(config, &mut errors);
+    validate_env(config, &mut errors);
+    validate_headers(config, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationErrors(errors))
+    }
+}
+
+/// Validates the named configs of the MCP servers of a service.
+pub fn validate_servers(servers: &[(String, McpServerConfig)]) -> Result<(), ValidationErrors> {
+    let mut errors = Vec::new();
+    if servers.len() > MAX_SERVERS {
+        errors.push(ValidationError::TooManyServers {
+            count: servers.len(),
+            max: MAX_SERVERS,
+        });
+    }
+    let mut seen = BTreeSet::new();
+    for (name, config) in servers {
+        if !is_valid_server_name(name) {
+            errors.push(ValidationError::InvalidServerName { name: name.clone() });
+        } else if RESERVED_SERVER_NAMES.contains(&name.as_str()) {
+            errors.push(ValidationError::ReservedServerName { name: name.clone() });
+        }
+        if !seen.insert(name.as_str()) {
+            errors.push(ValidationError::DuplicateServerName { name: name.clone() });
+        }
+        if let Err(ValidationErrors(server_errors)) = validate(config) {
+            errors.extend(
+                server_errors
+                    .into_iter()
+                    .map(|error| ValidationError::Server {
+                        name: name.clone(),
+                        error: Box::new(error),
+                    }),
+            );
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationErrors(errors))
+    }
+}
+
+/// `[a-z0-9][a-z0-9_-]*`, used as a path segment and in container names
+fn is_valid_server_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= MAX_SERVER_NAME_LEN
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+fn validate_package(config: &McpServerConfig, errors: &mut Vec<ValidationError>) {
+    let package = config.package.as_str();
+    if matches!(config.runtime, McpRuntime::Unknown) {
+        errors.push(ValidationError::UnknownRuntime);
+        return;
+    }
+    if package.trim().is_empty() {
+        errors.push(ValidationError::EmptyPackage);
+        return;
+    }
+    let result = match config.runtime {
+        McpRuntime::Python => check_pypi_package(package),
+        McpRuntime::Javascript => check_npm_package(package),
+        McpRuntime::Docker => check_docker_reference(package),
+        McpRuntime::Remote => check_remote_url(package),
+        McpRuntime::Unknown => Ok(()),
+    };
+    if let Err(reason) = result {
+        errors.push(ValidationError::InvalidPackage {
+            runtime: config.runtime.clone(),
+            package: package.to_string(),
+            reason,
+        });
+    }
+}
+
+fn validate_transport(config: &McpServerConfig, errors: &mut Vec<ValidationError>) {
+    let transport_adapter = config.transport_adapter;
+    // Only containers can listen on `PORT` by themselves, the other runtimes are always
+    // started behind the stdio to SSE gateway.
+    let supported = match config.runtime {
+        McpRuntime::Docker | McpRuntime::Unknown => true,
+        McpRuntime::Python | McpRuntime::Javascript | McpRuntime::Remote => {
+            transport_adapter.is_stdio_to_sse()
+        }
+    };
+    if !supported {
+        errors.push(ValidationError::UnsupportedTransport {
+            runtime: config.runtime.clone(),
+            transport_adapter,
+        });
+    }
+
+    let policy = &config.policy;
+    if transport_adapter.is_none() && !policy.is_empty() {
+        errors.push(ValidationError::PolicyRequiresGateway);
+    }
+    for (field, list) in [
+        ("tools", &policy.tools),
+        ("resources", &policy.resources),
+        ("prompts", &policy.prompts),
+    ] {
+        if has_empty_entry(list) {
+            errors.push(ValidationError::EmptyPolicyEntry { field });
+        }
+    }
+
+    if let Some(path) = &config.health_path.0
+        && (!path.starts_with('/') || path.chars().any(char::is_whitespace))
+    {
+        errors.push(ValidationError::InvalidHealthPath { path: path.clone() });
+    }
+}
+
+fn has_empty_entry(list: &AccessList) -> bool {
+    [&list.allow, &list.deny]
+        .into_iter()
+        .flat_map(|l| l.0.iter().flat_map(|l| l.0.iter()))
+        .any(|entry| entry.trim().is_empty())
+}
+
+fn validate_args(config: &McpServerConfig, errors: &mut Vec<ValidationError>) {
+    let args = config
+        .args
+        .0
+        .as_ref()
+        .map(|l| l.0.as_slice())
+        .unwrap_or_default();
+    if args.len() > MAX_ARGS {
+        errors.push(ValidationError::TooManyArgs {
+            count: args.len(),
+            max: MAX_ARGS,
+        });
+    }
+    for (index, arg) in args.iter().enumerate() {
+        if arg.len() > MAX_ARG_LEN {
+            errors.push(ValidationError::ArgTooLong {
+                index,
+                len: arg.len(),
+                max: MAX_ARG_LEN,
+            });
+        }
+    }
+}
+
+fn validate_env(config: &McpServerConfig, errors: &mut Vec<ValidationError>) {
+    let env = config.env.0.iter().flat_map(|l| l.0.iter());
+    let secret_env = config.secret_env.0.iter().flat_map(|l| l.0.iter());
+    let vars: Vec<&(String, String)> = env.chain(secret_env).collect();
+    if vars.len() > MAX_ENV_VARS {
+        errors.push(ValidationError::TooManyEnvVars {
+            count: vars.len(),
+            max: MAX_ENV_VARS,
+        });
+    }
+    let mut seen = BTreeSet::new();
+    for (name, value) in vars {
+        if !is_valid_env_name(name) {
+            errors.push(ValidationError::InvalidEnvName { name: name.clone() });
+        } else if RESERVED_ENV_NAMES.contains(&name.as_str()) {
+            errors.push(ValidationError::ReservedEnvName { name: name.clone() });
+        } else if !seen.insert(name.as_str()) {
+            errors.push(ValidationError::DuplicateEnvName { name: name.clone() });
+        }
+        if value.len() > MAX_ENV_VALUE_LEN {
+            errors.push(ValidationError::EnvValueTooLong {
+                name: name.clone(),
+                len: value.len(),
+                max: MAX_ENV_VALUE_LEN,
+            });
+        }
+    }
+}
+
+fn validate_headers(config: &McpServerConfig, errors: &mut Vec<ValidationError>) {
+    let headers = config
+        .headers
+        .0
+        .as_ref()
+        .map(|l| l.0.as_slice())
+        .unwrap_or_default();
+    if headers.is_empty() {
+        return;
+    }
+    if config.runtime != McpRuntime::Remote {
+        errors.push(ValidationError::HeadersRequireRemote);
+    }
+    if headers.len() > MAX_HEADERS {
+        errors.push(ValidationError::TooManyHeaders {
+            count: headers.len(),
+            max: MAX_HEADERS,
+        });
+    }
+    let secrets: BTreeSet<&str> = config
+        .secret_env
+        .0
+        .iter()
+        .flat_map(|l| l.0.iter())
+        .map(|(name, _)| name.as_str())
+        .collect();
+    for (name, value) in headers {
+        if !is_valid_header_name(name) {
+            errors.push(ValidationError::InvalidHeaderName { name: name.clone() });
+        } else if RESERVED_HEADER_NAMES.contains(&name.to_ascii_lowercase().as_str()) {
+            errors.push(ValidationError::ReservedHeaderName { name: name.clone() });
+        }
+        if value.len() > MAX_HEADER_VALUE_LEN || value.chars().any(|c| c.is_control()) {
+            errors.push(ValidationError::InvalidHeaderValue { name: name.clone() });
+        }
+        for segment in header_segments(value) {
+            if let HeaderSegment::Secret(secret) = segment
+                && !secrets.contains(secret)
+            {
+                errors.push(ValidationError::UnknownHeaderSecret {
+                    name: name.clone(),
+                    secret: secret.to_string(),
+                });
+            }
+        }
+    }
+}
+
+/// A part of a header value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HeaderSegment<'a> {
+    Text(&'a str),
+    /// The name of the secret environment variable of a `${NAME}` placeholder
+    Secret(&'a str),
+}
+
+/// Splits a header value into its text and its `${NAME}` placeholders, a `${` without a
+/// closing `}` being text.
+pub(crate) fn header_segments(value: &str) -> Vec<HeaderSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        if start > 0 {
+            segments.push(HeaderSegment::Text(&rest[..start]));
+        }
+        segments.push(HeaderSegment::Secret(&rest[start + 2..start + 2 + len]));
+        rest = &rest[start + 3 + len..];
+    }
+    if !rest.is_empty() {
+        segments.push(HeaderSegment::Text(rest));
+    }
+    segments
+}
+
+/// An HTTP token: letters, digits and ``!#$%&'*+-.^_`|~``
+fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// `[A-Za-z_][A-Za-z0-9_]*`
+fn is_valid_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A PyPI requirement as accepted by `uvx`: `name[extras]` optionally followed by
+/// `@version` or a version specifier such as `==1.2.3` or `>=1.0`.
+fn check_pypi_package(package: &str) -> Result<(), &'static str> {
+    let end = package
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+        .unwrap_or(package.len());
+    let (name, mut rest) = package.split_at(end);
+    let is_edge = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    if !is_edge(name.chars().next()) || !is_edge(name.chars().last()) {
+        return Err("the project name must start and end with a letter or a digit");
+    }
+    if let Some(extras) = rest.strip_prefix('[') {
+        let Some((extras, after)) = extras.split_once(']') else {
+            return Err("unterminated extras");
+        };
+        let valid = extras.split(',').all(|extra| {
+            let extra = extra.trim();
+            !extra.is_empty()
+                && extra
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        });
+        if !valid {
+            return Err("invalid extras");
+        }
+        rest = after;
+    }
+    if rest.is_empty() {
+        return Ok(());
+    }
+    let version = match rest.strip_prefix('@') {
+        Some(version) => version,
+        None => {
+            let specifiers = rest.split(',').map(|spec| {
+                ["===", "==", "!=", "~=", ">=", "<=", ">", "<"]
+                    .iter()
+                    .find_map(|op| spec.trim().strip_prefix(op))
+            });
+            let mut versions = Vec::new();
+            for version in specifiers {
+                versions.push(version.ok_or("invalid version specifier")?);
+            }
+            return versions
+                .into_iter()
+                .all(is_valid_version)
+                .then_some(())
+                .ok_or("invalid version");
+        }
+    };
+    is_valid_version(version)
+        .then_some(())
+        .ok_or("invalid version")
+}
+
+fn is_valid_version(version: &str) -> bool {
+    let version = version.trim();
+    !version.is_empty()
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '*' | '+' | '!' | '-' | '_'))
+}
+
+/// An npm package spec as accepted by `bunx`: `name` or `@scope/name`, optionally followed
+/// by `@version`, `@range` or `@tag`.
+fn check_npm_package(package: &str) -> Result<(), &'static str> {
+    let (scope, rest) = match package.strip_prefix('@') {
+        Some(scoped) => {
+            let (scope, rest) = scoped
+                .split_once('/')
+                .ok_or("a scoped package must be `@scope/name`")?;
+            (Some(scope), rest)
+        }
+        None => (None, package),
+    };
+    let (name, version) = match rest.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (rest, None),
+    };
+    let is_valid_part = |part: &str| {
+        !part.is_empty()
+            && !part.starts_with(['.', '_'])
+            && part.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '.' | '_' | '~')
+            })
+    };
+    if scope.is_some_and(|scope| !is_valid_part(scope)) {
+        return Err("invalid scope");
+    }
+    if !is_valid_part(name) {
+        return Err("the name must be lowercase and URL safe");
+    }
+    if package.len() > 214 {
+        return Err("the name must be at most 214 characters long");
+    }
+    if let Some(version) = version {
+        let valid = !version.is_empty()
+            && version.chars().all(|c| {
+                c.is_ascii_alphanumeric()
+                    || matches!(
+                        c,
+                        '.' | '-' | '_' | '+' | '^' | '~' | '<' | '>' | '=' | '*' | '|'
+                    )
+            });
+        if !valid {
+            return Err("invalid version, range or tag");
+        }
+    }
+    Ok(())
+}
+
+/// An absolute `http` or `https` URL, without credentials as they belong in the headers, of a
+/// host that is not an address of the operator. Host names are checked once resolved, see
+/// [`RemoteRunner`](crate::manager::remote::RemoteRunner).
+fn check_remote_url(url: &str) -> Result<(), &'static str> {
+    let Some(rest) = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    else {
+        return Err("the url must start with http:// or https://");
+    };
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("the url must not contain whitespace");
+    }
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    if authority.is_empty() {
+        return Err("the url must have a host");
+    }
+    if authority.contains('@') {
+        return Err("credentials must be sent in the headers");
+    }
+    let host = match authority.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    let local = host.eq_ignore_ascii_case("localhost")
+        || host.parse().is_ok_and(|ip| !is_public_address(&ip));
+    if local {
+        return Err("the url must not point to a loopback, private or link-local address");
+    }
+    Ok(())
+}
+
+/// IPv4 ranges that aren't on the public internet: the local network, shared, reserved,
+/// documentation, benchmarking and multicast ranges
+const NON_PUBLIC_V4: &[(Ipv4Addr, u8)] = &[
+    (Ipv4Addr::new(0, 0, 0, 0), 8),
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(100, 64, 0, 0), 10),
+    (Ipv4Addr::new(127, 0, 0, 0), 8),
+    (Ipv4Addr::new(169, 254, 0, 0), 16),
+    (Ipv4Addr::new(172, 16, 0, 0), 12),
+    (Ipv4Addr::new(192, 0, 0, 0), 24),
+    (Ipv4Addr::new(192, 0, 2, 0), 24),
+    (Ipv4Addr::new(192, 88, 99, 0), 24),
+    (Ipv4Addr::new(192, 168, 0, 0), 16),
+    (Ipv4Addr::new(198, 18, 0, 0), 15),
+    (Ipv4Addr::new(198, 51, 100, 0), 24),
+    (Ipv4Addr::new(203, 0, 113, 0), 24),
+    (Ipv4Addr::new(224, 0, 0, 0), 4),
+    (Ipv4Addr::new(240, 0, 0, 0), 4),
+];
+
+/// IPv6 ranges that aren't on the public internet, or translate to IPv4 addresses of the
+/// operator's network: NAT64, Teredo, discard, documentation, ULA, link-local, site-local
+/// and multicast
+const NON_PUBLIC_V6: &[(Ipv6Addr, u8)] = &[
+    (Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96),
+    (Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0), 48),
+    (Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 0), 64),
+    (Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0), 32),
+    (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0), 32),
+    (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+    (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10),
+    (Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8),
+];
+
+/// Whether the address can be reached by a remote MCP server, as opposed to the addresses
+/// reaching the operator's host and network, or not routed on the internet.
+///
+/// IPv6 addresses embedding an IPv4 address (mapped, compatible and 6to4) are checked as it.
+pub(crate) fn is_public_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let ip = u32::from(*ip);
+            !NON_PUBLIC_V4.iter().any(|&(range, len)| {
+                let mask = u32::MAX << (32 - len);
+                ip & mask == u32::from(range)
+            })
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            let bits = u128::from(*ip);
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_address(&IpAddr::V4(ip));
+            }
+            // `::` and `::1` are IPv4-compatible too
+            if bits >> 32 == 0 {
+                return bits > 1 && is_public_address(&IpAddr::V4(Ipv4Addr::from(bits as u32)));
+            }
+            // 6to4, `2002:a.b.c.d::/48`
+            if segments[0] == 0x2002 {
+                let ip = Ipv4Addr::from((bits >> 80) as u32);
+                return is_public_address(&IpAddr::V4(ip));
+            }
+            !NON_PUBLIC_V6.iter().any(|&(range, len)| {
+                let mask = u128::MAX << (128 - len);
+                bits & mask == u128::from(range)
+            })
+        }
+    }
+}
+
+/// A Docker image reference: `[registry[:port]/]path[:tag][@algorithm:digest]`.
+fn check_docker_reference(reference: &str) -> Result<(), &'static str> {
+    let (name, digest) = match reference.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (reference, None),
+    };
+    if let Some(digest) = digest {
+        let (algorithm, hex) = digest.split_once(':').ok_or("invalid digest")?;
+        let valid = !algorithm.is_empty()
+            && algorithm.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '+' | '.' | '_' | '-')
+            })
+            && hex.len() >= 32
+            && hex.chars().all(|c| c.is_ascii_hexdigit());
+        if !valid {
+            return Err("invalid digest");
+        }
+    }
+    // A tag is after the last `:` that is not part of a registry `host:port`
+    let (name, tag) = match name.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+        _ => (name, None),
+    };
+    if let Some(tag) = tag {
+        let mut chars = tag.chars();
+        let valid = tag.len() <= 128
+            && matches!(chars.next(), Some(c) if c.is_ascii_alphanumeric() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+        if !valid {
+            return Err("invalid tag");
+        }
+    }
+    let mut components: Vec<&str> = name.split('/').collect();
+    // The first component is a registry if it looks like a host (`.`, `:` or `localhost`)
+    if components.len() > 1 {
+        let first = components[0];
+        if first.contains(['.', ':']) || first == "localhost" {
+            let valid = first
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'));
+            if !valid {
+                return Err("invalid registry");
+            }
+            components.remove(0);
+        }
+    }
+    let is_valid_component = |component: &str| {
+        let alphanumeric = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        };
+        // Lowercase alphanumerics separated by `.`, `_`, `__` or one or more `-`
+        component
+            .split(['.', '_', '-'])
+            .enumerate()
+            .all(|(i, part)| alphanumeric(part) || (i > 0 && part.is_empty()))
+            && component.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && component.ends_with(|c: char| c.is_ascii_alphanumeric())
+            && !component.contains("___")
+            && !component.contains("..")
+    };
+    if components.is_empty() || !components.into_iter().all(is_valid_component) {
+        return Err("the repository must be lowercase alphanumerics separated by `.`, `_` or `-`");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: serde_json::Value) -> McpServerConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn is_valid(runtime: &str, package: &str) -> bool {
+        validate(&config(
+            serde_json::json!({ "runtime": runtime, "package": package }),
+        ))
+        .is_ok()
+    }
+
+    #[test]
+    fn packages_are_checked_against_their_registry() {
+        let valid = [
+            ("python", "mcp-server-fetch"),
+            ("python", "pkg[cli,extra]==1.0"),
+            ("python", "a>=1.0,<2"),
+            ("javascript", "@upstash/context7-mcp@latest"),
+            ("javascript", "foo@^1.2.0"),
+            ("docker", "ghcr.io/github/github-mcp-server"),
+            ("docker", "localhost:5000/a/b:tag"),
+            ("docker", "a__b"),
+        ];
+        for (runtime, package) in valid {
+            assert!(is_valid(runtime, package), "{runtime} {package}");
+        }
+        let invalid = [
+            ("python", ""),
+            ("python", "-bad"),
+            ("python", "pkg;rm -rf"),
+            ("python", "../x"),
+            ("javascript", "Foo"),
+            ("javascript", "@scope"),
+            ("javascript", "--eval=x"),
+            ("docker", "Upper/case"),
+            ("docker", "a@sha256:zz"),
+            ("docker", "a___b"),
+        ];
+        for (runtime, package) in invalid {
+            assert!(!is_valid(runtime, package), "{runtime} {package}");
+        }
+        let errors = validate(&config(
+            serde_json::json!({ "runtime": "unknown", "package": "x" }),
+        ));
+        assert_eq!(errors.unwrap_err().0, [ValidationError::UnknownRuntime]);
+    }
+
+    #[test]
+    fn env_args_and_policy_are_checked() {
+        let errors = validate(&config(serde_json::json!({
+            "runtime": "python",
+            "package": "x",
+            "transportAdapter": "none",
+            "env": [["PORT", "1"], ["1A", "x"], ["OK", "1"]],
+            "secretEnv": [["OK", "c"]],
+            "args": vec!["a"; MAX_ARGS + 1],
+            "policy": { "tools": { "allow": [""] } },
+        })))
+        .unwrap_err()
+        .0;
+        let expected = [
+            ValidationError::ReservedEnvName {
+                name: "PORT".into(),
+            },
+            ValidationError::InvalidEnvName { name: "1A".into() },
+            ValidationError::DuplicateEnvName { name: "OK".into() },
+            ValidationError::TooManyArgs {
+                count: MAX_ARGS + 1,
+                max: MAX_ARGS,
+            },
+            ValidationError::PolicyRequiresGateway,
+            ValidationError::EmptyPolicyEntry { field: "tools" },
+        ];
+        for error in expected {
+            assert!(errors.contains(&error), "{error:?} not in {errors:?}");
+        }
+        assert!(
+            errors
+                .iter()
+                .any(|error| matches!(error, ValidationError::UnsupportedTransport { .. }))
+        );
+    }
+
+    #[test]
+    fn remote_servers_take_an_url_and_headers() {
+        let mut remote = config(serde_json::json!({
+            "runtime": "remote",
+            "package": "https://mcp.example.com/sse",
+            "headers": [["Authorization", "Bearer ${TOKEN}"], ["X-Api-Version", "2"]],
+            "secretEnv": [["TOKEN", "00"]],
+        }));
+        assert_eq!(validate(&remote), Ok(()));
+        remote.package = "https://user:pw@example.com/mcp".into();
+        assert!(matches!(
+            &validate(&remote).unwrap_err().0[..],
+            [ValidationError::InvalidPackage { .. }]
+        ));
+        remote.package = "ftp://example.com".into();
+        assert!(validate(&remote).is_err());
+
+        remote.package = "https://example.com/mcp".into();
+        let headers = &mut remote.headers.0.as_mut().unwrap().0;
+        headers.push(("Host".into(), "x".into()));
+        headers.push(("Bad Name".into(), "x\n".into()));
+        headers.push(("X-Other".into(), "${TOKEN}:${OTHER} ${".into()));
+        assert_eq!(
+            validate(&remote).unwrap_err().0,
+            [
+                ValidationError::ReservedHeaderName {
+                    name: "Host".into()
+                },
+                ValidationError::InvalidHeaderName {
+                    name: "Bad Name".into()
+                },
+                ValidationError::InvalidHeaderValue {
+                    name: "Bad Name".into()
+                },
+                ValidationError::UnknownHeaderSecret {
+                    name: "X-Other".into(),
+                    secret: "OTHER".into()
+                },
+            ]
+        );
+
+        remote.runtime = McpRuntime::Python;
+        remote.package = "mcp-server-fetch".into();
+        let errors = validate(&remote).unwrap_err().0;
+        assert!(
+            errors.contains(&ValidationError::HeadersRequireRemote),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn remote_urls_must_not_reach_the_operator() {
+        let local = [
+            "http://127.0.0.1:8080/sse",
+            "http://localhost/mcp",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.1/mcp",
+            "http://192.168.1.10:3000/sse",
+            "http://0.0.0.0/sse",
+            "http://[::1]:8080/mcp",
+            "http://[::ffff:127.0.0.1]/mcp",
+            "http://[fd00::1]/mcp",
+            "http://100.64.0.1/mcp",
+            "http://100.127.255.254/mcp",
+            "http://198.18.0.1/mcp",
+            "http://198.19.255.255/mcp",
+            "http://224.0.0.1/mcp",
+            "http://240.0.0.1/mcp",
+            "http://255.255.255.255/mcp",
+            "http://192.0.2.1/mcp",
+            "http://[64:ff9b::a00:1]/mcp",
+            "http://[64:ff9b::5db8:d822]/mcp",
+            "http://[::7f00:1]/mcp",
+            "http://[::10.0.0.1]/mcp",
+            "http://[::]/mcp",
+            "http://[2002:a00:1::1]/mcp",
+            "http://[2001:db8::1]/mcp",
+            "http://[fe80::1]/mcp",
+            "http://[ff02::1]/mcp",
+        ];
+        for url in local {
+            assert!(check_remote_url(url).is_err(), "{url}");
+        }
+        let public = [
+            "https://mcp.example.com/sse",
+            "http://mcp.example.com:8080/mcp",
+            "https://93.184.216.34/mcp",
+            "https://[2606:4700::1111]:443/mcp",
+            "https://100.128.0.1/mcp",
+            "https://198.20.0.1/mcp",
+            "https://[::ffff:93.184.216.34]/mcp",
+            "https://[2002:5db8:d822::1]/mcp",
+        ];
+        for url in public {
+            assert!(check_remote_url(url).is_ok(), "{url}");
+        }
+    }
+}
@@ -11,7 +11,10 @@ use blueprint_sdk::tangle::consumer::TangleConsumer;
 use blueprint_sdk::tangle::filters::MatchesServiceId;
 use blueprint_sdk::tangle::layers::TangleLayer;
 use blueprint_sdk::tangle::producer::TangleProducer;
-use mcp_blueprint::{MCP_START_JOB_ID, MCP_STOP_JOB_ID, MyContext, mcp_start, mcp_stop};
+use mcp_blueprint::{
+    MCP_START_JOB_ID, MCP_STOP_JOB_ID, MyContext, mcp_start, mcp_stop, spawn_auto_start,
+    spawn_lifecycle_watcher, spawn_metrics_endpoint, spawn_reconciler, spawn_secrets_endpoint,
+};
 use tower::filter::FilterLayer;
 use tracing::error;
 use tracing::level_filters::LevelFilter;
@@ -37,6 +40,11 @@ async fn main() -> color_eyre::Result<()> {
 
     let service_id = env.protocol_settings.tangle()?.service_id.unwrap();
     let ctx = MyContext::new(env.clone()).await?;
+    spawn_secrets_endpoint(&ctx).await?;
+    spawn_metrics_endpoint(&ctx).await?;
+    spawn_reconciler(&ctx);
+    spawn_lifecycle_watcher(&ctx).await?;
+    spawn_auto_start(&ctx, service_id);
     let result = BlueprintRunner::builder(tangle_config, env.clone())
         .router(
             Router::new()